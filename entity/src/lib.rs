@@ -3,6 +3,8 @@
 pub mod prelude;
 
 pub mod blacklist;
+pub mod guild_settings;
 pub mod message_cache;
 pub mod rate_limit;
+pub mod tool_call;
 pub mod user;
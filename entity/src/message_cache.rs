@@ -9,22 +9,22 @@ pub struct Model {
 	pub id: u64,
 	#[sea_orm(unique)]
 	pub discord_message_id: u64,
-	pub ref_discord_message_id: Option<u64>,
-	#[sea_orm(unique)]
+	pub discord_channel_id: u64,
+	pub discord_guild_id: Option<u64>,
 	pub discord_user_id: u64,
+	pub ref_discord_message_id: Option<u64>,
+	#[sea_orm(column_type = "Text")]
 	pub content: String,
+	pub timestamp: DateTimeUtc,
+	pub edited_timestamp: Option<DateTimeUtc>,
+	#[sea_orm(column_type = "Text")]
+	pub attachments: String,
+	#[sea_orm(column_type = "Text")]
+	pub embeds: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
-	#[sea_orm(
-		belongs_to = "Entity",
-		from = "Column::RefDiscordMessageId",
-		to = "Column::DiscordMessageId",
-		on_update = "Cascade",
-		on_delete = "Cascade"
-	)]
-	SelfRef,
 	#[sea_orm(
 		belongs_to = "super::user::Entity",
 		from = "Column::DiscordUserId",
@@ -2,7 +2,9 @@
 
 pub use super::{
 	blacklist::Entity as Blacklist,
+	guild_settings::Entity as GuildSettings,
 	message_cache::Entity as MessageCache,
 	rate_limit::Entity as RateLimit,
+	tool_call::Entity as ToolCall,
 	user::Entity as User,
 };
@@ -18,7 +18,7 @@ pub struct Model {
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
-	#[sea_orm(has_one = "super::message_cache::Entity")]
+	#[sea_orm(has_many = "super::message_cache::Entity")]
 	MessageCache,
 }
 
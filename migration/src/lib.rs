@@ -1,12 +1,24 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20240114_000001_create_table;
+mod m20261018_000001_create_tool_call_table;
+mod m20261018_000002_create_guild_settings_table;
+mod m20261018_000003_recreate_message_cache_table;
+mod m20261018_000004_add_ranked_history_to_guild_settings;
+mod m20261018_000005_add_timestamp_index_to_message_cache;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
 	fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-		vec![Box::new(m20240114_000001_create_table::Migration)]
+		vec![
+			Box::new(m20240114_000001_create_table::Migration),
+			Box::new(m20261018_000001_create_tool_call_table::Migration),
+			Box::new(m20261018_000002_create_guild_settings_table::Migration),
+			Box::new(m20261018_000003_recreate_message_cache_table::Migration),
+			Box::new(m20261018_000004_add_ranked_history_to_guild_settings::Migration),
+			Box::new(m20261018_000005_add_timestamp_index_to_message_cache::Migration),
+		]
 	}
 }
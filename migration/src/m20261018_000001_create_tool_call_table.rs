@@ -0,0 +1,104 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(ToolCall::Table)
+					.col(
+						ColumnDef::new(ToolCall::Id)
+							.big_unsigned()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(ColumnDef::new(ToolCall::DiscordMessageId).big_unsigned().not_null())
+					.col(ColumnDef::new(ToolCall::DiscordUserId).big_unsigned().not_null())
+					.col(ColumnDef::new(ToolCall::DiscordGuildId).big_unsigned().null())
+					.col(ColumnDef::new(ToolCall::DiscordChannelId).big_unsigned().not_null())
+					.col(ColumnDef::new(ToolCall::Server).string().null())
+					.col(ColumnDef::new(ToolCall::Tool).string().not_null())
+					.col(ColumnDef::new(ToolCall::Arguments).text().not_null())
+					.col(ColumnDef::new(ToolCall::ResultSize).big_unsigned().not_null())
+					.col(ColumnDef::new(ToolCall::DurationMs).big_unsigned().not_null())
+					.col(ColumnDef::new(ToolCall::Error).text().null())
+					.col(
+						ColumnDef::new(ToolCall::CreatedAt)
+							.timestamp()
+							.default(Expr::current_timestamp())
+							.not_null(),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// entries are listed per user or guild, and purged by age
+		for (name, column) in [
+			("idx_tool_call_discord_user_id", ToolCall::DiscordUserId),
+			("idx_tool_call_discord_guild_id", ToolCall::DiscordGuildId),
+			("idx_tool_call_created_at", ToolCall::CreatedAt),
+		] {
+			manager
+				.create_index(Index::create().name(name).table(ToolCall::Table).col(column).to_owned())
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager.drop_table(Table::drop().table(ToolCall::Table).to_owned()).await?;
+
+		Ok(())
+	}
+}
+
+/// Tool call table.
+///
+/// Audit trail of every tool call made on behalf of a user. Entries are not linked to the message cache, since they
+/// have to outlive cached messages. Entries are purged once they are older than the configured retention period.
+#[derive(DeriveIden)]
+enum ToolCall {
+	Table,
+
+	/// Database ID for primary key.
+	Id,
+
+	/// Discord ID of the message which triggered the tool call.
+	DiscordMessageId,
+
+	/// Discord ID of the user who triggered the tool call.
+	DiscordUserId,
+
+	/// Discord ID of the guild the tool call was made in. Null in DMs.
+	DiscordGuildId,
+
+	/// Discord ID of the channel the tool call was made in.
+	DiscordChannelId,
+
+	/// Name of the MCP server providing the tool. Null if no server provides the tool.
+	Server,
+
+	/// Name of the tool.
+	Tool,
+
+	/// Arguments passed by the model, as JSON.
+	Arguments,
+
+	/// Number of characters of the result, before it was limited.
+	ResultSize,
+
+	/// Duration of the tool call in milliseconds.
+	DurationMs,
+
+	/// Error message if the tool call failed or was not approved.
+	Error,
+
+	/// Timestamp of the tool call.
+	CreatedAt,
+}
@@ -0,0 +1,71 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.create_table(
+				Table::create()
+					.table(GuildSettings::Table)
+					.col(
+						ColumnDef::new(GuildSettings::Id)
+							.big_unsigned()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(
+						ColumnDef::new(GuildSettings::DiscordGuildId)
+							.big_unsigned()
+							.not_null()
+							.unique_key(),
+					)
+					.col(ColumnDef::new(GuildSettings::Reactions).boolean().not_null().default(false))
+					.col(ColumnDef::new(GuildSettings::Polls).boolean().not_null().default(false))
+					.col(ColumnDef::new(GuildSettings::Threads).boolean().not_null().default(false))
+					.col(ColumnDef::new(GuildSettings::Pins).boolean().not_null().default(false))
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(GuildSettings::Table).to_owned())
+			.await?;
+
+		Ok(())
+	}
+}
+
+/// Guild settings table.
+///
+/// Settings managed by the moderators of a guild. Guilds without an entry use the defaults, which is every action
+/// being disabled.
+#[derive(DeriveIden)]
+enum GuildSettings {
+	Table,
+
+	/// Database ID for primary key.
+	Id,
+
+	/// Discord ID of the guild.
+	DiscordGuildId,
+
+	/// Whether the bot may react to messages.
+	Reactions,
+
+	/// Whether the bot may create polls.
+	Polls,
+
+	/// Whether the bot may open threads.
+	Threads,
+
+	/// Whether the bot may pin messages.
+	Pins,
+}
@@ -0,0 +1,178 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// the old table could hold a single message per user, its content is not worth migrating
+		manager
+			.drop_table(Table::drop().table(MessageCache::Table).to_owned())
+			.await?;
+
+		manager
+			.create_table(
+				Table::create()
+					.table(MessageCache::Table)
+					.col(
+						ColumnDef::new(MessageCache::Id)
+							.big_unsigned()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(
+						ColumnDef::new(MessageCache::DiscordMessageId)
+							.big_unsigned()
+							.not_null()
+							.unique_key(),
+					)
+					.col(ColumnDef::new(MessageCache::DiscordChannelId).big_unsigned().not_null())
+					.col(ColumnDef::new(MessageCache::DiscordGuildId).big_unsigned().null())
+					.col(ColumnDef::new(MessageCache::DiscordUserId).big_unsigned().not_null())
+					.col(ColumnDef::new(MessageCache::RefDiscordMessageId).big_unsigned().null())
+					.col(ColumnDef::new(MessageCache::Content).text().not_null())
+					.col(ColumnDef::new(MessageCache::Timestamp).timestamp().not_null())
+					.col(ColumnDef::new(MessageCache::EditedTimestamp).timestamp().null())
+					.col(ColumnDef::new(MessageCache::Attachments).text().not_null())
+					.col(ColumnDef::new(MessageCache::Embeds).text().not_null())
+					.foreign_key(
+						ForeignKey::create()
+							.name("fk_message_cache_discord_user_id")
+							.from(MessageCache::Table, MessageCache::DiscordUserId)
+							.to(User::Table, User::DiscordUserId)
+							.on_update(ForeignKeyAction::Cascade)
+							.on_delete(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		// history is read per channel in order, and messages are deleted per user on opt-out
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_message_cache_discord_channel_id_timestamp")
+					.table(MessageCache::Table)
+					.col(MessageCache::DiscordChannelId)
+					.col(MessageCache::Timestamp)
+					.to_owned(),
+			)
+			.await?;
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_message_cache_discord_user_id")
+					.table(MessageCache::Table)
+					.col(MessageCache::DiscordUserId)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_table(Table::drop().table(MessageCache::Table).to_owned())
+			.await?;
+
+		// restore the table as created initially
+		manager
+			.create_table(
+				Table::create()
+					.table(MessageCache::Table)
+					.col(
+						ColumnDef::new(MessageCache::Id)
+							.big_unsigned()
+							.not_null()
+							.auto_increment()
+							.primary_key(),
+					)
+					.col(
+						ColumnDef::new(MessageCache::DiscordMessageId)
+							.big_unsigned()
+							.not_null()
+							.unique_key(),
+					)
+					.col(ColumnDef::new(MessageCache::RefDiscordMessageId).big_unsigned().null())
+					.col(
+						ColumnDef::new(MessageCache::DiscordUserId)
+							.big_unsigned()
+							.not_null()
+							.unique_key(),
+					)
+					.col(ColumnDef::new(MessageCache::Content).string().not_null())
+					.foreign_key(
+						ForeignKey::create()
+							.name("fk_message_cache_ref_discord_user_id")
+							.from(MessageCache::Table, MessageCache::DiscordUserId)
+							.to(User::Table, User::DiscordUserId)
+							.on_update(ForeignKeyAction::Cascade)
+							.on_delete(ForeignKeyAction::Cascade),
+					)
+					.foreign_key(
+						ForeignKey::create()
+							.name("fk_message_cache_ref_discord_message_id")
+							.from(MessageCache::Table, MessageCache::RefDiscordMessageId)
+							.to(MessageCache::Table, MessageCache::DiscordMessageId)
+							.on_update(ForeignKeyAction::Cascade)
+							.on_delete(ForeignKeyAction::Cascade),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+}
+
+/// Message cache.
+///
+/// This table is used to cache messages from Discord, so we don't have to fetch them from Discord every time we
+/// assemble a context. It holds everything needed to restore a message for the context. Message cache is invalidated
+/// when a message is edited or deleted.
+#[derive(DeriveIden)]
+enum MessageCache {
+	Table,
+
+	/// Database ID for primary key.
+	Id,
+
+	/// Discord ID of the message.
+	DiscordMessageId,
+
+	/// Discord ID of the channel the message was sent in.
+	DiscordChannelId,
+
+	/// Discord ID of the guild the message was sent in. Null in DMs.
+	DiscordGuildId,
+
+	/// Reference to the user that sent the message.
+	DiscordUserId,
+
+	/// Discord ID of the message this message is a reply to. Replied messages are not necessarily cached.
+	RefDiscordMessageId,
+
+	/// The message content.
+	Content,
+
+	/// When the message was sent.
+	Timestamp,
+
+	/// When the message was last edited, null if it never was.
+	EditedTimestamp,
+
+	/// Metadata of the attachments of the message, as JSON.
+	Attachments,
+
+	/// Embeds of the message, as JSON.
+	Embeds,
+}
+
+#[derive(DeriveIden)]
+enum User {
+	Table,
+	DiscordUserId,
+}
@@ -0,0 +1,47 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(GuildSettings::Table)
+					.add_column(
+						ColumnDef::new(GuildSettings::RankedHistory)
+							.boolean()
+							.not_null()
+							.default(false),
+					)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.alter_table(
+				Table::alter()
+					.table(GuildSettings::Table)
+					.drop_column(GuildSettings::RankedHistory)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+}
+
+#[derive(DeriveIden)]
+enum GuildSettings {
+	Table,
+
+	/// Whether the channel history in the context is selected by relevance to the message the bot is answering, instead
+	/// of taking the most recent messages.
+	RankedHistory,
+}
@@ -0,0 +1,43 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+	async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		// expired entries are purged by their age across all channels, the channel index doesn't cover that
+		manager
+			.create_index(
+				Index::create()
+					.name("idx_message_cache_timestamp")
+					.table(MessageCache::Table)
+					.col(MessageCache::Timestamp)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+		manager
+			.drop_index(
+				Index::drop()
+					.name("idx_message_cache_timestamp")
+					.table(MessageCache::Table)
+					.to_owned(),
+			)
+			.await?;
+
+		Ok(())
+	}
+}
+
+#[derive(DeriveIden)]
+enum MessageCache {
+	Table,
+
+	/// When the message was sent.
+	Timestamp,
+}
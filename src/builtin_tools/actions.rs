@@ -0,0 +1,540 @@
+use entity::guild_settings;
+use futures::future::BoxFuture;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::serenity_prelude::{
+	AutoArchiveDuration,
+	ChannelId,
+	ChannelType,
+	Context,
+	CreateEmbed,
+	CreateMessage,
+	CreateThread,
+	GuildId,
+	Permissions,
+	ReactionType,
+};
+use sea_orm::{
+	ActiveModelTrait,
+	ActiveValue::Set,
+	ColumnTrait,
+	ConnectionTrait,
+	EntityTrait,
+	IntoActiveModel,
+	QueryFilter,
+	TryIntoModel,
+};
+use serde::Deserialize;
+use serde_json::{
+	Value,
+	json,
+};
+
+use super::{
+	Tool,
+	ToolContext,
+	parse_arguments,
+};
+use crate::{
+	channel_access::{
+		guild_channel,
+		permissions_in,
+	},
+	message_link::parse_message_link,
+	text::truncate_chars,
+};
+
+/// Discord limits thread names to 100 characters.
+const MAX_THREAD_NAME_LENGTH: usize = 100;
+
+/// Polls are answered with reactions, one regional indicator per option.
+const MAX_POLL_OPTIONS: usize = 10;
+
+/// Discord actions the model may take during an invocation.
+/// An action has to be enabled in the settings of the guild, and the bot needs the permissions for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiscordActions {
+	pub reactions: bool,
+	pub polls: bool,
+	pub threads: bool,
+	pub pins: bool,
+}
+
+impl DiscordActions {
+	/// Determines the actions allowed in a channel. Nothing is allowed outside of guilds.
+	pub async fn load<C: ConnectionTrait>(ctx: &Context, db: &C, guild_id: Option<GuildId>, channel_id: ChannelId) -> Result<Self> {
+		let settings = match guild_id {
+			Some(guild_id) => load_guild_settings(db, guild_id).await?,
+			None => None,
+		};
+		let settings = match settings {
+			Some(settings) if settings.reactions || settings.polls || settings.threads || settings.pins => settings,
+
+			// no need to ask Discord for permissions, if nothing is enabled anyway
+			_ => return Ok(Self::default()),
+		};
+
+		let channel = guild_channel(ctx, channel_id).await?;
+		let in_thread = matches!(
+			channel.kind,
+			ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+		);
+		let bot_id = ctx.cache.current_user().id;
+		let permissions = permissions_in(ctx, channel, bot_id).await?;
+
+		Ok(Self::allowed(&settings, permissions, in_thread))
+	}
+
+	/// Actions enabled in the settings, which the bot has the permissions for.
+	fn allowed(settings: &guild_settings::Model, permissions: Permissions, in_thread: bool) -> Self {
+		// threads have their own permission for sending messages
+		let send_messages = if in_thread {
+			Permissions::SEND_MESSAGES_IN_THREADS
+		} else {
+			Permissions::SEND_MESSAGES
+		};
+
+		let reactions = Permissions::ADD_REACTIONS | Permissions::READ_MESSAGE_HISTORY;
+		let polls = reactions | send_messages | Permissions::EMBED_LINKS;
+		let threads = Permissions::CREATE_PUBLIC_THREADS | Permissions::SEND_MESSAGES_IN_THREADS;
+		let pins = Permissions::MANAGE_MESSAGES | Permissions::READ_MESSAGE_HISTORY;
+
+		Self {
+			reactions: settings.reactions && permissions.contains(reactions),
+			polls: settings.polls && permissions.contains(polls),
+			// threads can't be nested
+			threads: settings.threads && permissions.contains(threads) && !in_thread,
+			pins: settings.pins && permissions.contains(pins),
+		}
+	}
+}
+
+/// Fetches the settings of a guild. Guilds without settings use the defaults.
+pub async fn load_guild_settings<C: ConnectionTrait>(db: &C, guild_id: GuildId) -> Result<Option<guild_settings::Model>> {
+	entity::prelude::GuildSettings::find()
+		.filter(guild_settings::Column::DiscordGuildId.eq(guild_id.get()))
+		.one(db)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to fetch guild settings from database")
+}
+
+/// Changes the settings of a guild, creating them with the defaults first if the guild has none yet.
+pub async fn update_guild_settings<C: ConnectionTrait>(
+	db: &C,
+	guild_id: GuildId,
+	update: impl FnOnce(&mut guild_settings::ActiveModel),
+) -> Result<guild_settings::Model> {
+	let mut settings = match load_guild_settings(db, guild_id).await? {
+		Some(settings) => settings.into_active_model(),
+		None => guild_settings::ActiveModel {
+			discord_guild_id: Set(guild_id.get()),
+			..Default::default()
+		},
+	};
+	update(&mut settings);
+
+	let settings = settings
+		.save(db)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to save guild settings")?;
+	settings.try_into_model().into_diagnostic()
+}
+
+/// Reacts to the message which triggered the invocation.
+pub struct AddReaction;
+
+#[derive(Deserialize)]
+struct AddReactionArguments {
+	emoji: String,
+}
+
+impl Tool for AddReaction {
+	fn name(&self) -> &'static str {
+		"add_reaction"
+	}
+
+	fn description(&self) -> &'static str {
+		"React to the message you are answering with an emoji."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"emoji": {
+					"type": "string",
+					"description": "A unicode emoji, or the name of a custom emoji of the server, like :name:",
+				},
+			},
+			"required": ["emoji"],
+		})
+	}
+
+	fn is_available(&self, actions: &DiscordActions) -> bool {
+		actions.reactions
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: AddReactionArguments = parse_arguments(self, arguments)?;
+			let reaction = reaction_type(ctx, &arguments.emoji).await?;
+
+			ctx
+				.channel_id
+				.create_reaction(&ctx.ctx.http, ctx.message_id, reaction)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to add reaction")?;
+
+			Ok(json!({ "reacted_with": arguments.emoji }))
+		})
+	}
+}
+
+/// Resolves an emoji given by the model. Custom emojis are only known to the model by name.
+async fn reaction_type(ctx: &ToolContext, emoji: &str) -> Result<ReactionType> {
+	let emoji = emoji.trim();
+
+	let name = emoji
+		.strip_prefix(':')
+		.and_then(|name| name.strip_suffix(':'))
+		.filter(|name| !name.is_empty());
+	if let (Some(name), Some(guild_id)) = (name, ctx.guild_id) {
+		let emojis = guild_id
+			.emojis(&ctx.ctx.http)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch emojis of guild")?;
+
+		return emojis
+			.into_iter()
+			.find(|custom| custom.name == name)
+			.map(ReactionType::from)
+			.ok_or_else(|| miette!("This server has no emoji named '{}'", name));
+	}
+
+	ReactionType::try_from(emoji).map_err(|_| miette!("'{}' is not a valid emoji", emoji))
+}
+
+/// Posts a poll in the channel of the invocation, which is answered with reactions.
+pub struct CreatePoll;
+
+#[derive(Deserialize)]
+struct CreatePollArguments {
+	question: String,
+	options: Vec<String>,
+}
+
+impl Tool for CreatePoll {
+	fn name(&self) -> &'static str {
+		"create_poll"
+	}
+
+	fn description(&self) -> &'static str {
+		"Post a poll in the current channel. Users vote by reacting to it."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"question": {
+					"type": "string",
+					"description": "The question of the poll",
+				},
+				"options": {
+					"type": "array",
+					"items": { "type": "string" },
+					"minItems": 2,
+					"maxItems": MAX_POLL_OPTIONS,
+					"description": "The options users can vote for",
+				},
+			},
+			"required": ["question", "options"],
+		})
+	}
+
+	fn is_available(&self, actions: &DiscordActions) -> bool {
+		actions.polls
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: CreatePollArguments = parse_arguments(self, arguments)?;
+			if !(2..=MAX_POLL_OPTIONS).contains(&arguments.options.len()) {
+				return Err(miette!("A poll needs between 2 and {} options", MAX_POLL_OPTIONS));
+			}
+
+			let poll = CreateMessage::new().embed(
+				CreateEmbed::new()
+					.title(truncate_chars(&arguments.question, 256))
+					.description(poll_description(&arguments.options)),
+			);
+			let poll = ctx
+				.channel_id
+				.send_message(&ctx.ctx.http, poll)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to post poll")?;
+
+			for index in 0..arguments.options.len() {
+				poll
+					.react(&ctx.ctx.http, ReactionType::Unicode(poll_emoji(index)))
+					.await
+					.into_diagnostic()
+					.wrap_err("failed to add poll option")?;
+			}
+
+			Ok(json!({ "poll": poll.link() }))
+		})
+	}
+}
+
+/// Emoji users react with to vote for an option.
+fn poll_emoji(index: usize) -> String {
+	char::from_u32('🇦' as u32 + index as u32)
+		.expect("poll options are limited to valid regional indicators")
+		.to_string()
+}
+
+fn poll_description(options: &[String]) -> String {
+	options
+		.iter()
+		.enumerate()
+		.map(|(index, option)| format!("{} {}", poll_emoji(index), option.trim()))
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// Opens a thread on the message which triggered the invocation.
+pub struct OpenThread;
+
+#[derive(Deserialize)]
+struct OpenThreadArguments {
+	name: String,
+}
+
+impl Tool for OpenThread {
+	fn name(&self) -> &'static str {
+		"open_thread"
+	}
+
+	fn description(&self) -> &'static str {
+		"Open a thread on the message you are answering, to move a long discussion out of the channel."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"name": {
+					"type": "string",
+					"description": "Short name of the thread, describing the topic",
+				},
+			},
+			"required": ["name"],
+		})
+	}
+
+	fn is_available(&self, actions: &DiscordActions) -> bool {
+		actions.threads
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: OpenThreadArguments = parse_arguments(self, arguments)?;
+			let name = arguments.name.trim();
+			if name.is_empty() {
+				return Err(miette!("The name of the thread must not be empty"));
+			}
+
+			let thread =
+				CreateThread::new(truncate_chars(name, MAX_THREAD_NAME_LENGTH)).auto_archive_duration(AutoArchiveDuration::OneDay);
+			let thread = ctx
+				.channel_id
+				.create_thread_from_message(&ctx.ctx, ctx.message_id, thread)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to open thread")?;
+
+			Ok(json!({ "thread": format!("<#{}>", thread.id) }))
+		})
+	}
+}
+
+/// Pins a message of the channel of the invocation.
+pub struct PinMessage;
+
+#[derive(Deserialize)]
+struct PinMessageArguments {
+	link: Option<String>,
+}
+
+impl Tool for PinMessage {
+	fn name(&self) -> &'static str {
+		"pin_message"
+	}
+
+	fn description(&self) -> &'static str {
+		"Pin a message of the current channel. Pins the message you are answering, unless a link is given."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"link": {
+					"type": "string",
+					"description": "Link to the message to pin, which has to be in the current channel",
+				},
+			},
+		})
+	}
+
+	fn is_available(&self, actions: &DiscordActions) -> bool {
+		actions.pins
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: PinMessageArguments = parse_arguments(self, arguments)?;
+
+			let message_id = match arguments.link.as_deref().map(str::trim).filter(|link| !link.is_empty()) {
+				None => ctx.message_id,
+				Some(link) => match parse_message_link(link) {
+					Some((_, channel_id, message_id)) if channel_id == ctx.channel_id => message_id,
+					Some(_) => return Err(miette!("Only messages of the current channel can be pinned")),
+					None => return Err(miette!("'{}' is not a link to a Discord message", link)),
+				},
+			};
+
+			ctx
+				.channel_id
+				.pin(&ctx.ctx.http, message_id)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to pin message")?;
+
+			Ok(json!({ "pinned": message_id.link(ctx.channel_id, ctx.guild_id) }))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use sea_orm::{
+		DatabaseBackend,
+		MockDatabase,
+		MockExecResult,
+	};
+
+	use super::*;
+
+	fn guild_settings() -> guild_settings::Model {
+		guild_settings::Model {
+			id: 1,
+			discord_guild_id: 1,
+			reactions: true,
+			polls: true,
+			threads: true,
+			pins: false,
+			ranked_history: false,
+		}
+	}
+
+	#[test]
+	fn test_allowed() {
+		let settings = guild_settings();
+		let permissions = Permissions::ADD_REACTIONS
+			| Permissions::READ_MESSAGE_HISTORY
+			| Permissions::SEND_MESSAGES
+			| Permissions::EMBED_LINKS
+			| Permissions::CREATE_PUBLIC_THREADS
+			| Permissions::SEND_MESSAGES_IN_THREADS
+			| Permissions::MANAGE_MESSAGES;
+
+		assert_eq!(DiscordActions::allowed(&settings, permissions, false), DiscordActions {
+			reactions: true,
+			polls: true,
+			threads: true,
+			pins: false,
+		});
+
+		// no nested threads, and polls need permission to send messages in threads
+		let in_thread = permissions - Permissions::SEND_MESSAGES_IN_THREADS;
+		assert_eq!(DiscordActions::allowed(&settings, in_thread, true), DiscordActions {
+			reactions: true,
+			..Default::default()
+		});
+
+		assert_eq!(
+			DiscordActions::allowed(&settings, Permissions::SEND_MESSAGES, false),
+			DiscordActions::default()
+		);
+	}
+
+	#[tokio::test]
+	async fn test_update_guild_settings() {
+		let created = guild_settings::Model {
+			id: 2,
+			discord_guild_id: 2,
+			reactions: false,
+			polls: false,
+			threads: false,
+			pins: true,
+			ranked_history: false,
+		};
+		let db = MockDatabase::new(DatabaseBackend::MySql)
+			.append_query_results([Vec::new(), vec![created.clone()]])
+			.append_exec_results([MockExecResult {
+				last_insert_id: 2,
+				rows_affected: 1,
+			}])
+			.into_connection();
+
+		let settings = update_guild_settings(&db, GuildId::new(2), |settings| settings.pins = Set(true))
+			.await
+			.unwrap();
+		assert_eq!(settings, created);
+
+		let log = format!("{:?}", db.into_transaction_log());
+		assert!(
+			log.contains("INSERT INTO `guild_settings` (`discord_guild_id`, `pins`)"),
+			"{}",
+			log
+		);
+
+		let updated = guild_settings::Model {
+			ranked_history: true,
+			..guild_settings()
+		};
+		let db = MockDatabase::new(DatabaseBackend::MySql)
+			.append_query_results([vec![guild_settings()], vec![updated.clone()]])
+			.append_exec_results([MockExecResult {
+				last_insert_id: 0,
+				rows_affected: 1,
+			}])
+			.into_connection();
+
+		let settings = update_guild_settings(&db, GuildId::new(1), |settings| settings.ranked_history = Set(true))
+			.await
+			.unwrap();
+		assert_eq!(settings, updated);
+
+		let log = format!("{:?}", db.into_transaction_log());
+		assert!(log.contains("UPDATE `guild_settings` SET `ranked_history`"), "{}", log);
+	}
+
+	#[test]
+	fn test_poll_description() {
+		let options = vec!["Pizza".to_string(), " Pasta ".to_string()];
+		assert_eq!(poll_description(&options), "🇦 Pizza\n🇧 Pasta");
+		assert_eq!(poll_emoji(MAX_POLL_OPTIONS - 1), "🇯");
+	}
+}
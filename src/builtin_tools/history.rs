@@ -0,0 +1,518 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+};
+
+use chrono::{
+	DateTime,
+	NaiveDate,
+	Utc,
+};
+use futures::future::BoxFuture;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::serenity_prelude::{
+	ChannelId,
+	GetMessages,
+	GuildId,
+	Message,
+	MessageId,
+	UserId,
+};
+use sea_orm::ConnectionTrait;
+use serde::Deserialize;
+use serde_json::{
+	Value,
+	json,
+};
+
+use super::{
+	Tool,
+	ToolContext,
+	parse_arguments,
+};
+use crate::{
+	channel_access::{
+		guild_channel,
+		permissions_in,
+	},
+	text::truncate_chars,
+	user_from_db_or_create,
+};
+
+/// Discord returns at most 100 messages per request.
+const PAGE_SIZE: u8 = 100;
+
+/// Limits how far back a single search goes, messages older than the channel history are fetched from Discord in pages.
+const MAX_SEARCHED_MESSAGES: usize = 1000;
+
+const DEFAULT_RESULTS: usize = 10;
+const MAX_RESULTS: usize = 25;
+
+/// Length of the excerpt shown for each matching message.
+const EXCERPT_LENGTH: usize = 200;
+
+/// First second of 2015, the epoch of Discord snowflakes, in milliseconds.
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+/// Searches older messages of the channel of the invocation, which are not part of the context.
+pub struct SearchHistory;
+
+#[derive(Deserialize)]
+struct Arguments {
+	query: Option<String>,
+	author: Option<String>,
+	after: Option<String>,
+	before: Option<String>,
+	limit: Option<usize>,
+}
+
+/// Criteria a message has to match to be found.
+#[derive(Debug, Default)]
+struct Filter {
+	/// Lowercase keywords, which all have to be contained in the message.
+	keywords: Vec<String>,
+
+	/// Lowercase name of the author.
+	author: Option<String>,
+
+	after: Option<DateTime<Utc>>,
+	before: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+	fn new(arguments: &Arguments) -> Result<Self> {
+		let keywords = arguments
+			.query
+			.as_deref()
+			.unwrap_or_default()
+			.split_whitespace()
+			.map(str::to_lowercase)
+			.collect();
+		let author = arguments
+			.author
+			.as_deref()
+			.map(|author| author.trim().trim_start_matches('@').to_lowercase())
+			.filter(|author| !author.is_empty());
+
+		Ok(Self {
+			keywords,
+			author,
+			after: arguments.after.as_deref().map(parse_date).transpose()?,
+			before: arguments.before.as_deref().map(parse_date).transpose()?,
+		})
+	}
+
+	/// Whether a message matches, given its content, the names of its author and the time it was sent.
+	fn matches(&self, content: &str, author_names: &[&str], timestamp: DateTime<Utc>) -> bool {
+		if self.after.is_some_and(|after| timestamp < after) || self.before.is_some_and(|before| timestamp >= before) {
+			return false;
+		}
+
+		if let Some(author) = &self.author {
+			if !author_names.iter().any(|name| name.to_lowercase() == *author) {
+				return false;
+			}
+		}
+
+		let content = content.to_lowercase();
+		self.keywords.iter().all(|keyword| content.contains(keyword.as_str()))
+	}
+}
+
+impl Tool for SearchHistory {
+	fn name(&self) -> &'static str {
+		"search_history"
+	}
+
+	fn description(&self) -> &'static str {
+		"Search older messages of the current channel by keywords, author and date. Returns excerpts with links, newest first. Only \
+		 the most recent 1000 messages are searched."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"query": {
+					"type": "string",
+					"description": "Keywords which all have to appear in a message, case insensitive",
+				},
+				"author": {
+					"type": "string",
+					"description": "Username of the author",
+				},
+				"after": {
+					"type": "string",
+					"description": "Only messages sent at or after this time, as date (2024-07-01) or RFC 3339 timestamp",
+				},
+				"before": {
+					"type": "string",
+					"description": "Only messages sent before this time, as date (2024-07-01) or RFC 3339 timestamp",
+				},
+				"limit": {
+					"type": "integer",
+					"description": format!("Maximum number of results, at most {}", MAX_RESULTS),
+				},
+			},
+		})
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: Arguments = parse_arguments(self, arguments)?;
+			let filter = Filter::new(&arguments)?;
+			let limit = arguments.limit.unwrap_or(DEFAULT_RESULTS).clamp(1, MAX_RESULTS);
+
+			// history is only visible to members allowed to read it, in DMs it's always the user's own history
+			if ctx.guild_id.is_some() {
+				let channel = guild_channel(&ctx.ctx, ctx.channel_id).await?;
+				let permissions = permissions_in(&ctx.ctx, channel, ctx.user_id).await?;
+				if !permissions.read_message_history() {
+					return Err(miette!("The user can't read the message history of this channel"));
+				}
+			}
+
+			search(ctx, &filter, limit).await
+		})
+	}
+}
+
+/// Collects matches while going through the history of the channel, newest first.
+struct Search<'a> {
+	filter: &'a Filter,
+	limit: usize,
+
+	/// Where the search happens, to link the results.
+	channel_id: ChannelId,
+	guild_id: Option<GuildId>,
+
+	/// Whether authors opted out, looked up once per author.
+	opted_out: HashMap<UserId, bool>,
+	results: Vec<Value>,
+	searched: usize,
+	oldest: Option<DateTime<Utc>>,
+}
+
+impl<'a> Search<'a> {
+	fn new(filter: &'a Filter, limit: usize, channel_id: ChannelId, guild_id: Option<GuildId>) -> Self {
+		Self {
+			filter,
+			limit,
+			channel_id,
+			guild_id,
+			opted_out: HashMap::new(),
+			results: Vec::new(),
+			searched: 0,
+			oldest: None,
+		}
+	}
+
+	/// Number of messages which may still be searched.
+	fn remaining(&self) -> usize {
+		MAX_SEARCHED_MESSAGES.saturating_sub(self.searched)
+	}
+
+	/// Searches the given messages, which have to be older than the ones searched before, newest first. Returns whether
+	/// the search is done, because enough matches were found or the messages are older than the filter allows.
+	async fn add<C: ConnectionTrait>(&mut self, db: &C, messages: &[Message]) -> Result<bool> {
+		for message in messages {
+			if self.remaining() == 0 {
+				return Ok(true);
+			}
+
+			let timestamp = *message.timestamp;
+			if self.filter.after.is_some_and(|after| timestamp < after) {
+				return Ok(true);
+			}
+			self.searched += 1;
+			self.oldest = Some(timestamp);
+
+			if !self.filter.matches(&message.content, &author_names(message), timestamp) {
+				continue;
+			}
+
+			// same as with the context, messages of users who opted out are never passed to the model
+			let author_opted_out = match self.opted_out.get(&message.author.id) {
+				Some(opted_out) => *opted_out,
+				None => {
+					let author = user_from_db_or_create(db, &message.author).await?;
+					let author_opted_out = author.opt_out_since.is_some();
+					self.opted_out.insert(message.author.id, author_opted_out);
+					author_opted_out
+				},
+			};
+			if author_opted_out {
+				continue;
+			}
+
+			self.results.push(json!({
+				"author": message.author.name,
+				"timestamp": message.timestamp.to_rfc3339(),
+				"excerpt": excerpt(&message.content, &self.filter.keywords, EXCERPT_LENGTH),
+				// messages fetched via HTTP don't know their guild, so the link has to be built from the invocation
+				"link": message.id.link(self.channel_id, self.guild_id),
+			}));
+			if self.results.len() >= self.limit {
+				return Ok(true);
+			}
+		}
+
+		Ok(self.remaining() == 0)
+	}
+
+	fn into_result(self) -> Value {
+		json!({
+			"results": self.results,
+			"searched_messages": self.searched,
+			"searched_back_to": self.oldest.map(|oldest| oldest.to_rfc3339()),
+		})
+	}
+}
+
+/// Goes through the history of the channel, newest first, until enough matches are found.
+async fn search(ctx: &ToolContext, filter: &Filter, limit: usize) -> Result<Value> {
+	let mut search = Search::new(filter, limit, ctx.channel_id, ctx.guild_id);
+
+	// skip messages which are too recent anyway, the message the user is asking about is never included
+	let before = match filter.before {
+		Some(before) => snowflake_at(before).min(ctx.message_id),
+		None => ctx.message_id,
+	};
+
+	let history = ctx.channel_history.all_before(ctx.channel_id, ctx.message_id);
+	search_channel(&mut search, ctx.db.as_ref(), history, before, |before, limit| async move {
+		ctx
+			.channel_id
+			.messages(&ctx.ctx, GetMessages::new().before(before).limit(limit))
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch message history")
+	})
+	.await?;
+
+	Ok(search.into_result())
+}
+
+/// Searches the messages sent before `before`, taken from the in-memory `history` as returned by
+/// [`ChannelHistory::all_before`] first. Older messages are fetched in pages with `fetch_page`, continuing right where
+/// the history ends. The message cache is no help here, it only holds scattered messages, like replies from long ago.
+///
+/// [`ChannelHistory::all_before`]: crate::channel_history::ChannelHistory::all_before
+async fn search_channel<C, F, P>(
+	search: &mut Search<'_>,
+	db: &C,
+	history: Option<(Vec<Message>, bool)>,
+	mut before: MessageId,
+	mut fetch_page: F,
+) -> Result<()>
+where
+	C: ConnectionTrait,
+	F: FnMut(MessageId, u8) -> P,
+	P: Future<Output = Result<Vec<Message>>>,
+{
+	if let Some((messages, complete)) = history {
+		let messages = messages.into_iter().filter(|message| message.id < before).collect::<Vec<_>>();
+		if search.add(db, &messages).await? || complete {
+			return Ok(());
+		}
+		before = messages.last().map_or(before, |message| message.id);
+	}
+
+	loop {
+		let page_size = search.remaining().min(PAGE_SIZE as usize) as u8;
+		let page = fetch_page(before, page_size).await?;
+		let Some(last) = page.last() else {
+			break;
+		};
+		before = last.id;
+
+		if search.add(db, &page).await? || page.len() < page_size as usize {
+			break;
+		}
+	}
+
+	Ok(())
+}
+
+fn author_names(message: &Message) -> Vec<&str> {
+	let mut names = vec![message.author.name.as_str()];
+	names.extend(message.author.global_name.as_deref());
+	names.extend(message.member.as_ref().and_then(|member| member.nick.as_deref()));
+	names
+}
+
+/// Parses a date or timestamp given by the model. Dates refer to the start of the day in UTC.
+fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+	let date = date.trim();
+	if let Ok(timestamp) = DateTime::parse_from_rfc3339(date) {
+		return Ok(timestamp.with_timezone(&Utc));
+	}
+
+	NaiveDate::parse_from_str(date, "%Y-%m-%d")
+		.ok()
+		.and_then(|date| date.and_hms_opt(0, 0, 0))
+		.map(|date| date.and_utc())
+		.ok_or_else(|| miette!("'{}' is neither a date like 2024-07-01 nor an RFC 3339 timestamp", date))
+}
+
+/// The first possible message ID at the given time, used to start paging from there.
+fn snowflake_at(time: DateTime<Utc>) -> MessageId {
+	let millis = (time.timestamp_millis() - DISCORD_EPOCH).max(1) as u64;
+	MessageId::new(millis << 22)
+}
+
+/// Cuts a window of the content around the first keyword, so long messages don't fill the result.
+fn excerpt(content: &str, keywords: &[String], max_length: usize) -> String {
+	let content = content.split_whitespace().collect::<Vec<_>>().join(" ");
+	if content.chars().count() <= max_length {
+		return content;
+	}
+
+	let lowercase = content.to_lowercase();
+	let first_match = keywords
+		.iter()
+		.filter_map(|keyword| lowercase.find(keyword.as_str()))
+		.min()
+		// lowercasing can change byte offsets, so we count in characters of the lowercase content
+		.map(|index| lowercase[..index].chars().count())
+		.unwrap_or(0);
+
+	// keep some context in front of the match
+	let start = first_match.saturating_sub(max_length / 4);
+	let window = content.chars().skip(start).collect::<String>();
+	let window = truncate_chars(&window, max_length);
+
+	if start > 0 { format!("…{}", window) } else { window }
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+	use sea_orm::{
+		DatabaseBackend,
+		MockDatabase,
+	};
+
+	use super::*;
+
+	fn message(id: u64, content: &str) -> Message {
+		let mut message = Message::default();
+		message.id = MessageId::new(id);
+		message.author.id = UserId::new(1);
+		message.author.name = "alex".to_string();
+		message.content = content.to_string();
+		message
+	}
+
+	fn filter(query: Option<&str>, author: Option<&str>, after: Option<&str>, before: Option<&str>) -> Filter {
+		Filter::new(&Arguments {
+			query: query.map(str::to_string),
+			author: author.map(str::to_string),
+			after: after.map(str::to_string),
+			before: before.map(str::to_string),
+			limit: None,
+		})
+		.unwrap()
+	}
+
+	#[test]
+	fn test_filter() {
+		let time = Utc.with_ymd_and_hms(2024, 7, 10, 12, 0, 0).unwrap();
+		let content = "The Release is planned for Friday";
+
+		assert!(filter(None, None, None, None).matches(content, &["alex"], time));
+		assert!(filter(Some("release friday"), None, None, None).matches(content, &["alex"], time));
+		assert!(!filter(Some("release monday"), None, None, None).matches(content, &["alex"], time));
+
+		assert!(filter(None, Some("@Alex"), None, None).matches(content, &["bob", "Alex"], time));
+		assert!(!filter(None, Some("alex"), None, None).matches(content, &["bob"], time));
+
+		assert!(filter(None, None, Some("2024-07-10"), Some("2024-07-11")).matches(content, &[], time));
+		assert!(!filter(None, None, Some("2024-07-11"), None).matches(content, &[], time));
+		assert!(!filter(None, None, None, Some("2024-07-10T12:00:00Z")).matches(content, &[], time));
+	}
+
+	#[test]
+	fn test_parse_date() {
+		assert_eq!(
+			parse_date("2024-07-01").unwrap(),
+			Utc.with_ymd_and_hms(2024, 7, 1, 0, 0, 0).unwrap()
+		);
+		assert_eq!(
+			parse_date("2024-07-01T14:30:00+02:00").unwrap(),
+			Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap()
+		);
+		assert!(parse_date("last week").is_err());
+	}
+
+	#[test]
+	fn test_snowflake_at() {
+		// snowflake of a known message, sent at 2016-04-30 11:18:25.796 UTC
+		let time = Utc.timestamp_millis_opt(1_462_015_105_796).unwrap();
+		assert_eq!(snowflake_at(time).get() >> 22, 175928847299117063 >> 22);
+	}
+
+	#[test]
+	fn test_excerpt() {
+		assert_eq!(excerpt("short  message\nhere", &[], 50), "short message here");
+
+		let content = format!("{} release {}", "a ".repeat(100), "b ".repeat(100));
+		let excerpt = excerpt(&content, &["release".to_string()], 40);
+		assert!(excerpt.starts_with('…'));
+		assert!(excerpt.contains("release"));
+		assert_eq!(excerpt.chars().count(), 41);
+	}
+
+	#[tokio::test]
+	async fn test_search_channel() {
+		let db = MockDatabase::new(DatabaseBackend::MySql).into_connection();
+		let filter = filter(Some("deploy"), None, None, None);
+		let mut search = Search::new(&filter, 10, ChannelId::new(1), None);
+		search.opted_out.insert(UserId::new(1), false);
+
+		// the channel history reaches back to message 97, everything older is only known to Discord
+		let content = |id| match id {
+			98 | 60 | 3 => "deploy failed",
+			_ => "chatter",
+		};
+		let history = (97..100).rev().map(|id| message(id, content(id))).collect::<Vec<_>>();
+		let channel = (1..97).rev().map(|id| message(id, content(id))).collect::<Vec<_>>();
+
+		// an old message, like a reply the message cache may hold, doesn't hide the messages in between
+		let mut requests = Vec::new();
+		search_channel(
+			&mut search,
+			&db,
+			Some((history, false)),
+			MessageId::new(100),
+			|before, limit| {
+				requests.push(before);
+				let page = channel
+					.iter()
+					.filter(|message| message.id < before)
+					.take(limit as usize)
+					.cloned()
+					.collect();
+				std::future::ready(Ok(page))
+			},
+		)
+		.await
+		.unwrap();
+
+		assert_eq!(requests, vec![MessageId::new(97)]);
+		let result = search.into_result();
+		assert_eq!(result["searched_messages"], 99);
+		let links = result["results"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|result| result["link"].as_str().unwrap().rsplit('/').next().unwrap().to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(links, vec!["98", "60", "3"]);
+	}
+}
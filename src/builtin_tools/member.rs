@@ -0,0 +1,154 @@
+use futures::future::BoxFuture;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::serenity_prelude::{
+	GuildId,
+	Member,
+	UserId,
+};
+use serde::Deserialize;
+use serde_json::{
+	Value,
+	json,
+};
+
+use super::{
+	Tool,
+	ToolContext,
+	parse_arguments,
+};
+
+/// Number of members fetched when searching for a member by name.
+const MEMBER_SEARCH_LIMIT: u64 = 10;
+
+/// Looks up the public profile of a member of the current guild.
+pub struct MemberProfile;
+
+#[derive(Deserialize)]
+struct Arguments {
+	user: String,
+}
+
+impl Tool for MemberProfile {
+	fn name(&self) -> &'static str {
+		"member_profile"
+	}
+
+	fn description(&self) -> &'static str {
+		"Look up the public profile of a member of the current server: names, roles, avatar, and when they joined."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"user": {
+					"type": "string",
+					"description": "Username, nickname or ID of the member",
+				},
+			},
+			"required": ["user"],
+		})
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: Arguments = parse_arguments(self, arguments)?;
+			let guild_id = ctx
+				.guild_id
+				.ok_or_else(|| miette!("Members can only be looked up in servers"))?;
+
+			let member = find_member(ctx, guild_id, &arguments.user)
+				.await?
+				.ok_or_else(|| miette!("No member found for '{}'", arguments.user))?;
+
+			if ctx.opted_out(&member.user).await? {
+				return Err(miette!("This member opted out, so their profile can't be used"));
+			}
+
+			let guild = guild_id
+				.to_partial_guild(&ctx.ctx)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to get guild")?;
+			let roles = member
+				.roles
+				.iter()
+				.filter_map(|role_id| guild.roles.get(role_id))
+				.map(|role| role.name.clone())
+				.collect::<Vec<_>>();
+
+			Ok(json!({
+				"username": member.user.name,
+				"global_name": member.user.global_name,
+				"nickname": member.nick,
+				"bot": member.user.bot,
+				"account_created": member.user.created_at().to_rfc3339(),
+				"joined_at": member.joined_at.map(|joined_at| joined_at.to_rfc3339()),
+				"roles": roles,
+				"avatar_url": member.face(),
+			}))
+		})
+	}
+}
+
+/// Finds a member by ID, mention or name. Exact matches of names are preferred over partial ones.
+async fn find_member(ctx: &ToolContext, guild_id: GuildId, user: &str) -> Result<Option<Member>> {
+	if let Some(user_id) = parse_user_id(user) {
+		let member = guild_id.member(&ctx.ctx, user_id).await.ok();
+		return Ok(member);
+	}
+
+	let name = user.trim().trim_start_matches('@');
+	let members = guild_id
+		.search_members(&ctx.ctx.http, name, Some(MEMBER_SEARCH_LIMIT))
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to search members")?;
+
+	let is_exact = |member: &&Member| {
+		[
+			Some(&member.user.name),
+			member.user.global_name.as_ref(),
+			member.nick.as_ref(),
+		]
+		.into_iter()
+		.flatten()
+		.any(|candidate| candidate.eq_ignore_ascii_case(name))
+	};
+	let exact = members.iter().find(is_exact).cloned();
+
+	Ok(exact.or_else(|| members.into_iter().next()))
+}
+
+/// Parses a user ID, either given as is or as mention.
+fn parse_user_id(user: &str) -> Option<UserId> {
+	let user = user.trim();
+	let id = user
+		.strip_prefix("<@")
+		.and_then(|id| id.strip_suffix('>'))
+		.map(|id| id.trim_start_matches('!'))
+		.unwrap_or(user);
+
+	// snowflakes are never zero, creating ids from zero panics
+	id.parse::<u64>().ok().filter(|id| *id != 0).map(UserId::new)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_user_id() {
+		assert_eq!(parse_user_id("123"), Some(UserId::new(123)));
+		assert_eq!(parse_user_id(" <@123> "), Some(UserId::new(123)));
+		assert_eq!(parse_user_id("<@!123>"), Some(UserId::new(123)));
+		assert_eq!(parse_user_id("alice"), None);
+		assert_eq!(parse_user_id("@alice"), None);
+		assert_eq!(parse_user_id("0"), None);
+	}
+}
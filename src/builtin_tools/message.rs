@@ -0,0 +1,99 @@
+use futures::future::BoxFuture;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use serde::Deserialize;
+use serde_json::{
+	Value,
+	json,
+};
+
+use super::{
+	Tool,
+	ToolContext,
+	parse_arguments,
+};
+use crate::{
+	channel_access::can_read_history,
+	message_link::parse_message_link,
+};
+
+/// Fetches a Discord message by its link, so the model can see what users are referring to.
+pub struct FetchMessage;
+
+#[derive(Deserialize)]
+struct Arguments {
+	link: String,
+}
+
+impl Tool for FetchMessage {
+	fn name(&self) -> &'static str {
+		"fetch_message"
+	}
+
+	fn description(&self) -> &'static str {
+		"Fetch a Discord message by its link, returning author, time, content and attachments. Only messages from the current server \
+		 can be fetched."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"link": {
+					"type": "string",
+					"description": "Link to the message, like https://discord.com/channels/<server>/<channel>/<message>",
+				},
+			},
+			"required": ["link"],
+		})
+	}
+
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: Arguments = parse_arguments(self, arguments)?;
+			let (guild_id, channel_id, message_id) =
+				parse_message_link(&arguments.link).ok_or_else(|| miette!("'{}' is not a link to a Discord message", arguments.link))?;
+
+			if guild_id != ctx.guild_id {
+				return Err(miette!("Only messages from the current server can be fetched"));
+			}
+			if !can_read_history(&ctx.ctx, guild_id, channel_id, ctx.user_id, ctx.channel_id).await? {
+				return Err(miette!("The user can't read messages in this channel"));
+			}
+
+			let message = channel_id
+				.message(&ctx.ctx, message_id)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to fetch message")?;
+
+			if ctx.opted_out(&message.author).await? {
+				return Err(miette!("The author of this message opted out, so it can't be used"));
+			}
+
+			let attachments = message
+				.attachments
+				.iter()
+				.map(|attachment| {
+					json!({
+						"filename": attachment.filename,
+						"url": attachment.url,
+					})
+				})
+				.collect::<Vec<_>>();
+
+			Ok(json!({
+				"author": message.author.name,
+				"timestamp": message.timestamp.to_rfc3339(),
+				"edited": message.edited_timestamp.is_some(),
+				"content": message.content,
+				"attachments": attachments,
+				"embeds": message.embeds.len(),
+			}))
+		})
+	}
+}
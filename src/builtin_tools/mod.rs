@@ -0,0 +1,239 @@
+mod actions;
+mod history;
+mod member;
+mod message;
+mod time;
+
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use llm::{
+	FunctionCall,
+	chat::{
+		FunctionTool,
+		Tool as LlmTool,
+	},
+};
+use log::warn;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+};
+use poise::serenity_prelude::{
+	ChannelId,
+	Context,
+	GuildId,
+	Message,
+	MessageId,
+	User,
+	UserId,
+};
+use sea_orm::DatabaseConnection;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+pub use self::actions::{
+	DiscordActions,
+	load_guild_settings,
+	update_guild_settings,
+};
+use crate::{
+	channel_history::ChannelHistory,
+	mcp::ToolCallOutput,
+	user_from_db_or_create,
+};
+
+/// A tool implemented by the bot itself, offered to the model next to the tools of MCP servers.
+/// Unlike MCP servers, built-in tools have access to bot internals, like the Discord client and the database.
+pub trait Tool: Send + Sync {
+	/// Name of the tool, as it is shown to the model.
+	fn name(&self) -> &'static str;
+
+	/// Tells the model what the tool does and when to use it.
+	fn description(&self) -> &'static str;
+
+	/// JSON schema of the arguments.
+	fn parameters(&self) -> Value;
+
+	/// Whether the tool is offered for an invocation, depending on the Discord actions allowed there.
+	fn is_available(&self, _actions: &DiscordActions) -> bool {
+		true
+	}
+
+	/// Calls the tool with the arguments given by the model. The result is passed back to the model as is.
+	fn call<'a>(&'a self, ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>>;
+}
+
+/// The Discord invocation a tool is called for.
+/// Tools act on behalf of the invoking user, so they must not reveal anything the user couldn't see themselves.
+pub struct ToolContext {
+	pub ctx: Context,
+	pub db: Arc<DatabaseConnection>,
+	pub channel_history: Arc<ChannelHistory>,
+	pub user_id: UserId,
+	pub guild_id: Option<GuildId>,
+	pub channel_id: ChannelId,
+
+	/// The message which triggered the invocation.
+	pub message_id: MessageId,
+
+	/// Discord actions the bot may take in the channel of the invocation.
+	pub actions: DiscordActions,
+}
+
+impl ToolContext {
+	pub async fn new(ctx: Context, db: Arc<DatabaseConnection>, channel_history: Arc<ChannelHistory>, message: &Message) -> Self {
+		// broken settings shouldn't break the reply, the bot just can't take any actions
+		let actions = DiscordActions::load(&ctx, db.as_ref(), message.guild_id, message.channel_id)
+			.await
+			.unwrap_or_else(|err| {
+				warn!("Failed to determine allowed Discord actions: {:?}", err);
+				DiscordActions::default()
+			});
+
+		Self {
+			ctx,
+			db,
+			channel_history,
+			user_id: message.author.id,
+			guild_id: message.guild_id,
+			channel_id: message.channel_id,
+			message_id: message.id,
+			actions,
+		}
+	}
+
+	/// Whether the given user opted out, in which case tools must not return anything about them.
+	async fn opted_out(&self, user: &User) -> Result<bool> {
+		let user = user_from_db_or_create(self.db.as_ref(), user).await?;
+		Ok(user.opt_out_since.is_some())
+	}
+}
+
+/// All built-in tools offered to the model.
+pub struct ToolRegistry {
+	tools: Vec<Box<dyn Tool>>,
+}
+
+impl Default for ToolRegistry {
+	/// Creates a registry with all tools shipped with the bot.
+	fn default() -> Self {
+		let mut registry = Self {
+			tools: Vec::new(),
+		};
+		registry.register(time::CurrentTime);
+		registry.register(message::FetchMessage);
+		registry.register(member::MemberProfile);
+		registry.register(history::SearchHistory);
+		registry.register(actions::AddReaction);
+		registry.register(actions::CreatePoll);
+		registry.register(actions::OpenThread);
+		registry.register(actions::PinMessage);
+		registry
+	}
+}
+
+impl ToolRegistry {
+	pub fn register(&mut self, tool: impl Tool + 'static) {
+		self.tools.push(Box::new(tool));
+	}
+
+	fn find(&self, name: &str) -> Option<&dyn Tool> {
+		self.tools.iter().find(|tool| tool.name() == name).map(|tool| tool.as_ref())
+	}
+
+	/// Adds the built-in tools available for an invocation to the tools offered to the model.
+	/// Tools already in the list take precedence, the model can't tell tools with the same name apart.
+	pub fn merge_llm_tools(&self, actions: &DiscordActions, tools: &mut Vec<LlmTool>) {
+		for tool in &self.tools {
+			if !tool.is_available(actions) || tools.iter().any(|existing| existing.function.name == tool.name()) {
+				continue;
+			}
+
+			tools.push(LlmTool {
+				tool_type: "function".to_string(),
+				function: FunctionTool {
+					name: tool.name().to_string(),
+					description: tool.description().to_string(),
+					parameters: tool.parameters(),
+				},
+			});
+		}
+	}
+
+	/// Calls the built-in tool requested by the model.
+	/// Returns `None` if there is no built-in tool with that name available for the invocation.
+	pub async fn call(&self, ctx: &ToolContext, call: &FunctionCall) -> Option<Result<ToolCallOutput>> {
+		let tool = self.find(&call.name).filter(|tool| tool.is_available(&ctx.actions))?;
+
+		let result = async {
+			let arguments = serde_json::from_str::<Value>(&call.arguments)
+				.into_diagnostic()
+				.wrap_err(format!("Failed to parse tool call arguments for tool '{}'", call.name))?;
+			tool.call(ctx, arguments).await
+		};
+
+		Some(result.await.map(|value| ToolCallOutput {
+			value,
+			images: Vec::new(),
+			attachments: Vec::new(),
+			sources: Vec::new(),
+		}))
+	}
+}
+
+/// Deserializes the arguments of a tool call into the arguments of the tool.
+fn parse_arguments<T: DeserializeOwned>(tool: &dyn Tool, arguments: Value) -> Result<T> {
+	serde_json::from_value(arguments)
+		.into_diagnostic()
+		.wrap_err(format!("Invalid arguments for tool '{}'", tool.name()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_merge_llm_tools() {
+		let registry = ToolRegistry::default();
+
+		let mut tools = vec![LlmTool {
+			tool_type: "function".to_string(),
+			function: FunctionTool {
+				name: "current_time".to_string(),
+				description: "Provided by an MCP server".to_string(),
+				parameters: Value::Null,
+			},
+		}];
+		registry.merge_llm_tools(&DiscordActions::default(), &mut tools);
+
+		let names = tools.iter().map(|tool| tool.function.name.as_str()).collect::<Vec<_>>();
+		assert_eq!(names, vec![
+			"current_time",
+			"fetch_message",
+			"member_profile",
+			"search_history",
+		]);
+		assert_eq!(tools[0].function.description, "Provided by an MCP server");
+
+		// actions are only offered where they are allowed
+		let actions = DiscordActions {
+			reactions: true,
+			pins: true,
+			..Default::default()
+		};
+		let mut tools = Vec::new();
+		registry.merge_llm_tools(&actions, &mut tools);
+
+		let names = tools.iter().map(|tool| tool.function.name.as_str()).collect::<Vec<_>>();
+		assert_eq!(names, vec![
+			"current_time",
+			"fetch_message",
+			"member_profile",
+			"search_history",
+			"add_reaction",
+			"pin_message",
+		]);
+	}
+}
@@ -0,0 +1,97 @@
+use chrono::{
+	DateTime,
+	Utc,
+};
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use miette::{
+	Result,
+	miette,
+};
+use serde::Deserialize;
+use serde_json::{
+	Value,
+	json,
+};
+
+use super::{
+	Tool,
+	ToolContext,
+	parse_arguments,
+};
+
+/// Tells the current time in a given timezone, models have no reliable sense of time.
+pub struct CurrentTime;
+
+#[derive(Deserialize)]
+struct Arguments {
+	timezone: Option<String>,
+}
+
+impl Tool for CurrentTime {
+	fn name(&self) -> &'static str {
+		"current_time"
+	}
+
+	fn description(&self) -> &'static str {
+		"Get the current date and time in a timezone."
+	}
+
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"timezone": {
+					"type": "string",
+					"description": "IANA name of the timezone, like 'Europe/Berlin' or 'America/New_York'. Defaults to UTC.",
+				},
+			},
+		})
+	}
+
+	fn call<'a>(&'a self, _ctx: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<Value>> {
+		Box::pin(async move {
+			let arguments: Arguments = parse_arguments(self, arguments)?;
+			time_in(arguments.timezone.as_deref(), Utc::now())
+		})
+	}
+}
+
+fn time_in(timezone: Option<&str>, now: DateTime<Utc>) -> Result<Value> {
+	let timezone = match timezone.map(str::trim) {
+		None | Some("") => Tz::UTC,
+		Some(name) => name
+			.parse::<Tz>()
+			.map_err(|_| miette!("Unknown timezone '{}', expected an IANA name like 'Europe/Berlin'", name))?,
+	};
+
+	let time = now.with_timezone(&timezone);
+	Ok(json!({
+		"timezone": timezone.name(),
+		"time": time.to_rfc3339(),
+		"weekday": time.format("%A").to_string(),
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	#[test]
+	fn test_time_in() {
+		let now = Utc.with_ymd_and_hms(2024, 7, 1, 12, 30, 0).unwrap();
+
+		assert_eq!(
+			time_in(Some("Europe/Berlin"), now).unwrap(),
+			json!({
+				"timezone": "Europe/Berlin",
+				"time": "2024-07-01T14:30:00+02:00",
+				"weekday": "Monday",
+			})
+		);
+		assert_eq!(time_in(None, now).unwrap()["time"], "2024-07-01T12:30:00+00:00");
+		assert!(time_in(Some("Mars/Olympus_Mons"), now).is_err());
+	}
+}
@@ -0,0 +1,81 @@
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::serenity_prelude::{
+	ChannelId,
+	ChannelType,
+	Context,
+	GuildChannel,
+	GuildId,
+	Permissions,
+	UserId,
+};
+
+/// Fetches a channel of a guild.
+pub async fn guild_channel(ctx: &Context, channel_id: ChannelId) -> Result<GuildChannel> {
+	channel_id
+		.to_channel(ctx)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to get channel")?
+		.guild()
+		.ok_or_else(|| miette!("channel {} is not a guild channel", channel_id))
+}
+
+/// Calculates the permissions of a member in a guild channel. Threads use the permissions of their parent channel.
+pub async fn permissions_in(ctx: &Context, channel: GuildChannel, user_id: UserId) -> Result<Permissions> {
+	let channel = match (channel.kind, channel.parent_id) {
+		(ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread, Some(parent_id)) => {
+			guild_channel(ctx, parent_id).await?
+		},
+		_ => channel,
+	};
+
+	let guild = channel
+		.guild_id
+		.to_partial_guild(ctx)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to get guild")?;
+	let member = channel
+		.guild_id
+		.member(ctx, user_id)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to get member")?;
+
+	Ok(guild.user_permissions_in(&channel, &member))
+}
+
+/// Whether a user can read the message history of a channel in the given guild, or in DMs if there is no guild.
+/// Users can always read the channel they invoked the bot in, but only the channel they invoked the bot in is known
+/// to be readable in DMs and private threads.
+pub async fn can_read_history(
+	ctx: &Context,
+	guild_id: Option<GuildId>,
+	channel_id: ChannelId,
+	user_id: UserId,
+	invocation_channel_id: ChannelId,
+) -> Result<bool> {
+	let guild_id = match guild_id {
+		Some(guild_id) => guild_id,
+		None => return Ok(channel_id == invocation_channel_id),
+	};
+
+	// links can claim any guild, the channel tells where it actually is
+	let channel = guild_channel(ctx, channel_id).await?;
+	if channel.guild_id != guild_id {
+		return Ok(false);
+	}
+
+	// private threads are only visible to their members, which permissions don't tell us
+	if channel.kind == ChannelType::PrivateThread {
+		return Ok(channel_id == invocation_channel_id);
+	}
+
+	let permissions = permissions_in(ctx, channel, user_id).await?;
+	Ok(permissions.view_channel() && permissions.read_message_history())
+}
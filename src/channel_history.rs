@@ -0,0 +1,207 @@
+use std::{
+	collections::VecDeque,
+	num::NonZeroUsize,
+	sync::Mutex,
+};
+
+use lru::LruCache;
+use poise::serenity_prelude::{
+	ChannelId,
+	Message,
+	MessageId,
+	MessageUpdateEvent,
+};
+
+/// Number of channels a history is kept for, the least recently active channels are forgotten first.
+const MAX_CHANNELS: usize = 500;
+
+/// Recent messages of a channel, without gaps from the oldest message up to the most recent one.
+#[derive(Debug, Default)]
+struct ChannelBuffer {
+	/// Messages ordered from oldest to newest.
+	messages: VecDeque<Message>,
+
+	/// Whether the oldest message is the first message of the channel, so there is nothing left to backfill.
+	complete: bool,
+}
+
+impl ChannelBuffer {
+	fn position(&self, message_id: MessageId) -> Result<usize, usize> {
+		self.messages.binary_search_by_key(&message_id, |message| message.id)
+	}
+}
+
+/// Recent history of the channels the bot is in, fed by the messages received from the gateway. This spares fetching
+/// the channel history for every invocation, the REST API is only needed to backfill messages from before the bot
+/// started listening, or after a reconnect lost events.
+pub struct ChannelHistory {
+	channels: Mutex<LruCache<ChannelId, ChannelBuffer>>,
+
+	/// Maximum number of messages kept per channel.
+	capacity: usize,
+}
+
+impl ChannelHistory {
+	/// Creates an empty history, keeping up to `capacity` messages per channel.
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			channels: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_CHANNELS).unwrap())),
+			capacity,
+		}
+	}
+
+	/// Adds a message received from the gateway.
+	pub fn add(&self, message: &Message) {
+		let mut channels = self.channels.lock().unwrap();
+		let buffer = channels.get_or_insert_mut(message.channel_id, ChannelBuffer::default);
+
+		// events usually arrive in order, but nothing guarantees it
+		if let Err(position) = buffer.position(message.id) {
+			buffer.messages.insert(position, message.clone());
+		}
+		self.trim(buffer);
+	}
+
+	/// Applies an edit to a message, if it is part of the history.
+	pub fn update(&self, event: &MessageUpdateEvent) {
+		let mut channels = self.channels.lock().unwrap();
+		let Some(buffer) = channels.get_mut(&event.channel_id) else {
+			return;
+		};
+
+		if let Ok(position) = buffer.position(event.id) {
+			event.apply_to_message(&mut buffer.messages[position]);
+		}
+	}
+
+	/// Removes deleted messages from the history.
+	pub fn remove(&self, channel_id: ChannelId, message_ids: &[MessageId]) {
+		let mut channels = self.channels.lock().unwrap();
+		let Some(buffer) = channels.get_mut(&channel_id) else {
+			return;
+		};
+
+		buffer.messages.retain(|message| !message_ids.contains(&message.id));
+	}
+
+	/// Forgets the history of all channels. Events missed while the gateway was disconnected would leave gaps otherwise.
+	pub fn clear(&self) {
+		self.channels.lock().unwrap().clear();
+	}
+
+	/// Returns up to `limit` messages sent right before the given one, newest first, like the REST API does.
+	/// Returns `None` if the history can't tell, because it doesn't reach back far enough and needs a backfill.
+	pub fn before(&self, channel_id: ChannelId, message_id: MessageId, limit: usize) -> Option<Vec<Message>> {
+		let mut channels = self.channels.lock().unwrap();
+		let buffer = channels.get(&channel_id)?;
+
+		// the message itself has to be known, otherwise there might be a gap between it and the history
+		let position = buffer.position(message_id).ok()?;
+		if position < limit && !buffer.complete {
+			return None;
+		}
+
+		Some(
+			buffer
+				.messages
+				.range(position.saturating_sub(limit)..position)
+				.rev()
+				.cloned()
+				.collect(),
+		)
+	}
+
+	/// Returns all messages sent before the given one, newest first, along with whether they reach back to the first
+	/// message of the channel. Returns `None` if the message isn't part of the history, so it can't tell about gaps.
+	pub fn all_before(&self, channel_id: ChannelId, message_id: MessageId) -> Option<(Vec<Message>, bool)> {
+		let mut channels = self.channels.lock().unwrap();
+		let buffer = channels.get(&channel_id)?;
+		let position = buffer.position(message_id).ok()?;
+
+		Some((buffer.messages.range(..position).rev().cloned().collect(), buffer.complete))
+	}
+
+	/// Adds messages fetched from the REST API to the front of the history. `history` has to contain the messages sent
+	/// right before the given one, newest first, as returned for a request of `limit` messages.
+	pub fn backfill(&self, channel_id: ChannelId, message_id: MessageId, history: &[Message], limit: usize) {
+		let mut channels = self.channels.lock().unwrap();
+		let Some(buffer) = channels.get_mut(&channel_id) else {
+			return;
+		};
+
+		// without the message itself, the fetched messages might not connect to the history
+		if buffer.position(message_id).is_err() {
+			return;
+		}
+
+		let oldest = buffer.messages.front().map(|message| message.id).unwrap_or(message_id);
+		for message in history.iter().filter(|message| message.id < oldest) {
+			buffer.messages.push_front(message.clone());
+		}
+		buffer.complete = history.len() < limit;
+		self.trim(buffer);
+	}
+
+	fn trim(&self, buffer: &mut ChannelBuffer) {
+		while buffer.messages.len() > self.capacity {
+			buffer.messages.pop_front();
+			buffer.complete = false;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CHANNEL_ID: ChannelId = ChannelId::new(1);
+
+	fn message(id: u64) -> Message {
+		let mut message = Message::default();
+		message.id = MessageId::new(id);
+		message.channel_id = CHANNEL_ID;
+		message
+	}
+
+	fn ids(messages: &[Message]) -> Vec<u64> {
+		messages.iter().map(|message| message.id.get()).collect()
+	}
+
+	#[test]
+	fn test_before() {
+		let history = ChannelHistory::new(10);
+		for id in [1, 2, 4, 3, 5] {
+			history.add(&message(id));
+		}
+
+		assert_eq!(ids(&history.before(CHANNEL_ID, MessageId::new(5), 3).unwrap()), vec![4, 3, 2]);
+		assert!(history.before(CHANNEL_ID, MessageId::new(5), 5).is_none());
+		assert!(history.before(CHANNEL_ID, MessageId::new(6), 1).is_none());
+		assert!(history.before(ChannelId::new(2), MessageId::new(5), 1).is_none());
+
+		history.remove(CHANNEL_ID, &[MessageId::new(4)]);
+		assert_eq!(ids(&history.before(CHANNEL_ID, MessageId::new(5), 2).unwrap()), vec![3, 2]);
+
+		let (messages, complete) = history.all_before(CHANNEL_ID, MessageId::new(5)).unwrap();
+		assert_eq!(ids(&messages), vec![3, 2, 1]);
+		assert!(!complete);
+		assert!(history.all_before(CHANNEL_ID, MessageId::new(6)).is_none());
+	}
+
+	#[test]
+	fn test_backfill() {
+		let history = ChannelHistory::new(4);
+		history.add(&message(3));
+		history.add(&message(4));
+		assert!(history.before(CHANNEL_ID, MessageId::new(4), 3).is_none());
+
+		// the channel starts with message 1, so fewer messages are returned than requested
+		history.backfill(CHANNEL_ID, MessageId::new(4), &[message(3), message(2), message(1)], 5);
+		assert_eq!(ids(&history.before(CHANNEL_ID, MessageId::new(4), 5).unwrap()), vec![3, 2, 1]);
+
+		// trimming the oldest message means the history no longer reaches the start of the channel
+		history.add(&message(5));
+		assert!(history.before(CHANNEL_ID, MessageId::new(5), 4).is_none());
+		assert_eq!(ids(&history.before(CHANNEL_ID, MessageId::new(5), 3).unwrap()), vec![4, 3, 2]);
+	}
+}
@@ -1,28 +1,63 @@
+use std::sync::Arc;
+
+use entity::guild_settings;
+use log::debug;
 use miette::{
 	IntoDiagnostic,
 	Result,
 	WrapErr,
 };
 use poise::serenity_prelude::{
+	ChannelId,
+	ChannelType,
 	Context,
 	GetMessages,
+	GuildChannel,
 	Message,
+	MessageId,
+	MessageType,
+	UserId,
+};
+
+use crate::{
+	channel_access::{
+		can_read_history,
+		guild_channel,
+	},
+	channel_history::ChannelHistory,
+	message_cache::MessageCache,
+	message_link::{
+		MessageLink,
+		find_message_links,
+	},
+	relevance,
+	tokenizer::Tokenizer,
 };
 
 /// This struct contains settings involved when building the context for an invocation.
 /// Limiting what will be fetched from Discord and potentially included as context for the invocation.
 #[derive(Debug)]
 pub struct InvocationContextSettings {
+	/// Counts tokens the way the model does. Counts are exact if a vocabulary is available for the model, otherwise
+	/// they are estimated.
+	pub tokenizer: Arc<dyn Tokenizer>,
+
 	/// Maximum number of tokens to include in the context.
-	/// This is an approximate limit, as we don't know the exact token count of the messages.
-	/// But assuming a good enough estimate, we can prevent gobbling up too many tokens by fetching too many large
-	/// messages.
+	/// This prevents gobbling up too many tokens by fetching too many large messages.
 	pub max_token_count: usize,
 
 	/// Maximum number of messages that are simply fetched from the channel history.
 	/// These provide potentially more context, but also increase token count.
 	pub max_channel_history: Option<usize>,
 
+	/// Number of messages from the channel history to choose from, if history is selected by relevance.
+	/// Limited to 100, the maximum Discord returns at once.
+	pub relevance_window: usize,
+
+	/// Number of messages right before the initial message which are always included, if history is selected by
+	/// relevance. These are usually what the initial message refers to, even without sharing any terms with it.
+	pub min_recent_history: usize,
+
 	/// The maximum depth for fetching replied messages.
 	pub reply_chain_depth: Option<usize>,
 
@@ -34,10 +69,25 @@ pub struct InvocationContextSettings {
 	/// Maximum number of tokens allowed to be included due to reply chain windows.
 	/// Once this limit is reached, only directly replied messages will be included.
 	pub reply_chain_max_token_count: Option<usize>,
+
+	/// Maximum number of messages to include because they were linked in the initial message or the reply chain.
+	/// Linked messages are only included if both the bot and the invoking user can read them.
+	pub max_linked_messages: Option<usize>,
+
+	/// Threads with at most this many messages are included as a whole instead of the channel history, if they fit into
+	/// the token budget. The thread starter is included regardless of this setting.
+	pub max_thread_messages: Option<usize>,
 }
 
 impl InvocationContextSettings {
-	pub async fn extract_context_from_message(&self, ctx: &Context, message: &Message) -> Result<Vec<ContextMessageVariant>> {
+	pub async fn extract_context_from_message(
+		&self,
+		ctx: &Context,
+		cache: &MessageCache,
+		channel_history: &ChannelHistory,
+		message: &Message,
+		selection: HistorySelection,
+	) -> Result<Vec<ContextMessageVariant>> {
 		let mut limit_tracker = LimitTracker::new();
 		let mut messages = Vec::<ContextMessageVariant>::new();
 
@@ -46,6 +96,17 @@ impl InvocationContextSettings {
 		limit_tracker.add_message(&entry, self);
 		messages.push(entry);
 
+		// in threads, the starter message is usually outside of the history window, but sets the topic of the thread
+		let thread = thread_of(ctx, message).await?;
+		if let Some((thread, parent)) = &thread {
+			if let Some(starter) = fetch_thread_starter(ctx, cache, thread, parent).await {
+				// like the initial message, the starter is always added, regardless of limits
+				let entry = ContextMessageVariant::ThreadStarter(starter, ThreadInfo::new(thread, parent));
+				limit_tracker.add_message(&entry, self);
+				messages.push(entry);
+			}
+		}
+
 		// resolve reply chains if enabled
 		if let Some(reply_chain_depth) = self.reply_chain_depth {
 			// first we resolve the reply chain itself
@@ -55,25 +116,34 @@ impl InvocationContextSettings {
 				let mut current_message = message.to_owned();
 
 				for _ in 0..reply_chain_depth {
-					if let Some(replied_message) = current_message.referenced_message.as_ref() {
-						let entry = ContextMessageVariant::Reply(*replied_message.to_owned());
-						if !limit_tracker.add_message(&entry, self) {
+					if current_message.kind != MessageType::InlineReply {
+						break;
+					}
+					let Some((channel_id, replied_id)) = current_message
+						.message_reference
+						.as_ref()
+						.and_then(|reference| Some((reference.channel_id, reference.message_id?)))
+					else {
+						break;
+					};
+
+					// discord only includes the replied message without its own reply, so we go through the cache for the next one
+					let replied_message = match cache.fetch(ctx, channel_id, replied_id).await {
+						Ok(replied_message) => replied_message,
+						Err(err) => {
+							// replied messages can be deleted, which just ends the chain
+							debug!("Failed to fetch replied message {}: {:?}", replied_id, err);
 							break;
-						}
-						messages.push(entry);
-						chain_messages.push(replied_message.to_owned());
-
-						// refetch from discord, since discord won't give us next message in chain
-						let replied_message = replied_message
-							.channel_id
-							.message(ctx, replied_message.id)
-							.await
-							.into_diagnostic()
-							.wrap_err("failed to fetch replied message")?;
-						current_message = replied_message;
-					} else {
+						},
+					};
+
+					let entry = ContextMessageVariant::Reply(replied_message.clone());
+					if !limit_tracker.add_message(&entry, self) {
 						break;
 					}
+					messages.push(entry);
+					chain_messages.push(replied_message.clone());
+					current_message = replied_message;
 				}
 				chain_messages
 			};
@@ -131,17 +201,28 @@ impl InvocationContextSettings {
 			}
 		}
 
-		// fetch messages from channel history if enabled
-		if let Some(max_channel_history) = self.max_channel_history {
-			let history = message
-				.channel_id
-				.messages(ctx, GetMessages::new().before(message.id).limit(max_channel_history as u8))
-				.await
-				.into_diagnostic()
-				.wrap_err("failed to fetch channel history")?;
-
-			for message in history.into_iter() {
-				let entry = ContextMessageVariant::History(message);
+		// resolve message links in the initial message and the reply chain if enabled
+		if let Some(max_linked_messages) = self.max_linked_messages {
+			let mut links = Vec::new();
+			for entry in &messages {
+				if let ContextMessageVariant::Initial(linking) | ContextMessageVariant::Reply(linking) = entry {
+					for link in find_message_links(&linking.content) {
+						if !links.contains(&link) {
+							links.push(link);
+						}
+					}
+				}
+			}
+
+			for link in links {
+				if limit_tracker.linked_count >= max_linked_messages {
+					break;
+				}
+
+				let Some(linked_message) = fetch_linked_message(ctx, cache, message, link).await else {
+					continue;
+				};
+				let entry = ContextMessageVariant::Linked(linked_message);
 				if !limit_tracker.add_message(&entry, self) {
 					break;
 				}
@@ -149,6 +230,73 @@ impl InvocationContextSettings {
 			}
 		}
 
+		// include short threads as a whole if enabled, but only if the whole thread fits
+		let mut whole_thread = false;
+		if let (Some((thread, _)), Some(max_thread_messages)) = (&thread, self.max_thread_messages) {
+			if let Some(thread_messages) = fetch_thread(ctx, thread, message.id, max_thread_messages).await? {
+				let entries = thread_messages
+					.into_iter()
+					.map(ContextMessageVariant::Thread)
+					.collect::<Vec<_>>();
+
+				let mut thread_tracker = limit_tracker.clone();
+				if entries.iter().all(|entry| thread_tracker.add_message(entry, self)) {
+					limit_tracker = thread_tracker;
+					messages.extend(entries);
+					whole_thread = true;
+				}
+			}
+		}
+
+		// fetch messages from channel history if enabled, the whole thread already contains them
+		if let Some(max_channel_history) = self.max_channel_history.filter(|_| !whole_thread) {
+			let window = match selection {
+				HistorySelection::Recent => max_channel_history,
+				HistorySelection::Relevant => self.relevance_window.max(max_channel_history).min(100),
+			};
+
+			// the history received from the gateway is only missing messages from before the bot started listening
+			let history = match channel_history.before(message.channel_id, message.id, window) {
+				Some(history) => history,
+				None => {
+					let history = message
+						.channel_id
+						.messages(ctx, GetMessages::new().before(message.id).limit(window as u8))
+						.await
+						.into_diagnostic()
+						.wrap_err("failed to fetch channel history")?;
+					channel_history.backfill(message.channel_id, message.id, &history, window);
+					history
+				},
+			};
+
+			match selection {
+				HistorySelection::Recent => {
+					for message in history.into_iter() {
+						let entry = ContextMessageVariant::History(message);
+						if !limit_tracker.add_message(&entry, self) {
+							break;
+						}
+						messages.push(entry);
+					}
+				},
+				HistorySelection::Relevant => {
+					// the most recent messages come first, the remaining budget is filled by relevance
+					let mut history = history;
+					let older = history.split_off(self.min_recent_history.min(history.len()));
+					history.extend(relevance::rank(message, older));
+
+					// a message exceeding the limits doesn't mean a less relevant one can't fit anymore
+					for message in history.into_iter() {
+						let entry = ContextMessageVariant::History(message);
+						if limit_tracker.add_message(&entry, self) {
+							messages.push(entry);
+						}
+					}
+				},
+			}
+		}
+
 		// order messages by increasing id and eliminate duplicates
 		messages.sort_by_key(|m| {
 			let message: &Message = m.into();
@@ -156,12 +304,129 @@ impl InvocationContextSettings {
 		});
 		messages.dedup_by_key(|m| m.id());
 
-		//
-
 		Ok(messages)
 	}
 }
 
+/// Returns the thread the message was sent in together with its parent channel, or `None` outside of threads.
+async fn thread_of(ctx: &Context, message: &Message) -> Result<Option<(GuildChannel, GuildChannel)>> {
+	if message.guild_id.is_none() {
+		return Ok(None);
+	}
+
+	let channel = guild_channel(ctx, message.channel_id).await?;
+	let parent_id = match (channel.kind, channel.parent_id) {
+		(ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread, Some(parent_id)) => parent_id,
+		_ => return Ok(None),
+	};
+	let parent = guild_channel(ctx, parent_id).await?;
+
+	Ok(Some((channel, parent)))
+}
+
+/// Fetches the message a thread was started with. Forum posts start with a message in the thread itself, other threads
+/// share the ID of the message in the parent channel they were started from.
+/// Threads started without a message, or whose starter was deleted, have no starter.
+async fn fetch_thread_starter(
+	ctx: &Context,
+	cache: &MessageCache,
+	thread: &GuildChannel,
+	parent: &GuildChannel,
+) -> Option<Message> {
+	let channel_id: ChannelId = match parent.kind {
+		ChannelType::Forum => thread.id,
+		_ => parent.id,
+	};
+
+	match cache.fetch(ctx, channel_id, MessageId::new(thread.id.get())).await {
+		Ok(starter) => Some(starter),
+		Err(err) => {
+			debug!("No starter message for thread {}: {}", thread.id, err);
+			None
+		},
+	}
+}
+
+/// Fetches all messages of a thread before the given message, oldest first.
+/// Returns `None` if the thread has more than `max_messages` messages.
+async fn fetch_thread(
+	ctx: &Context,
+	thread: &GuildChannel,
+	before: MessageId,
+	max_messages: usize,
+) -> Result<Option<Vec<Message>>> {
+	// the message count of old threads stops at 50, but it never overestimates
+	if thread.message_count.is_some_and(|count| count as usize > max_messages) {
+		return Ok(None);
+	}
+
+	let mut thread_messages = Vec::new();
+	let mut before = before;
+	loop {
+		let page = thread
+			.id
+			.messages(ctx, GetMessages::new().before(before).limit(100))
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch thread messages")?;
+
+		thread_messages.extend(page.iter().cloned());
+		if thread_messages.len() > max_messages {
+			return Ok(None);
+		}
+
+		match page.last() {
+			Some(last) if page.len() == 100 => before = last.id,
+			_ => break,
+		}
+	}
+
+	thread_messages.reverse();
+	Ok(Some(thread_messages))
+}
+
+/// Fetches a linked message, if it is in the guild of the invocation and both the bot and the invoking user can read
+/// it. Links which can't be resolved are skipped, they are just text to the model then.
+async fn fetch_linked_message(ctx: &Context, cache: &MessageCache, message: &Message, link: MessageLink) -> Option<Message> {
+	let (guild_id, channel_id, message_id) = link;
+	if guild_id != message.guild_id {
+		debug!(
+			"Skipping link to message {}, it is not in the guild of the invocation",
+			message_id
+		);
+		return None;
+	}
+
+	let bot_id = ctx.cache.current_user().id;
+	for user_id in [message.author.id, bot_id] {
+		if !can_read(ctx, message, link, user_id).await {
+			debug!("Skipping link to message {}, user {} can't read it", message_id, user_id);
+			return None;
+		}
+	}
+
+	match cache.fetch(ctx, channel_id, message_id).await {
+		Ok(linked_message) => Some(linked_message),
+		Err(err) => {
+			debug!("Skipping link to message {}, it could not be fetched: {}", message_id, err);
+			None
+		},
+	}
+}
+
+async fn can_read(ctx: &Context, message: &Message, link: MessageLink, user_id: UserId) -> bool {
+	let (guild_id, channel_id, _) = link;
+	can_read_history(ctx, guild_id, channel_id, user_id, message.channel_id)
+		.await
+		.unwrap_or_else(|err| {
+			debug!(
+				"Failed to check access of user {} to channel {}: {:?}",
+				user_id, channel_id, err
+			);
+			false
+		})
+}
+
 /// Use during message selection to prevent exceeding specified limits.
 #[derive(Clone)]
 struct LimitTracker {
@@ -176,6 +441,9 @@ struct LimitTracker {
 
 	/// Current number of messages from the reply chain.
 	reply_chain_count: usize,
+
+	/// Current number of linked messages.
+	linked_count: usize,
 }
 
 impl LimitTracker {
@@ -185,6 +453,7 @@ impl LimitTracker {
 			reply_chain_tokens: 0,
 			history_count: 0,
 			reply_chain_count: 0,
+			linked_count: 0,
 		}
 	}
 
@@ -196,7 +465,7 @@ impl LimitTracker {
 
 		let message = match message {
 			ContextMessageVariant::Initial(message) => {
-				self.tokens += estimate_token_count(&message.content);
+				self.tokens += settings.tokenizer.count_tokens(&message.content);
 				message
 			},
 			ContextMessageVariant::History(message) => {
@@ -205,17 +474,23 @@ impl LimitTracker {
 			},
 			ContextMessageVariant::Reply(message) => {
 				self.reply_chain_count += 1;
-				self.reply_chain_tokens += estimate_token_count(&message.content);
+				self.reply_chain_tokens += settings.tokenizer.count_tokens(&message.content);
 				message
 			},
 			ContextMessageVariant::ReplyWindow(message) => {
-				self.reply_chain_tokens += estimate_token_count(&message.content);
+				self.reply_chain_tokens += settings.tokenizer.count_tokens(&message.content);
 				message
 			},
+			ContextMessageVariant::Linked(message) => {
+				self.linked_count += 1;
+				message
+			},
+			ContextMessageVariant::ThreadStarter(message, _) => message,
+			ContextMessageVariant::Thread(message) => message,
 		};
 
 		// all messages are added to the total token count
-		self.tokens += estimate_token_count(&message.content);
+		self.tokens += settings.tokenizer.count_tokens(&message.content);
 
 		if self.is_within_limits(settings) {
 			true
@@ -253,10 +528,39 @@ impl LimitTracker {
 			}
 		}
 
+		// check if we are within the linked message count
+		if let Some(max_linked_messages) = settings.max_linked_messages {
+			if self.linked_count > max_linked_messages {
+				return false;
+			}
+		}
+
 		true
 	}
 }
 
+/// How messages are selected from the channel history, configured per guild.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, poise::ChoiceParameter)]
+pub enum HistorySelection {
+	/// The most recent messages, until the limits are reached.
+	#[default]
+	#[name = "recent"]
+	Recent,
+
+	/// The most recent few messages, followed by the messages most relevant to the initial message from a wider window.
+	#[name = "relevant"]
+	Relevant,
+}
+
+impl From<Option<&guild_settings::Model>> for HistorySelection {
+	fn from(settings: Option<&guild_settings::Model>) -> Self {
+		match settings {
+			Some(settings) if settings.ranked_history => Self::Relevant,
+			_ => Self::Recent,
+		}
+	}
+}
+
 /// This enum allows to differentiate between the different ways a message was included in the context.
 pub enum ContextMessageVariant {
 	/// The initial message that was used to start the invocation.
@@ -271,6 +575,45 @@ pub enum ContextMessageVariant {
 
 	/// This message was included in the context because it was fetched from the channel history.
 	History(Message),
+
+	/// This message was included in the context because it was linked in the initial message or the reply chain.
+	Linked(Message),
+
+	/// This message started the thread the invocation happened in.
+	ThreadStarter(Message, ThreadInfo),
+
+	/// This message was included in the context because the whole thread fit into the context.
+	Thread(Message),
+}
+
+/// Describes the thread a thread starter started.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+	/// Name of the thread, which is the title for forum posts.
+	pub title: String,
+
+	/// Names of the tags applied to a forum post.
+	pub tags: Vec<String>,
+
+	/// Whether the thread is a post in a forum channel.
+	pub forum_post: bool,
+}
+
+impl ThreadInfo {
+	fn new(thread: &GuildChannel, parent: &GuildChannel) -> Self {
+		let tags = thread
+			.applied_tags
+			.iter()
+			.filter_map(|tag_id| parent.available_tags.iter().find(|tag| tag.id == *tag_id))
+			.map(|tag| tag.name.clone())
+			.collect();
+
+		Self {
+			title: thread.name.clone(),
+			tags,
+			forum_post: parent.kind == ChannelType::Forum,
+		}
+	}
 }
 
 impl ContextMessageVariant {
@@ -281,6 +624,9 @@ impl ContextMessageVariant {
 			ContextMessageVariant::History(message) => message.id,
 			ContextMessageVariant::Reply(message) => message.id,
 			ContextMessageVariant::ReplyWindow(message) => message.id,
+			ContextMessageVariant::Linked(message) => message.id,
+			ContextMessageVariant::ThreadStarter(message, _) => message.id,
+			ContextMessageVariant::Thread(message) => message.id,
 		}
 		.into()
 	}
@@ -293,12 +639,51 @@ impl<'a> From<&'a ContextMessageVariant> for &'a Message {
 			ContextMessageVariant::History(message) => message,
 			ContextMessageVariant::Reply(message) => message,
 			ContextMessageVariant::ReplyWindow(message) => message,
+			ContextMessageVariant::Linked(message) => message,
+			ContextMessageVariant::ThreadStarter(message, _) => message,
+			ContextMessageVariant::Thread(message) => message,
 		}
 	}
 }
 
-fn estimate_token_count(str: &str) -> usize {
-	// TODO: use tiktoken-rs
-	// for now we just count 6 characters as a token
-	str.chars().count() / 6
+#[cfg(test)]
+mod tests {
+	use poise::serenity_prelude::{
+		ForumTag,
+		ForumTagId,
+	};
+	use serde_json::json;
+
+	use super::*;
+
+	fn tag(id: u64, name: &str) -> ForumTag {
+		serde_json::from_value(json!({ "id": id.to_string(), "name": name, "moderated": false })).unwrap()
+	}
+
+	#[test]
+	fn test_thread_info() {
+		let mut forum = GuildChannel::default();
+		forum.kind = ChannelType::Forum;
+		forum.available_tags = vec![tag(1, "bug"), tag(2, "question"), tag(3, "windows")];
+
+		let mut post = GuildChannel::default();
+		post.name = "Crash on startup".to_string();
+		// tags removed from the forum since are skipped
+		post.applied_tags = vec![ForumTagId::new(3), ForumTagId::new(4), ForumTagId::new(1)];
+
+		let info = ThreadInfo::new(&post, &forum);
+		assert_eq!(info.title, "Crash on startup");
+		assert_eq!(info.tags, vec!["windows", "bug"]);
+		assert!(info.forum_post);
+
+		let mut channel = GuildChannel::default();
+		channel.kind = ChannelType::Text;
+		let mut thread = GuildChannel::default();
+		thread.name = "Release planning".to_string();
+
+		let info = ThreadInfo::new(&thread, &channel);
+		assert_eq!(info.title, "Release planning");
+		assert!(info.tags.is_empty());
+		assert!(!info.forum_post);
+	}
 }
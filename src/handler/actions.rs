@@ -0,0 +1,96 @@
+use entity::guild_settings;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::{
+	CreateReply,
+	serenity_prelude::CreateEmbed,
+};
+use sea_orm::ActiveValue::Set;
+
+use crate::{
+	Context,
+	builtin_tools::{
+		load_guild_settings,
+		update_guild_settings,
+	},
+};
+
+/// Discord actions the bot can take on its own, if a guild allows it.
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+enum DiscordAction {
+	#[name = "reactions"]
+	Reactions,
+	#[name = "polls"]
+	Polls,
+	#[name = "threads"]
+	Threads,
+	#[name = "pins"]
+	Pins,
+}
+
+/// Shows or changes which Discord actions the bot may take in this server, besides answering.
+#[poise::command(
+	slash_command,
+	guild_only,
+	ephemeral,
+	default_member_permissions = "MANAGE_GUILD",
+	required_permissions = "MANAGE_GUILD"
+)]
+pub async fn actions(
+	ctx: Context<'_>,
+	#[description = "Action to allow or disallow"] action: Option<DiscordAction>,
+	#[description = "Whether the bot may take the action"] enabled: Option<bool>,
+) -> Result<()> {
+	let guild_id = ctx.guild_id().ok_or(miette!("command is only available in guilds"))?;
+	let db = ctx.data().db.as_ref();
+
+	let settings = match (action, enabled) {
+		(Some(action), Some(enabled)) => Some(
+			update_guild_settings(db, guild_id, |settings| match action {
+				DiscordAction::Reactions => settings.reactions = Set(enabled),
+				DiscordAction::Polls => settings.polls = Set(enabled),
+				DiscordAction::Threads => settings.threads = Set(enabled),
+				DiscordAction::Pins => settings.pins = Set(enabled),
+			})
+			.await?,
+		),
+		(None, None) => load_guild_settings(db, guild_id).await?,
+		_ => {
+			ctx
+				.reply("Specify both the action and whether it is enabled, or neither to see the current settings.")
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to send message")?;
+			return Ok(());
+		},
+	};
+
+	let enabled = |allowed: fn(&guild_settings::Model) -> bool| {
+		let allowed = settings.as_ref().is_some_and(allowed);
+		if allowed { "Allowed" } else { "Not allowed" }
+	};
+
+	ctx
+		.send(
+			CreateReply::default().embed(
+				CreateEmbed::new()
+					.title("Discord actions")
+					.description("Actions also require the respective permissions of the bot in the channel.")
+					.fields(vec![
+						("React to messages", enabled(|settings| settings.reactions), true),
+						("Create polls", enabled(|settings| settings.polls), true),
+						("Open threads", enabled(|settings| settings.threads), true),
+						("Pin messages", enabled(|settings| settings.pins), true),
+					]),
+			),
+		)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to send message")?;
+
+	Ok(())
+}
@@ -11,6 +11,7 @@ use miette::{
 use poise::{
 	serenity_prelude::{
 		CreateEmbed,
+		GuildId,
 		Mentionable,
 		UserId,
 	},
@@ -30,8 +31,16 @@ use sea_orm::{
 use crate::{
 	AppState,
 	Context,
+	text::truncate_chars,
+	tool_audit::{
+		ToolAudit,
+		format_entry,
+	},
 };
 
+/// Number of tool calls listed by the audit commands.
+const AUDIT_LISTING_SIZE: u64 = 20;
+
 pub fn register_commands(commands: &mut Vec<Command<AppState, Report>>) {
 	commands.push(admin());
 }
@@ -42,7 +51,7 @@ pub fn register_commands(commands: &mut Vec<Command<AppState, Report>>) {
 	owners_only,
 	dm_only,
 	subcommand_required,
-	subcommands("user", "register", "guilds")
+	subcommands("user", "register", "guilds", "reload", "audit")
 )]
 async fn admin(_ctx: Context<'_>) -> Result<()> {
 	unreachable!("This command is only available as a subcommand")
@@ -63,7 +72,7 @@ async fn user(_ctx: Context<'_>) -> Result<()> {
 /// Outputs the status of a user.
 #[poise::command(prefix_command, owners_only, dm_only, rename = "status")]
 async fn user_status(ctx: Context<'_>, user: UserId) -> Result<()> {
-	let db = &ctx.data().db;
+	let db = ctx.data().db.as_ref();
 
 	let db_user = entity::prelude::User::find()
 		.filter(user::Column::DiscordUserId.eq(user.get()))
@@ -120,7 +129,7 @@ async fn user_blacklist(_ctx: Context<'_>) -> Result<()> {
 /// Checks blacklist status of a user.
 #[poise::command(prefix_command, owners_only, dm_only, rename = "get")]
 async fn user_blacklist_get(ctx: Context<'_>, user: UserId) -> Result<(), Report> {
-	let db = &ctx.data().db;
+	let db = ctx.data().db.as_ref();
 
 	let blacklist_entry = entity::prelude::Blacklist::find()
 		.filter(blacklist::Column::DiscordUserId.eq(user.get()))
@@ -155,7 +164,7 @@ async fn user_blacklist_get(ctx: Context<'_>, user: UserId) -> Result<(), Report
 /// Updates blacklist status of a user.
 #[poise::command(prefix_command, owners_only, dm_only, rename = "set")]
 async fn user_blacklist_set(ctx: Context<'_>, user: UserId, blacklisted: bool, #[rest] reason: String) -> Result<(), Report> {
-	let db = &ctx.data().db;
+	let db = ctx.data().db.as_ref();
 
 	// check if target user is owner, as owners cannot be blacklisted
 	if ctx.framework().options.owners.contains(&user) {
@@ -259,3 +268,73 @@ async fn guilds(ctx: Context<'_>) -> Result<(), Report> {
 		.into_diagnostic()
 		.wrap_err("failed to list servers")
 }
+
+/// Reloads the MCP configuration and refetches all tools.
+#[poise::command(prefix_command, owners_only, dm_only)]
+async fn reload(ctx: Context<'_>) -> Result<(), Report> {
+	let connection = ctx.data().mcp_manager.reload().await?;
+
+	ctx
+		.reply(format!(
+			"Reloaded MCP configuration, {} tools are now available.",
+			connection.get_llm_tools().len()
+		))
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to send message")?;
+
+	Ok(())
+}
+
+/// Commands for inspecting the tool call audit trail.
+#[poise::command(
+	prefix_command,
+	owners_only,
+	dm_only,
+	subcommand_required,
+	subcommands("audit_user", "audit_guild")
+)]
+async fn audit(_ctx: Context<'_>) -> Result<()> {
+	unreachable!("This command is only available as a subcommand")
+}
+
+/// Lists recent tool calls triggered by a user.
+#[poise::command(prefix_command, owners_only, dm_only, rename = "user")]
+async fn audit_user(ctx: Context<'_>, user: UserId) -> Result<()> {
+	let entries = ToolAudit::new(ctx.data().db.as_ref())
+		.recent_for_user(user, AUDIT_LISTING_SIZE)
+		.await?;
+
+	send_audit_listing(ctx, format!("Recent tool calls of {}", user.mention()), entries).await
+}
+
+/// Lists recent tool calls made in a guild.
+#[poise::command(prefix_command, owners_only, dm_only, rename = "guild")]
+async fn audit_guild(ctx: Context<'_>, guild: GuildId) -> Result<()> {
+	let entries = ToolAudit::new(ctx.data().db.as_ref())
+		.recent_for_guild(guild, AUDIT_LISTING_SIZE)
+		.await?;
+
+	send_audit_listing(ctx, format!("Recent tool calls in guild {}", guild), entries).await
+}
+
+async fn send_audit_listing(ctx: Context<'_>, title: String, entries: Vec<entity::tool_call::Model>) -> Result<()> {
+	if entries.is_empty() {
+		ctx
+			.reply("No tool calls recorded.")
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to send message")?;
+		return Ok(());
+	}
+
+	let listing = entries.iter().map(format_entry).collect::<Vec<_>>().join("\n");
+
+	ctx
+		.send(CreateReply::default().embed(CreateEmbed::new().title(title).description(truncate_chars(&listing, 4096))))
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to send message")?;
+
+	Ok(())
+}
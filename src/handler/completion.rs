@@ -1,17 +1,27 @@
-use std::collections::{
-	HashMap,
-	HashSet,
+use std::{
+	collections::{
+		HashMap,
+		HashSet,
+	},
+	sync::Arc,
+	time::Instant,
 };
 
+use futures::future::join_all;
 use llm::{
 	FunctionCall,
+	LLMProvider,
 	ToolCall,
 	chat::{
 		ChatMessage,
 		ChatRole,
+		ImageMime,
 	},
 };
-use log::debug;
+use log::{
+	debug,
+	error,
+};
 use miette::{
 	IntoDiagnostic,
 	Report,
@@ -23,9 +33,14 @@ use poise::{
 	FrameworkContext,
 	serenity_prelude::{
 		ChannelId,
+		ChannelType,
 		CreateAllowedMentions,
+		CreateAttachment,
 		CreateMessage,
+		EditMessage,
+		GuildId,
 		Message,
+		UserId,
 	},
 };
 use sea_orm::DatabaseConnection;
@@ -37,11 +52,63 @@ use tracing::trace;
 
 use crate::{
 	AppState,
-	context_extraction::ContextMessageVariant,
+	builtin_tools::{
+		ToolContext,
+		ToolRegistry,
+		load_guild_settings,
+	},
+	channel_access::guild_channel,
+	context_extraction::{
+		ContextMessageVariant,
+		HistorySelection,
+	},
 	invocation_builder::InvocationBuilder,
+	mcp::{
+		ClientServices,
+		InvocationContext,
+		McpConnection,
+		ToolAttachment,
+		ToolCallOutput,
+		ToolMedia,
+		truncate_result,
+	},
+	mcp_config::{
+		ApprovalMode,
+		ToolSettings,
+	},
+	mcp_elicitation::Elicitor,
+	mcp_sampling::Sampler,
+	text::truncate_chars,
+	tool_approval::{
+		ApprovalDecision,
+		ToolApproval,
+	},
+	tool_audit::{
+		ToolAudit,
+		ToolCallRecord,
+	},
+	tool_status::ToolStatus,
 	user_from_db_or_create,
 };
 
+/// Discord limits the number of attachments per message.
+const MAX_ATTACHMENTS: usize = 10;
+
+/// Conservative limit for a single attachment, which is accepted regardless of the boost level of a guild.
+const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+
+/// Reply used if the model doesn't come up with an answer, even when asked again without tools.
+const FALLBACK_ANSWER: &str = "Sorry, I couldn't finish my answer. Please try again, or ask a more specific question.";
+
+/// Discord rejects messages longer than this many characters.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Tells the user that the answer might be missing information, because the model couldn't use all the tools it wanted.
+const CUT_SHORT_NOTE: &str = "-# Tool use was cut short, this answer might be incomplete.";
+
+/// Maximum number of tool calls and sources listed beneath a reply, so the footer doesn't overshadow the answer.
+const MAX_FOOTER_ENTRIES: usize = 5;
+
 #[derive(serde::Serialize)]
 struct GuildContext {
 	id: u64,
@@ -97,7 +164,7 @@ pub async fn handle_completion(
 	app: &AppState,
 	new_message: &Message,
 ) -> Result<()> {
-	let db_user = user_from_db_or_create(&app.db, &new_message.author).await?;
+	let db_user = user_from_db_or_create(app.db.as_ref(), &new_message.author).await?;
 
 	// if user opted out, we don't do anything, not even send a message, since that would be spammy
 	if db_user.opt_out_since.is_some() {
@@ -119,7 +186,7 @@ pub async fn handle_completion(
 	let is_owner = framework.options().owners.contains(&new_message.author.id);
 
 	// note order, as this ensures we still hit database, even if user is owner
-	if !check_rate_limit(new_message, app).await? && !is_owner {
+	if !check_rate_limit(app, new_message.author.id, new_message.channel_id, new_message.guild_id).await? && !is_owner {
 		// prevent user from spamming us with timeout
 		let error_report_future = tokio::time::timeout(std::time::Duration::from_secs(10), async {
 			let rate_limited_message = new_message
@@ -150,7 +217,8 @@ pub async fn handle_completion(
 
 	let typing_notification = typing_indicator(ctx, new_message.channel_id);
 
-	let completion_request = tokio::time::timeout(app.completion_timeout, generate_llm_response(ctx, app, new_message));
+	let deadline = tokio::time::Instant::now() + app.completion_timeout;
+	let completion_request = tokio::time::timeout_at(deadline, generate_llm_response(ctx, app, new_message, deadline));
 
 	// assuming typing notifications don't fail, we can just wait for the fork to finish and will keep sending typing
 	// notifications in the meantime
@@ -171,13 +239,19 @@ pub async fn handle_completion(
 	Ok(())
 }
 
-async fn check_rate_limit(new_message: &Message, app: &AppState) -> Result<bool> {
-	let mut context = HashMap::<&str, String>::new();
-	context.insert("user_id", new_message.author.id.to_string());
-	context.insert("channel_id", new_message.channel_id.to_string());
-	if let Some(guild_id) = new_message.guild_id {
+/// Keys used to evaluate rate limits, identifying the invoking user, channel and guild.
+pub fn rate_limit_context(user_id: UserId, channel_id: ChannelId, guild_id: Option<GuildId>) -> HashMap<&'static str, String> {
+	let mut context = HashMap::new();
+	context.insert("user_id", user_id.to_string());
+	context.insert("channel_id", channel_id.to_string());
+	if let Some(guild_id) = guild_id {
 		context.insert("guild_id", guild_id.to_string());
 	}
+	context
+}
+
+pub async fn check_rate_limit(app: &AppState, user_id: UserId, channel_id: ChannelId, guild_id: Option<GuildId>) -> Result<bool> {
+	let context = rate_limit_context(user_id, channel_id, guild_id);
 
 	let db = &app.db;
 	let limit = app.path_rate_limits.lock().await;
@@ -225,6 +299,17 @@ async fn create_tera_context<'a>(ctx: &'a poise::serenity_prelude::Context, mess
 
 			tera_context.insert("guild", &GuildContext::from(&guild));
 			tera_context.insert("channel", &ChannelContext::from(&channel));
+
+			// the name of a thread often says little, its parent channel tells what the thread is about
+			if let Some(parent_id) = channel.parent_id.filter(|_| {
+				matches!(
+					channel.kind,
+					ChannelType::PublicThread | ChannelType::PrivateThread | ChannelType::NewsThread
+				)
+			}) {
+				let parent = guild_channel(ctx, parent_id).await?;
+				tera_context.insert("parent_channel", &ChannelContext::from(&parent));
+			}
 		},
 		None => {
 			tera_context.insert("name", &ctx.cache.current_user().name);
@@ -245,6 +330,7 @@ async fn generate_llm_response<'a>(
 	ctx: &'a poise::serenity_prelude::Context,
 	app: &'a AppState,
 	message: &'a Message,
+	deadline: tokio::time::Instant,
 ) -> Result<()> {
 	let tera = &app.tera;
 	let context_settings = &app.context_settings;
@@ -252,8 +338,30 @@ async fn generate_llm_response<'a>(
 	let mcp_manager = &app.mcp_manager;
 
 	// create a new MCP connection session for this LLM response generation
-	let mcp_connection = mcp_manager.create_connection().await?;
-	let tera_context = create_tera_context(ctx, message).await?;
+	let invocation_context = InvocationContext {
+		user_id: message.author.id.to_string(),
+		guild_id: message.guild_id.map(|id| id.to_string()),
+		channel_id: message.channel_id.to_string(),
+		display_name: message
+			.member
+			.as_ref()
+			.and_then(|member| member.nick.clone())
+			.unwrap_or_else(|| message.author.global_name.clone().unwrap_or(message.author.name.clone())),
+	};
+	let services = ClientServices {
+		sampler: Some(Arc::new(Sampler::new(
+			llm_client.clone(),
+			app.model.clone(),
+			app.db.clone(),
+			app.path_rate_limits.clone(),
+			context_settings.tokenizer.clone(),
+			rate_limit_context(message.author.id, message.channel_id, message.guild_id),
+		))),
+		elicitor: Some(Arc::new(Elicitor::new(ctx.clone(), message))),
+	};
+	let mut mcp_connection = mcp_manager.create_connection(Some(invocation_context), services).await?;
+	let mut tera_context = create_tera_context(ctx, message).await?;
+	tera_context.insert("resources", &mcp_connection.read_context_resources().await);
 
 	// remove empty lines, and truncate leading and trailing whitespace
 	let preprompt = tera
@@ -266,9 +374,14 @@ async fn generate_llm_response<'a>(
 		.collect::<Vec<_>>()
 		.join("\n");
 
-	// TODO: implement message cache to avoid fetching messages multiple times
-	// TODO: pass message cache as argument
-	let mut chat_history = context_settings.extract_context_from_message(ctx, message).await?;
+	// DMs have no settings, so they always use the most recent history
+	let selection = match message.guild_id {
+		Some(guild_id) => HistorySelection::from(load_guild_settings(app.db.as_ref(), guild_id).await?.as_ref()),
+		None => HistorySelection::default(),
+	};
+	let mut chat_history = context_settings
+		.extract_context_from_message(ctx, &app.message_cache, &app.channel_history, message, selection)
+		.await?;
 
 	if std::env::var("DUMP_EXTRACTED_HISTORY")
 		.map(|v| v.to_lowercase())
@@ -281,13 +394,14 @@ async fn generate_llm_response<'a>(
 	// remove all messages for users that opted out
 	remove_opted_out_users(&app.db, &mut chat_history).await?;
 
-	// unpack chat history into messages, we longer need inclusion reason
-	let chat_history = chat_history.iter().map(|m| m.into()).collect::<Vec<&Message>>();
-
 	// add all messages to invocation builder, so it can remove markup and extract users and emotes
+	// apart from thread starters, which describe the thread, we no longer need the inclusion reason
 	let mut invocation_builder = InvocationBuilder::new(ctx.cache.current_user().id, "you");
-	for message in chat_history {
-		invocation_builder.add_message(message);
+	for entry in &chat_history {
+		match entry {
+			ContextMessageVariant::ThreadStarter(message, thread) => invocation_builder.add_thread_starter(message, thread),
+			entry => invocation_builder.add_message(entry.into()),
+		}
 	}
 
 	// Build the conversation using LLM crate's ChatMessage format
@@ -303,95 +417,237 @@ async fn generate_llm_response<'a>(
 
 	dump_llm_messages(&messages);
 
-	// Tool calling loop - allow up to 10 iterations before forcing completion
-	const MAX_TOOL_ITERATIONS: usize = 10;
-	const MAX_TOOL_CALLS: usize = 30;
+	// Tool calling loop - once the configured limits are reached, the model is forced to answer without tools
+	let max_tool_iterations = app.max_tool_iterations;
+	let max_tool_calls = app.max_tool_calls;
 	let mut conversation = messages;
 
-	// store all tool calls and results outside the loop for persistence across iterations
+	// the preprompt and the messages of the context, which can be dropped to fit the prompt into the context window
+	let mut context_end = conversation.len();
+
+	// store all tool calls outside the loop for persistence across iterations
 	let mut tool_calls: Vec<ToolCall> = Vec::new();
-	let mut tool_results: Vec<ToolCall> = Vec::new();
 
-	for iteration in 0..MAX_TOOL_ITERATIONS {
-		// we limit the number of iterations
-		let tools_available = if iteration == MAX_TOOL_ITERATIONS - 1 {
+	// files returned by tools, which will be attached to the final reply
+	let mut attachments: Vec<ToolAttachment> = Vec::new();
+
+	// links returned by tools, which can be listed as sources beneath the reply
+	let mut sources: Vec<String> = Vec::new();
+
+	// set if the model wanted to use more tools than it was allowed to, either by calls we skipped or by asking for more
+	// once tools were no longer offered
+	let mut cut_short = false;
+
+	// optionally tell the user what we are doing, while tools are running
+	let tool_status = app.tool_status.then(|| ToolStatus::start(ctx.http.clone(), message));
+
+	// sensitive tools are only called once a human approved the call
+	let tool_approval = ToolApproval::new(ctx.clone(), message, deadline);
+
+	// built-in tools act on behalf of the invoking user
+	let tool_context = ToolContext::new(ctx.clone(), app.db.clone(), app.channel_history.clone(), message).await;
+
+	let mut iteration = 0;
+	let content = loop {
+		iteration += 1;
+
+		// servers may have changed their tools since the last iteration, so we always pass the current list
+		mcp_connection.refresh_tools().await?;
+		let mut tools = mcp_connection.get_llm_tools();
+		app.builtin_tools.merge_llm_tools(&tool_context.actions, &mut tools);
+
+		// once the budget is used up, the model has to answer with what it already has
+		let exhausted = iteration >= max_tool_iterations || tool_calls.len() >= max_tool_calls;
+		if exhausted && !tool_calls.is_empty() {
+			debug!(
+				"Tool budget exhausted after {} iterations and {} tool calls, forcing final response",
+				iteration,
+				tool_calls.len()
+			);
+			conversation.push(
+				ChatMessage::user()
+					.content(
+						"[SYSTEM: You can not use any more tools. Answer the user now, based on the information you already have, and \
+						 mention if your answer is incomplete.]",
+					)
+					.build(),
+			);
+		}
+
+		let tools_available = if exhausted || tools.is_empty() {
 			None
 		} else {
-			llm_client.tools()
+			Some(tools.as_slice())
 		};
 
+		// tool results grow with every iteration, so the whole prompt is checked before every request
+		app
+			.prompt_budget
+			.fit(&mut conversation, &mut context_end, tools_available.unwrap_or_default());
+
 		let response = llm_client
 			.chat_with_tools(&conversation, tools_available)
 			.await
 			.into_diagnostic()
 			.wrap_err("completion request failed")?;
 
-		// Check if the model wants to use tools
-		if let Some(new_calls) = response.tool_calls() {
-			// Process tool calls and collect results
-			for call in new_calls.into_iter() {
-				// if we would be over the limit with this call, we stop processing further calls
-				if tool_calls.len() >= MAX_TOOL_CALLS {
-					debug!(
-						"Reached maximum number of tool calls ({}), stopping further tool processing",
-						MAX_TOOL_CALLS
-					);
-					break;
+		// Check if the model wants to use tools, some models still try to, even if we didn't offer any
+		let new_calls = match response.tool_calls() {
+			Some(new_calls) if !exhausted && !new_calls.is_empty() => new_calls,
+			new_calls => {
+				if exhausted && new_calls.is_some_and(|new_calls| !new_calls.is_empty()) {
+					debug!("Model asked for more tools after the tool budget was exhausted");
+					cut_short = true;
 				}
 
-				debug!("Processing tool call: {}", call.function.name);
-				trace!("  - Arguments: {}", call.function.arguments);
-
-				let result = process_tool_call(&call, &mcp_connection).await?;
-				let pretty_json = serde_json::to_string_pretty(&result)
-					.into_diagnostic()
-					.wrap_err("failed to pretty-print tool result")?;
-				trace!("  - Response: {}", pretty_json);
-
-				// results are reported back with same tool call struct, yes
-				tool_results.push(ToolCall {
-					id: call.id.clone(),
-					call_type: "function".to_string(),
-					function: FunctionCall {
-						name: call.function.name.clone(),
-						arguments: serde_json::to_string(&result)
-							.into_diagnostic()
-							.wrap_err("failed to serialize tool result")?,
-					},
-				});
-
-				// add last so we avoid a clone and can consume the call directly
-				tool_calls.push(call);
+				// No tool calls - we have our final response, unless the model only tried to call tools
+				match response.text().filter(|text| !text.trim().is_empty()) {
+					Some(text) => break text,
+					None => break answer_without_tools(llm_client.as_ref(), &conversation).await?,
+				}
+			},
+		};
+
+		// images returned by tools in this iteration, paired with the name of the tool
+		let mut images: Vec<(ToolCall, ToolMedia)> = Vec::new();
+
+		// tells model which files returned in this iteration will actually be shown to the user
+		let mut attachment_notes: Vec<String> = Vec::new();
+
+		// if we would be over the limit with these calls, we only process as many as we are still allowed to
+		let remaining = max_tool_calls.saturating_sub(tool_calls.len());
+		if new_calls.len() > remaining {
+			debug!(
+				"Reached maximum number of tool calls ({}), skipping {} calls",
+				max_tool_calls,
+				new_calls.len() - remaining
+			);
+			cut_short = true;
+		}
+		let new_calls = new_calls.into_iter().take(remaining).collect::<Vec<_>>();
+
+		// calls within a single turn are independent of each other, so we run them concurrently
+		// join_all keeps the order of the calls, so results can be matched up again
+		let outputs = join_all(new_calls.iter().map(|call| {
+			execute_tool_call(
+				call,
+				app,
+				message,
+				&mcp_connection,
+				&tool_context,
+				tool_status.as_ref(),
+				&tool_approval,
+			)
+		}))
+		.await;
+
+		// results are reported back with same tool call struct, yes
+		let mut turn_results: Vec<ToolCall> = Vec::new();
+		for (call, output) in new_calls.iter().zip(outputs) {
+			let ToolCallOutput {
+				value: result,
+				images: call_images,
+				attachments: call_attachments,
+				sources: call_sources,
+			} = output;
+
+			let pretty_json = serde_json::to_string_pretty(&result)
+				.into_diagnostic()
+				.wrap_err("failed to pretty-print tool result")?;
+			trace!("Response of tool call {} ({}): {}", call.id, call.function.name, pretty_json);
+
+			turn_results.push(ToolCall {
+				id: call.id.clone(),
+				call_type: "function".to_string(),
+				function: FunctionCall {
+					name: call.function.name.clone(),
+					arguments: serde_json::to_string(&result)
+						.into_diagnostic()
+						.wrap_err("failed to serialize tool result")?,
+				},
+			});
+
+			sources.extend(call_sources);
+			images.extend(call_images.into_iter().map(|image| (call.clone(), image)));
+			for attachment in call_attachments {
+				attachment_notes.push(collect_attachment(&mut attachments, attachment));
 			}
+		}
 
-			// add assistant's tool call to conversation
-			conversation.push(ChatMessage::assistant().tool_use(tool_calls.clone()).build());
+		// add assistant's tool calls of this turn to conversation
+		conversation.push(ChatMessage::assistant().tool_use(new_calls.clone()).build());
 
-			// add tool results to conversation
-			conversation.push(ChatMessage::user().tool_result(tool_results.clone()).build());
-		} else {
-			// No tool calls - we have our final response
-			let content = response.text().ok_or(miette!("LLM response has no content"))?;
-			trace!(
-				"Final response after {} iterations, {} tools called{}",
-				iteration + 1,
-				tool_calls.len(),
-				if tool_calls.is_empty() {
-					String::new()
-				} else {
-					format!(
-						":\n{}",
-						tool_calls
-							.iter()
-							.map(|call| format!("  - {} ({})", call.function.name, call.function.arguments))
-							.collect::<Vec<_>>()
-							.join("\n")
-					)
-				}
+		// add tool results to conversation
+		conversation.push(ChatMessage::user().tool_result(turn_results).build());
+
+		// keep track of all calls across iterations
+		tool_calls.extend(new_calls);
+
+		// tool results are text only, so images are passed as separate messages, if the model can see them
+		if app.vision {
+			conversation.extend(images_to_messages(images));
+		}
+
+		if !attachment_notes.is_empty() {
+			conversation.push(
+				ChatMessage::user()
+					.content(format!(
+						"[SYSTEM: {}. The user can see attached files below your reply.]",
+						attachment_notes.join(", ")
+					))
+					.build(),
 			);
+		}
+	};
 
-			let content = invocation_builder.retransform_response(&content);
+	trace!(
+		"Final response after {} iterations, {} tools called{}",
+		iteration,
+		tool_calls.len(),
+		if tool_calls.is_empty() {
+			String::new()
+		} else {
+			format!(
+				":\n{}",
+				tool_calls
+					.iter()
+					.map(|call| format!("  - {} ({})", call.function.name, call.function.arguments))
+					.collect::<Vec<_>>()
+					.join("\n")
+			)
+		}
+	);
+
+	let content = invocation_builder.retransform_response(&content);
+
+	// let user know where the answer comes from, and whether it might be missing information
+	let footer = app.tool_footer.then(|| tools_footer(&tool_calls, &sources)).flatten();
+	let content = finish_reply(content, footer, cut_short);
+
+	let attachments = attachments
+		.into_iter()
+		.map(|attachment| CreateAttachment::bytes(attachment.media.data, attachment.filename));
+
+	let status_message = match tool_status {
+		Some(tool_status) => tool_status.finish().await,
+		None => None,
+	};
+
+	match status_message {
+		// status message already replies to the user, so we turn it into the final reply
+		Some(mut status_message) => {
+			let mut edit = EditMessage::new().content(content);
+			for attachment in attachments {
+				edit = edit.new_attachment(attachment);
+			}
 
+			status_message
+				.edit(ctx, edit)
+				.await
+				.into_diagnostic()
+				.wrap_err("failed to replace status message with reply")?;
+		},
+		None => {
 			message
 				.channel_id
 				.send_message(
@@ -399,15 +655,15 @@ async fn generate_llm_response<'a>(
 					CreateMessage::new()
 						.reference_message(message)
 						.allowed_mentions(CreateAllowedMentions::new().empty_roles().empty_users().replied_user(true))
-						.content(content),
+						.content(content)
+						.add_files(attachments),
 				)
 				.await
 				.into_diagnostic()
 				.wrap_err("failed to send reply message")?;
-
-			return Ok(());
-		}
+		},
 	}
+
 	Ok(())
 }
 
@@ -483,6 +739,9 @@ fn dump_extracted_messages(messages: &[ContextMessageVariant]) {
 			ContextMessageVariant::Initial(_) => "Initial",
 			ContextMessageVariant::ReplyWindow(_) => "ReplyWindow",
 			ContextMessageVariant::History(_) => "History",
+			ContextMessageVariant::Linked(_) => "Linked",
+			ContextMessageVariant::ThreadStarter(..) => "ThreadStarter",
+			ContextMessageVariant::Thread(_) => "Thread",
 		};
 
 		let message: &Message = message.into();
@@ -505,18 +764,478 @@ fn dump_llm_messages(messages: &[ChatMessage]) {
 	}
 }
 
-async fn process_tool_call(tool_call: &ToolCall, mcp_connection: &crate::mcp::McpConnection) -> Result<Value> {
-	match mcp_connection.handle_llm_tool_call(tool_call).await {
-		None => Ok(json!({
-			"id": "tool_not_found",
-			"error": format!("No tool found with name '{}'", tool_call.function.name)
-		})),
+/// Executes a single tool call and prepares the result for the model.
+/// Failures are reported back to the model as result of the respective call, so they never affect other calls.
+async fn execute_tool_call(
+	call: &ToolCall,
+	app: &AppState,
+	message: &Message,
+	mcp_connection: &McpConnection,
+	tool_context: &ToolContext,
+	tool_status: Option<&ToolStatus>,
+	tool_approval: &ToolApproval,
+) -> ToolCallOutput {
+	debug!("Processing tool call: {}", call.function.name);
+	trace!("  - Arguments: {}", call.function.arguments);
+
+	let settings = mcp_connection.tool_settings(&call.function.name);
+	if let Some(tool_status) = tool_status {
+		let label = settings
+			.display_name
+			.clone()
+			.unwrap_or_else(|| format!("Using `{}`", call.function.name));
+		tool_status.tool_started(&call.id, label);
+	}
+
+	let decision = if settings.approval != ApprovalMode::None {
+		if let Some(tool_status) = tool_status {
+			tool_status.tool_progress(&call.id, "waiting for approval".to_string());
+		}
+
+		// if we can't ask, we can't call the tool either
+		tool_approval
+			.request(&call.function, settings.approval)
+			.await
+			.unwrap_or_else(|err| {
+				error!("Failed to ask for approval of tool call: {:?}", err);
+				ApprovalDecision::Denied
+			})
+	} else {
+		ApprovalDecision::Approved
+	};
+
+	let started = Instant::now();
+	let (mut output, error) = if decision == ApprovalDecision::Approved {
+		let on_progress = |progress: String| {
+			if let Some(tool_status) = tool_status {
+				tool_status.tool_progress(&call.id, progress);
+			}
+		};
+		process_tool_call(call, mcp_connection, &app.builtin_tools, tool_context, &on_progress).await
+	} else {
+		debug!("Tool call {} was not approved: {:?}", call.function.name, decision);
+		let error = decision.explanation().to_string();
+		(error_output("tool_not_approved", &error), Some(error))
+	};
+
+	// every call ends up in the audit trail, even if it never reached a server
+	let record = ToolCallRecord {
+		server: mcp_connection.tool_server(&call.function.name).map(str::to_string),
+		tool: call.function.name.clone(),
+		arguments: call.function.arguments.clone(),
+		result_size: result_text(&output.value).chars().count(),
+		duration: started.elapsed(),
+		error,
+	};
+	if let Err(err) = ToolAudit::new(app.db.as_ref()).record(message, record).await {
+		error!("Failed to record tool call in audit trail: {:?}", err);
+	}
+
+	// prevent a single tool from filling the entire context window
+	output.value = limit_tool_result(app.llm_client.as_ref(), &call.function.name, output.value, &settings).await;
+
+	if let Some(tool_status) = tool_status {
+		tool_status.tool_finished(&call.id);
+	}
+
+	output
+}
+
+/// Calls the tool and returns its output, together with the error message if the call failed.
+/// Tools of MCP servers take precedence over built-in tools with the same name.
+async fn process_tool_call(
+	tool_call: &ToolCall,
+	mcp_connection: &McpConnection,
+	builtin_tools: &ToolRegistry,
+	tool_context: &ToolContext,
+	on_progress: &(dyn Fn(String) + Send + Sync),
+) -> (ToolCallOutput, Option<String>) {
+	let builtin_result = match mcp_connection.tool_server(&tool_call.function.name) {
+		Some(_) => None,
+		None => builtin_tools.call(tool_context, &tool_call.function).await,
+	};
+	let result = match builtin_result {
+		Some(result) => Some(result),
+		None => mcp_connection.handle_llm_tool_call(tool_call, on_progress).await,
+	};
+
+	let (id, error) = match result {
+		None => (
+			"tool_not_found",
+			format!("No tool found with name '{}'", tool_call.function.name),
+		),
 		Some(result) => match result {
-			Ok(value) => Ok(value),
-			Err(err) => Ok(json!({
-				"id": "tool_error",
-				"error": format!("Tool execution failed: {}", err)
-			})),
+			Ok(output) => return (output, None),
+			Err(err) => ("tool_error", format!("Tool execution failed: {}", err)),
 		},
+	};
+
+	(error_output(id, &error), Some(error))
+}
+
+/// Output reporting a failed tool call to the model.
+fn error_output(id: &str, error: &str) -> ToolCallOutput {
+	ToolCallOutput {
+		value: json!({
+			"id": id,
+			"error": error,
+		}),
+		images: Vec::new(),
+		attachments: Vec::new(),
+		sources: Vec::new(),
+	}
+}
+
+/// Text of a tool result as it is passed to the model.
+fn result_text(result: &Value) -> String {
+	match result {
+		Value::String(text) => text.clone(),
+		other => other.to_string(),
+	}
+}
+
+/// Appends the tools footer and the note about tool use being cut short to the answer, keeping the reply within the
+/// length limit of Discord. The note takes precedence over the footer, which is dropped if it doesn't fit. Answers
+/// which are too long on their own are truncated.
+fn finish_reply(answer: String, footer: Option<String>, cut_short: bool) -> String {
+	let note = cut_short.then_some(CUT_SHORT_NOTE);
+	let reserved = note.map_or(0, |note| note.chars().count() + 1);
+
+	let mut content = truncate_chars(&answer, MAX_MESSAGE_LENGTH - reserved);
+	if let Some(footer) = footer {
+		// less than the limit, leaving room for the line break in front of the footer
+		if content.chars().count() + footer.chars().count() < MAX_MESSAGE_LENGTH - reserved {
+			content.push('\n');
+			content.push_str(&footer);
+		} else {
+			debug!("Dropping tools footer, the reply would exceed the length limit");
+		}
+	}
+	if let Some(note) = note {
+		content.push('\n');
+		content.push_str(note);
+	}
+
+	content
+}
+
+/// Asks the model once more for an answer, without offering any tools. Some models keep trying to call tools, even if
+/// none are offered, in which case the user gets a fixed reply instead of an error.
+async fn answer_without_tools(llm_client: &(dyn LLMProvider + Send + Sync), conversation: &[ChatMessage]) -> Result<String> {
+	let mut conversation = conversation.to_vec();
+	conversation.push(
+		ChatMessage::user()
+			.content("[SYSTEM: Tools are not available. Answer the user with text only.]")
+			.build(),
+	);
+
+	let response = llm_client
+		.chat_with_tools(&conversation, None)
+		.await
+		.into_diagnostic()
+		.wrap_err("completion request failed")?;
+
+	match response.text().filter(|text| !text.trim().is_empty()) {
+		Some(text) => Ok(text),
+		None => {
+			debug!("Model didn't answer with text when asked again, falling back to a fixed reply");
+			Ok(FALLBACK_ANSWER.to_string())
+		},
+	}
+}
+
+/// Applies the configured size limit to a tool result.
+/// Oversized results are either summarized by the model or truncated. If summarization fails, the result is truncated
+/// instead.
+async fn limit_tool_result(
+	llm_client: &(dyn LLMProvider + Send + Sync),
+	tool_name: &str,
+	result: Value,
+	settings: &ToolSettings,
+) -> Value {
+	let max_size = settings.max_result_size;
+	let text = result_text(&result);
+
+	let size = text.chars().count();
+	if size <= max_size || !settings.summarize {
+		return truncate_result(result, max_size);
+	}
+
+	// summarizing huge results is expensive as well, so we also limit what we pass to the summary
+	let input = match truncate_result(Value::String(text), max_size * 4) {
+		Value::String(input) => input,
+		other => other.to_string(),
+	};
+	let prompt = format!(
+		"The following is the output of the tool '{}'. Summarize it in at most {} characters, keeping all facts, numbers and links \
+		 that could be relevant. Reply with the summary only.\n\n{}",
+		tool_name, max_size, input
+	);
+
+	match llm_client.chat(&[ChatMessage::user().content(prompt).build()]).await {
+		Ok(response) => match response.text() {
+			Some(summary) => {
+				debug!("Summarized {} characters of output from tool '{}'", size, tool_name);
+				let summary = format!("[summary of {} characters of tool output]\n{}", size, summary);
+				truncate_result(Value::String(summary), max_size)
+			},
+			None => truncate_result(result, max_size),
+		},
+		Err(err) => {
+			debug!(
+				"Failed to summarize output of tool '{}', truncating instead: {}",
+				tool_name, err
+			);
+			truncate_result(result, max_size)
+		},
+	}
+}
+
+/// Adds a file returned by a tool to the attachments of the reply, if Discord limits allow it.
+/// Returns a note for the model, telling it whether the file will be shown to the user.
+fn collect_attachment(attachments: &mut Vec<ToolAttachment>, mut attachment: ToolAttachment) -> String {
+	if attachment.media.data.len() > MAX_ATTACHMENT_SIZE {
+		return format!("{} can not be attached, since it is too large", attachment.filename);
+	}
+
+	if attachments.len() >= MAX_ATTACHMENTS {
+		return format!("{} can not be attached, since there are too many files", attachment.filename);
+	}
+
+	// discord requires unique file names within a message
+	if attachments.iter().any(|other| other.filename == attachment.filename) {
+		attachment.filename = format!("{}-{}", attachments.len() + 1, attachment.filename);
+	}
+
+	let note = format!("{} will be attached to your reply", attachment.filename);
+	attachments.push(attachment);
+	note
+}
+
+/// Lists the tools used for a reply and the sources they returned, formatted as Discord subtext.
+/// Returns `None` if no tools were used.
+fn tools_footer(tool_calls: &[ToolCall], sources: &[String]) -> Option<String> {
+	if tool_calls.is_empty() {
+		return None;
+	}
+
+	let mut calls = tool_calls
+		.iter()
+		.take(MAX_FOOTER_ENTRIES)
+		.map(|call| {
+			let arguments = summarize_arguments(&call.function.arguments);
+			if arguments.is_empty() {
+				format!("`{}`", call.function.name)
+			} else {
+				format!("`{}` ({})", call.function.name, arguments)
+			}
+		})
+		.collect::<Vec<_>>();
+	if tool_calls.len() > MAX_FOOTER_ENTRIES {
+		calls.push(format!("{} more", tool_calls.len() - MAX_FOOTER_ENTRIES));
+	}
+
+	let mut footer = format!("-# Tools used: {}", calls.join(", "));
+
+	// multiple tools often return the same links
+	let mut unique_sources: Vec<&String> = Vec::new();
+	for source in sources {
+		if !unique_sources.contains(&source) {
+			unique_sources.push(source);
+		}
+	}
+
+	if !unique_sources.is_empty() {
+		// angle brackets prevent Discord from embedding every single link
+		let mut links = unique_sources
+			.iter()
+			.take(MAX_FOOTER_ENTRIES)
+			.map(|source| format!("<{}>", source))
+			.collect::<Vec<_>>();
+		if unique_sources.len() > MAX_FOOTER_ENTRIES {
+			links.push(format!("{} more", unique_sources.len() - MAX_FOOTER_ENTRIES));
+		}
+
+		footer.push_str(&format!("\n-# Sources: {}", links.join(", ")));
+	}
+
+	Some(footer)
+}
+
+/// Condenses the JSON arguments of a tool call into a short summary, e.g. `query: rust async`.
+/// Long values are shortened, since the footer only needs to hint at what the tool was asked.
+fn summarize_arguments(arguments: &str) -> String {
+	const MAX_VALUE_LENGTH: usize = 40;
+
+	let arguments = match serde_json::from_str::<Value>(arguments) {
+		Ok(Value::Object(arguments)) => arguments,
+		_ => return String::new(),
+	};
+
+	arguments
+		.iter()
+		.filter(|(_, value)| !value.is_null())
+		.map(|(key, value)| {
+			let value = match value {
+				Value::String(text) => text.clone(),
+				other => other.to_string(),
+			};
+
+			// backticks and line breaks would break the formatting of the footer
+			let mut value = value.replace(['`', '\n'], " ");
+			if value.chars().count() > MAX_VALUE_LENGTH {
+				value = format!("{}…", value.chars().take(MAX_VALUE_LENGTH).collect::<String>());
+			}
+
+			format!("{}: {}", key, value)
+		})
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Converts images returned by tools into messages for vision capable models.
+/// Images in formats not supported by the llm crate are skipped, the model will only see their placeholder.
+fn images_to_messages(images: Vec<(ToolCall, ToolMedia)>) -> Vec<ChatMessage> {
+	let mut numbers = HashMap::<String, usize>::new();
+	let mut messages = Vec::new();
+
+	for (call, image) in images {
+		// placeholders are numbered per call, a tool called twice in a turn starts over for the second call
+		let number = numbers.entry(call.id.clone()).or_default();
+		*number += 1;
+
+		let mime = match image.mime_type.as_str() {
+			"image/jpeg" => ImageMime::JPEG,
+			"image/png" => ImageMime::PNG,
+			"image/gif" => ImageMime::GIF,
+			"image/webp" => ImageMime::WEBP,
+			_ => {
+				debug!(
+					"Skipping image of unsupported type {} returned by tool '{}'",
+					image.mime_type, call.function.name
+				);
+				continue;
+			},
+		};
+
+		messages.push(
+			ChatMessage::user()
+				.content(format!(
+					"[SYSTEM: image #{} returned by call {} of tool '{}']",
+					number, call.id, call.function.name
+				))
+				.image(mime, image.data)
+				.build(),
+		);
+	}
+
+	messages
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn call(name: &str, arguments: &str) -> ToolCall {
+		ToolCall {
+			id: name.to_string(),
+			call_type: "function".to_string(),
+			function: FunctionCall {
+				name: name.to_string(),
+				arguments: arguments.to_string(),
+			},
+		}
+	}
+
+	#[test]
+	fn test_summarize_arguments() {
+		assert_eq!(summarize_arguments(r#"{"query": "rust async"}"#), "query: rust async");
+		assert_eq!(
+			summarize_arguments(r#"{"count": 3, "query": "a `b`\nc", "skip": null}"#),
+			"count: 3, query: a  b  c"
+		);
+		assert_eq!(
+			summarize_arguments(r#"{"text": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"}"#),
+			format!("text: {}…", "a".repeat(40))
+		);
+		assert_eq!(summarize_arguments("{}"), "");
+		assert_eq!(summarize_arguments("not json"), "");
+	}
+
+	#[test]
+	fn test_tools_footer() {
+		assert_eq!(tools_footer(&[], &["https://example.com".to_string()]), None);
+
+		let calls = [call("time", "{}"), call("search", r#"{"query": "rust"}"#)];
+		assert_eq!(
+			tools_footer(&calls, &[]),
+			Some("-# Tools used: `time`, `search` (query: rust)".to_string())
+		);
+
+		let sources = ["https://a.com", "https://b.com", "https://a.com"].map(str::to_string);
+		assert_eq!(
+			tools_footer(&calls, &sources),
+			Some("-# Tools used: `time`, `search` (query: rust)\n-# Sources: <https://a.com>, <https://b.com>".to_string())
+		);
+	}
+
+	#[test]
+	fn test_finish_reply() {
+		let footer = || Some("-# Tools used: `time`".to_string());
+		assert_eq!(
+			finish_reply("answer".to_string(), footer(), false),
+			"answer\n-# Tools used: `time`"
+		);
+		assert_eq!(
+			finish_reply("answer".to_string(), footer(), true),
+			format!("answer\n-# Tools used: `time`\n{}", CUT_SHORT_NOTE)
+		);
+
+		// the footer is dropped instead of exceeding the limit
+		let long = "a".repeat(MAX_MESSAGE_LENGTH - 10);
+		assert_eq!(finish_reply(long.clone(), footer(), false), long);
+
+		// the note is kept, cutting into the answer if needed
+		let reply = finish_reply("a".repeat(MAX_MESSAGE_LENGTH), footer(), true);
+		assert_eq!(reply.chars().count(), MAX_MESSAGE_LENGTH);
+		assert!(reply.ends_with(&format!("…\n{}", CUT_SHORT_NOTE)));
+	}
+
+	#[test]
+	fn test_images_to_messages() {
+		let image = |mime_type: &str| ToolMedia {
+			mime_type: mime_type.to_string(),
+			data: vec![0],
+		};
+		let mut first = call("screenshot", "{}");
+		first.id = "call_1".to_string();
+		let mut second = call("screenshot", "{}");
+		second.id = "call_2".to_string();
+
+		let messages = images_to_messages(vec![
+			(first.clone(), image("image/png")),
+			(first, image("image/png")),
+			(second.clone(), image("image/bmp")),
+			(second, image("image/jpeg")),
+		]);
+		let labels = messages.iter().map(|message| message.content.as_str()).collect::<Vec<_>>();
+		assert_eq!(labels, vec![
+			"[SYSTEM: image #1 returned by call call_1 of tool 'screenshot']",
+			"[SYSTEM: image #2 returned by call call_1 of tool 'screenshot']",
+			"[SYSTEM: image #2 returned by call call_2 of tool 'screenshot']",
+		]);
+	}
+
+	#[test]
+	fn test_tools_footer_limits_entries() {
+		let calls = (0..7).map(|i| call(&format!("tool{}", i), "{}")).collect::<Vec<_>>();
+		let sources = (0..6).map(|i| format!("https://{}.com", i)).collect::<Vec<_>>();
+
+		let footer = tools_footer(&calls, &sources).unwrap();
+		assert_eq!(
+			footer,
+			"-# Tools used: `tool0`, `tool1`, `tool2`, `tool3`, `tool4`, 2 more\n-# Sources: <https://0.com>, <https://1.com>, \
+			 <https://2.com>, <https://3.com>, <https://4.com>, 1 more"
+		);
 	}
 }
@@ -0,0 +1,62 @@
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::{
+	CreateReply,
+	serenity_prelude::CreateEmbed,
+};
+use sea_orm::ActiveValue::Set;
+
+use crate::{
+	Context,
+	builtin_tools::{
+		load_guild_settings,
+		update_guild_settings,
+	},
+	context_extraction::HistorySelection,
+};
+
+/// Shows or changes how messages from the channel history are selected as context in this server.
+#[poise::command(
+	slash_command,
+	guild_only,
+	ephemeral,
+	default_member_permissions = "MANAGE_GUILD",
+	required_permissions = "MANAGE_GUILD"
+)]
+pub async fn history(
+	ctx: Context<'_>,
+	#[description = "Whether to take the most recent or the most relevant messages"] selection: Option<HistorySelection>,
+) -> Result<()> {
+	let guild_id = ctx.guild_id().ok_or(miette!("command is only available in guilds"))?;
+	let db = ctx.data().db.as_ref();
+
+	let settings = match selection {
+		Some(selection) => Some(
+			update_guild_settings(db, guild_id, |settings| {
+				settings.ranked_history = Set(selection == HistorySelection::Relevant)
+			})
+			.await?,
+		),
+		None => load_guild_settings(db, guild_id).await?,
+	};
+
+	let description = match HistorySelection::from(settings.as_ref()) {
+		HistorySelection::Recent => "The most recent messages of the channel are included as context.",
+		HistorySelection::Relevant => {
+			"The last few messages of the channel are included as context, followed by the messages most relevant to the message the \
+			 bot is answering."
+		},
+	};
+
+	ctx
+		.send(CreateReply::default().embed(CreateEmbed::new().title("Channel history").description(description)))
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to send message")?;
+
+	Ok(())
+}
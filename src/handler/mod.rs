@@ -1,3 +1,6 @@
+pub mod actions;
 pub mod admin;
 pub mod completion;
+pub mod history;
 pub mod opt_out;
+pub mod prompts;
@@ -26,9 +26,8 @@ use sea_orm::{
 };
 
 use crate::{
-	message_cache::MessageCache,
-	user_from_db_or_create,
 	Context,
+	user_from_db_or_create,
 };
 
 #[derive(PartialEq)]
@@ -84,7 +83,7 @@ pub async fn opt_out_dialogue(ctx: Context<'_>) -> Result<()> {
 	let id = ctx.id();
 	let app = ctx.data();
 	let lockout_duration = &app.opt_out_lockout;
-	let db_user = user_from_db_or_create(&app.db, ctx.author()).await?;
+	let db_user = user_from_db_or_create(app.db.as_ref(), ctx.author()).await?;
 
 	let state = calculate_state(lockout_duration, db_user);
 
@@ -129,7 +128,7 @@ pub async fn opt_out_dialogue(ctx: Context<'_>) -> Result<()> {
 
 	if let Some(interaction) = response {
 		// user could modify state from different interaction, so we need to recalculate it, on mismatch, we return an error
-		let db_user = user_from_db_or_create(&app.db, ctx.author()).await?;
+		let db_user = user_from_db_or_create(app.db.as_ref(), ctx.author()).await?;
 		if calculate_state(lockout_duration, db_user.clone()) != state {
 			return Err(miette!("state of user has changed during interaction"));
 		}
@@ -156,11 +155,10 @@ pub async fn opt_out_dialogue(ctx: Context<'_>) -> Result<()> {
 			return Ok(());
 		};
 
-		let cache = MessageCache::new(&app.db);
-		cache.delete_from_user(ctx.author().id).await?;
+		app.message_cache.delete_from_user(ctx.author().id).await?;
 
 		db_user
-			.update(&app.db)
+			.update(app.db.as_ref())
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to update user")?;
@@ -0,0 +1,430 @@
+use log::warn;
+use miette::{
+	IntoDiagnostic,
+	Report,
+	Result,
+	WrapErr,
+	miette,
+};
+use poise::{
+	ApplicationContext,
+	BoxFuture,
+	Command,
+	CommandParameter,
+	CreateReply,
+	FrameworkError,
+	serenity_prelude::{
+		CommandOptionType,
+		ResolvedValue,
+	},
+};
+use rmcp::model::{
+	JsonObject,
+	Prompt,
+};
+use serde_json::Value;
+
+use crate::{
+	AppState,
+	Context,
+	handler::{
+		admin::get_blacklist_for_user,
+		completion::check_rate_limit,
+	},
+	mcp::{
+		ClientServices,
+		InvocationContext,
+		McpManager,
+	},
+	text::truncate_chars,
+	user_from_db_or_create,
+};
+
+/// Discord allows at most 25 subcommands per command, and 25 options per subcommand.
+const MAX_DISCORD_OPTIONS: usize = 25;
+
+/// Names of commands and options are limited to 32 characters.
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Descriptions of commands and options are limited to 100 characters.
+const MAX_DESCRIPTION_LENGTH: usize = 100;
+
+/// Discord limits the length of a message.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Identifies the prompt behind a generated subcommand, stored in the custom data of the command.
+#[derive(Debug, PartialEq)]
+struct PromptCommand {
+	server_name: String,
+	prompt_name: String,
+
+	/// Names of the command options, paired with the names of the prompt arguments they map to.
+	arguments: Vec<(String, String)>,
+}
+
+/// Runs a prompt provided by one of the connected MCP servers.
+#[poise::command(slash_command)]
+async fn prompt(_ctx: Context<'_>) -> Result<()> {
+	unreachable!("This command is only available as a subcommand")
+}
+
+/// Registers the `prompt` command, with a subcommand for each prompt offered by MCP servers with prompts enabled.
+/// Prompts are only listed on startup, commands have to be registered with Discord again once they changed.
+pub async fn register_commands(commands: &mut Vec<Command<AppState, Report>>, mcp_manager: &McpManager) -> Result<()> {
+	let connection = mcp_manager.create_connection(None, ClientServices::default()).await?;
+	let prompts = connection.list_prompts().await?;
+	if prompts.is_empty() {
+		return Ok(());
+	}
+
+	let mut subcommands: Vec<Command<AppState, Report>> = Vec::new();
+	for (server_name, prompt) in prompts {
+		if subcommands.len() >= MAX_DISCORD_OPTIONS {
+			warn!(
+				"Too many MCP prompts, skipping prompt '{}' of server '{}'",
+				prompt.name, server_name
+			);
+			continue;
+		}
+
+		let name = command_name(&prompt.name);
+		if !is_valid_name(&name) || subcommands.iter().any(|command| command.name == name) {
+			warn!(
+				"Skipping prompt '{}' of MCP server '{}', command name '{}' is invalid or already taken",
+				prompt.name, server_name, name
+			);
+			continue;
+		}
+
+		subcommands.push(prompt_command(name, server_name, prompt));
+	}
+
+	let mut command = self::prompt();
+	command.subcommands = subcommands;
+	command.subcommand_required = true;
+	commands.push(command);
+
+	Ok(())
+}
+
+/// Converts a name into one accepted by Discord for commands and options: lowercase, without whitespace or special
+/// characters.
+fn command_name(name: &str) -> String {
+	name
+		.trim()
+		.chars()
+		.flat_map(char::to_lowercase)
+		.map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+		.take(MAX_NAME_LENGTH)
+		.collect()
+}
+
+/// Whether a converted name is usable, names made of punctuation only would be meaningless to users.
+fn is_valid_name(name: &str) -> bool {
+	name.chars().any(char::is_alphanumeric)
+}
+
+/// Creates the subcommand running the given prompt, with an option for each argument of the prompt.
+fn prompt_command(name: String, server_name: String, prompt: Prompt) -> Command<AppState, Report> {
+	let mut arguments = prompt.arguments.unwrap_or_default();
+
+	// discord requires required options to come first
+	arguments.sort_by_key(|argument| !argument.required.unwrap_or(false));
+
+	let mut names: Vec<String> = Vec::new();
+	let arguments = arguments
+		.into_iter()
+		.filter_map(|argument| {
+			let name = command_name(&argument.name);
+			if !is_valid_name(&name) || names.contains(&name) {
+				warn!(
+					"Skipping argument '{}' of prompt '{}' of MCP server '{}', option name '{}' is invalid or already taken",
+					argument.name, prompt.name, server_name, name
+				);
+				return None;
+			}
+
+			names.push(name.clone());
+			Some((name, argument))
+		})
+		.take(MAX_DISCORD_OPTIONS)
+		.collect::<Vec<_>>();
+
+	let parameters = arguments
+		.iter()
+		.map(|(name, argument)| CommandParameter {
+			name: name.clone(),
+			name_localizations: Default::default(),
+			description: argument
+				.description
+				.as_deref()
+				.map(|description| truncate_chars(description, MAX_DESCRIPTION_LENGTH)),
+			description_localizations: Default::default(),
+			required: argument.required.unwrap_or(false),
+			channel_types: None,
+			choices: Vec::new(),
+			type_setter: Some(|option| option.kind(CommandOptionType::String)),
+			autocomplete_callback: None,
+			__non_exhaustive: (),
+		})
+		.collect::<Vec<_>>();
+
+	let custom_data = PromptCommand {
+		server_name,
+		prompt_name: prompt.name,
+		arguments: arguments.into_iter().map(|(name, argument)| (name, argument.name)).collect(),
+	};
+
+	Command {
+		name: name.clone(),
+		identifying_name: format!("prompt {}", name),
+		source_code_name: name,
+		description: prompt
+			.description
+			.as_deref()
+			.map(|description| truncate_chars(description, MAX_DESCRIPTION_LENGTH)),
+		slash_action: Some(run_prompt_action),
+		parameters,
+		custom_data: Box::new(custom_data),
+		..Default::default()
+	}
+}
+
+fn run_prompt_action(
+	ctx: ApplicationContext<'_, AppState, Report>,
+) -> BoxFuture<'_, Result<(), FrameworkError<'_, AppState, Report>>> {
+	Box::pin(async move {
+		run_prompt(ctx)
+			.await
+			.map_err(|error| FrameworkError::new_command(ctx.into(), error))
+	})
+}
+
+/// Fetches the prompt with the arguments given by the user, and replies with the completion of the model.
+/// Subject to the same checks as a mention of the bot.
+async fn run_prompt(app_ctx: ApplicationContext<'_, AppState, Report>) -> Result<()> {
+	let command = app_ctx
+		.command
+		.custom_data
+		.downcast_ref::<PromptCommand>()
+		.ok_or_else(|| miette!("command is not backed by an MCP prompt"))?;
+
+	let mut arguments = JsonObject::new();
+	for option in app_ctx.args {
+		let argument = command.arguments.iter().find(|(name, _)| name == option.name);
+		if let (Some((_, argument)), ResolvedValue::String(value)) = (argument, &option.value) {
+			arguments.insert(argument.clone(), Value::String(value.to_string()));
+		}
+	}
+
+	let display_name = app_ctx
+		.interaction
+		.member
+		.as_ref()
+		.and_then(|member| member.nick.clone())
+		.unwrap_or_else(|| {
+			app_ctx
+				.interaction
+				.user
+				.global_name
+				.clone()
+				.unwrap_or(app_ctx.interaction.user.name.clone())
+		});
+
+	let ctx = Context::from(app_ctx);
+	let app = ctx.data();
+
+	if let Some(reason) = refusal_reason(ctx).await? {
+		ctx
+			.send(CreateReply::default().ephemeral(true).content(reason))
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to refuse prompt command")?;
+		return Ok(());
+	}
+
+	ctx
+		.defer()
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to defer prompt command")?;
+
+	let invocation_context = InvocationContext {
+		user_id: ctx.author().id.to_string(),
+		guild_id: ctx.guild_id().map(|id| id.to_string()),
+		channel_id: ctx.channel_id().to_string(),
+		display_name,
+	};
+	let connection = app
+		.mcp_manager
+		.create_connection(Some(invocation_context), ClientServices::default())
+		.await?;
+	let messages = connection
+		.get_prompt(&command.server_name, &command.prompt_name, arguments)
+		.await?;
+
+	let response = tokio::time::timeout(app.completion_timeout, app.llm_client.chat(&messages))
+		.await
+		.map_err(|_| miette!("completion request timed out"))?
+		.into_diagnostic()
+		.wrap_err("completion request failed")?;
+	let content = response.text().ok_or(miette!("LLM response has no content"))?;
+
+	ctx
+		.say(truncate_chars(&content, MAX_MESSAGE_LENGTH))
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to send prompt response")?;
+
+	Ok(())
+}
+
+/// Tells the user why the prompt can't be run for them, if it can't.
+async fn refusal_reason(ctx: Context<'_>) -> Result<Option<&'static str>> {
+	let app = ctx.data();
+
+	let db_user = user_from_db_or_create(app.db.as_ref(), ctx.author()).await?;
+	if db_user.opt_out_since.is_some() {
+		return Ok(Some("You opted out, so the bot won't process your requests."));
+	}
+
+	if get_blacklist_for_user(&app.db, ctx.author().id).await?.is_some() {
+		return Ok(Some("You are not allowed to use the bot."));
+	}
+
+	if !app.whitelist.contains(ctx.channel_id(), &ctx.serenity_context()).await? {
+		return Ok(Some("This channel is not whitelisted."));
+	}
+
+	// bot owner can always use the bot, but we still hit the database
+	let is_owner = ctx.framework().options().owners.contains(&ctx.author().id);
+	if !check_rate_limit(app, ctx.author().id, ctx.channel_id(), ctx.guild_id()).await? && !is_owner {
+		return Ok(Some("I'm currently receiving too many requests, please try again later."));
+	}
+
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use rmcp::model::PromptArgument;
+
+	use super::*;
+
+	#[test]
+	fn test_command_name() {
+		assert_eq!(command_name("summarize"), "summarize");
+		assert_eq!(command_name(" Code Review "), "code-review");
+		assert_eq!(command_name("explain_this.file"), "explain_this-file");
+		assert_eq!(command_name(&"a".repeat(40)).len(), MAX_NAME_LENGTH);
+
+		assert!(is_valid_name("code-review"));
+		assert!(!is_valid_name(""));
+		assert!(!is_valid_name("---"));
+	}
+
+	#[test]
+	fn test_prompt_command() {
+		let argument = |name: &str, required| PromptArgument {
+			name: name.to_string(),
+			description: Some(format!("The {}", name)),
+			required,
+		};
+		let prompt = Prompt {
+			name: "Code Review".to_string(),
+			description: Some("d".repeat(150)),
+			arguments: Some(vec![
+				argument("language", None),
+				argument("codeSnippet", Some(true)),
+				argument("style", Some(false)),
+			]),
+		};
+
+		let command = prompt_command("code-review".to_string(), "github".to_string(), prompt);
+		assert_eq!(command.name, "code-review");
+		assert_eq!(
+			command.description.as_ref().map(|d| d.chars().count()),
+			Some(MAX_DESCRIPTION_LENGTH)
+		);
+
+		let parameters = command
+			.parameters
+			.iter()
+			.map(|parameter| (parameter.name.as_str(), parameter.required))
+			.collect::<Vec<_>>();
+		assert_eq!(parameters, vec![("codesnippet", true), ("language", false), ("style", false)]);
+
+		let custom_data = command.custom_data.downcast_ref::<PromptCommand>().unwrap();
+		assert_eq!(custom_data, &PromptCommand {
+			server_name: "github".to_string(),
+			prompt_name: "Code Review".to_string(),
+			arguments: vec![
+				("codesnippet".to_string(), "codeSnippet".to_string()),
+				("language".to_string(), "language".to_string()),
+				("style".to_string(), "style".to_string()),
+			],
+		});
+
+		let mut prompt = self::prompt();
+		prompt.subcommands = vec![command];
+		prompt.subcommand_required = true;
+		let registered = serde_json::to_value(prompt.create_as_slash_command().unwrap()).unwrap();
+
+		let subcommand = &registered["options"][0];
+		assert_eq!(subcommand["name"], "code-review");
+		assert_eq!(subcommand["type"], u8::from(CommandOptionType::SubCommand));
+
+		let options = subcommand["options"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|option| {
+				(
+					option["name"].as_str().unwrap(),
+					option["type"].as_u64().unwrap(),
+					option["required"].as_bool().unwrap_or(false),
+				)
+			})
+			.collect::<Vec<_>>();
+		let string = u64::from(u8::from(CommandOptionType::String));
+		assert_eq!(options, vec![
+			("codesnippet", string, true),
+			("language", string, false),
+			("style", string, false)
+		]);
+	}
+
+	#[test]
+	fn test_prompt_command_skips_invalid_arguments() {
+		let argument = |name: &str| PromptArgument {
+			name: name.to_string(),
+			description: None,
+			required: None,
+		};
+		let prompt = Prompt {
+			name: "search".to_string(),
+			description: None,
+			arguments: Some(vec![
+				argument("Foo"),
+				argument("foo"),
+				argument("?!"),
+				argument(" "),
+				argument("bar"),
+			]),
+		};
+
+		let command = prompt_command("search".to_string(), "web".to_string(), prompt);
+		let parameters = command
+			.parameters
+			.iter()
+			.map(|parameter| parameter.name.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(parameters, vec!["foo", "bar"]);
+
+		let custom_data = command.custom_data.downcast_ref::<PromptCommand>().unwrap();
+		assert_eq!(custom_data.arguments, vec![
+			("foo".to_string(), "Foo".to_string()),
+			("bar".to_string(), "bar".to_string()),
+		]);
+	}
+}
@@ -6,10 +6,13 @@ use poise::serenity_prelude::{
 	EmojiId,
 	Message,
 	MessageId,
+	MessageType,
 	UserId,
 };
 use regex::Regex;
 
+use crate::context_extraction::ThreadInfo;
+
 // discord user mention regex
 lazy_static! {
 	static ref USER_MENTION_REGEX: Regex = Regex::new(r"<@!?(?P<id>\d+)>").unwrap();
@@ -52,6 +55,9 @@ pub struct InvocationBuilder {
 
 	/// Mapping of emotes that reply can use.
 	emote_cache: HashMap<String, EmojiId>,
+
+	/// Facts about thread starters, describing the thread they started.
+	thread_facts: HashMap<MessageId, String>,
 }
 
 // TODO: implement database lookup for emoji and user ids
@@ -67,7 +73,22 @@ impl InvocationBuilder {
 			input_messages: Vec::new(),
 			user_cache,
 			emote_cache: HashMap::new(),
+			thread_facts: HashMap::new(),
+		}
+	}
+
+	/// Adds the message a thread was started with to the conversation, describing the thread to the model.
+	pub fn add_thread_starter(&mut self, message: &Message, thread: &ThreadInfo) {
+		let mut fact = match thread.forum_post {
+			true => format!("starts the forum post \"{}\"", thread.title),
+			false => format!("starts the thread \"{}\"", thread.title),
+		};
+		if !thread.tags.is_empty() {
+			fact.push_str(&format!(" tagged {}", thread.tags.join(", ")));
 		}
+
+		self.thread_facts.insert(message.id, fact);
+		self.add_message(message);
 	}
 
 	/// Adds a message to the conversation.
@@ -97,6 +118,9 @@ impl InvocationBuilder {
 		let mut message_lookup = HashMap::<MessageId, usize>::new();
 		let mut message_counter = 1;
 
+		// the last message is the one the bot was invoked with
+		let invocation_channel_id = self.input_messages.last().map(|message| message.channel_id);
+
 		for message in self.input_messages.iter() {
 			// some messages are completely empty, since they only contain embeds or attachments, we skip those
 			if message.content.is_empty() {
@@ -109,11 +133,23 @@ impl InvocationBuilder {
 			let mut facts = vec![format!("message no. {}", message_counter)];
 
 			// if message is reply to other message, check if the message is in the lookup table and include reference
-			if let Some(referenced_message) = &message.referenced_message {
-				if let Some(ref_number) = message_lookup.get(&referenced_message.id) {
-					facts.push(format!("reply to message no. {}", ref_number));
-				};
+			// messages restored from the message cache only carry the reference, not the replied message itself
+			let replied_id = match &message.message_reference {
+				Some(reference) if message.kind == MessageType::InlineReply => reference.message_id,
+				_ => None,
 			};
+			if let Some(ref_number) = replied_id.and_then(|replied_id| message_lookup.get(&replied_id)) {
+				facts.push(format!("reply to message no. {}", ref_number));
+			}
+
+			// thread starters can be in the parent channel, but are part of the conversation
+			if let Some(fact) = self.thread_facts.get(&message.id) {
+				facts.push(fact.clone());
+			} else if invocation_channel_id.is_some_and(|channel_id| channel_id != message.channel_id) {
+				// linked messages can be from other channels, which the model would otherwise mistake for part of the
+				// conversation
+				facts.push("linked message from another channel".to_string());
+			}
 
 			// add information about things the model can't see
 			if has_attachments || has_embeds {
@@ -224,3 +260,58 @@ impl InvocationBuilder {
 		result.to_string()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use poise::serenity_prelude::ChannelId;
+
+	use super::*;
+
+	fn message(id: u64, channel_id: u64, author: &str, content: &str) -> Message {
+		let mut message = Message::default();
+		message.id = MessageId::new(id);
+		message.channel_id = ChannelId::new(channel_id);
+		message.author.id = UserId::new(id + 100);
+		message.author.name = author.to_string();
+		message.content = content.to_string();
+		message
+	}
+
+	fn headers(builder: &InvocationBuilder) -> Vec<String> {
+		builder
+			.build_llm_messages()
+			.into_iter()
+			.step_by(2)
+			.map(|message| message.content)
+			.collect()
+	}
+
+	#[test]
+	fn test_add_thread_starter() {
+		// forum posts start with a message in the post itself
+		let mut builder = InvocationBuilder::new(UserId::new(1), "bot");
+		builder.add_thread_starter(&message(10, 10, "alice", "It crashes right away"), &ThreadInfo {
+			title: "Crash on startup".to_string(),
+			tags: vec!["bug".to_string(), "windows".to_string()],
+			forum_post: true,
+		});
+		builder.add_message(&message(11, 10, "bob", "same here"));
+		assert_eq!(headers(&builder), vec![
+			"[SYSTEM: message no. 1, starts the forum post \"Crash on startup\" tagged bug, windows]",
+			"[SYSTEM: message no. 2]",
+		]);
+
+		// other threads are started from a message in the parent channel, which isn't a linked message though
+		let mut builder = InvocationBuilder::new(UserId::new(1), "bot");
+		builder.add_thread_starter(&message(20, 2, "alice", "Let's plan the release"), &ThreadInfo {
+			title: "Release planning".to_string(),
+			tags: Vec::new(),
+			forum_post: false,
+		});
+		builder.add_message(&message(21, 20, "bob", "Friday?"));
+		assert_eq!(headers(&builder), vec![
+			"[SYSTEM: message no. 1, starts the thread \"Release planning\"]",
+			"[SYSTEM: message no. 2]",
+		]);
+	}
+}
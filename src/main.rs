@@ -1,16 +1,34 @@
+mod builtin_tools;
+mod channel_access;
+mod channel_history;
 mod context_extraction;
 mod gcra;
 mod handler;
 mod invocation_builder;
 mod mcp;
 mod mcp_config;
+mod mcp_elicitation;
+mod mcp_sampling;
 mod message_cache;
+mod message_link;
+mod prompt_budget;
 mod rate_limit_config;
+mod relevance;
+mod text;
+mod tokenizer;
+mod tool_approval;
+mod tool_audit;
+mod tool_status;
 
 use std::{
 	collections::HashSet,
-	num::NonZeroU32,
+	num::{
+		NonZeroU32,
+		NonZeroUsize,
+	},
+	path::Path,
 	str::FromStr,
+	sync::Arc,
 	time::Duration,
 };
 
@@ -28,6 +46,7 @@ use llm::{
 		LLMBuilder,
 	},
 };
+use lru::LruCache;
 use miette::{
 	IntoDiagnostic,
 	Report,
@@ -50,6 +69,7 @@ use poise::{
 		CreateAllowedMentions,
 		FullEvent,
 		GatewayIntents,
+		Message,
 		User,
 	},
 };
@@ -69,30 +89,41 @@ use tera::Tera;
 use tokio::sync::Mutex;
 use tracing::{
 	Instrument,
+	debug,
 	error,
 	info,
 	info_span,
 	trace,
+	warn,
 };
 
 use crate::{
+	builtin_tools::ToolRegistry,
+	channel_history::ChannelHistory,
 	context_extraction::InvocationContextSettings,
 	gcra::GCRAConfig,
 	handler::{
+		actions,
 		admin,
 		admin::get_blacklist_for_user,
 		completion::handle_completion,
+		history,
 		opt_out,
+		prompts,
 	},
 	mcp::McpManager,
 	mcp_config::McpConfig,
 	message_cache::MessageCache,
+	prompt_budget::PromptBudget,
 	rate_limit_config::{
 		PathRateLimits,
 		RateLimitConfig,
 	},
 };
 
+/// Maximum length of an answer of the model, which is kept free in its context window.
+const MAX_ANSWER_TOKENS: u32 = 2000;
+
 lazy_static! {
 	pub static ref APP_VERSION: semver::Version = semver::Version::parse(env!("CARGO_PKG_VERSION"))
 		.into_diagnostic()
@@ -121,6 +152,21 @@ struct EnvConfig {
 	#[envconfig(from = "TEMPLATE_DIR", default = "templates")]
 	template_dir: String,
 
+	#[envconfig(from = "TOKENIZER", default = "auto")]
+	tokenizer: String,
+
+	#[envconfig(from = "TOKENIZER_DIR", default = "tokenizers")]
+	tokenizer_dir: String,
+
+	#[envconfig(from = "CONTEXT_WINDOW")]
+	context_window: Option<usize>,
+
+	#[envconfig(from = "MESSAGE_CACHE_SIZE", default = "5000")]
+	message_cache_size: NonZeroUsize,
+
+	#[envconfig(from = "CHANNEL_HISTORY_SIZE", default = "100")]
+	channel_history_size: usize,
+
 	#[envconfig(from = "RATE_LIMIT_CONFIG", default = "rate_limits.toml")]
 	rate_limit_config: String,
 
@@ -132,6 +178,27 @@ struct EnvConfig {
 
 	#[envconfig(from = "COMPLETION_TIMEOUT", default = "60s")]
 	completion_timeout: ParsedDuration,
+
+	#[envconfig(from = "VISION", default = "false")]
+	vision: bool,
+
+	#[envconfig(from = "MAX_TOOL_ITERATIONS", default = "10")]
+	max_tool_iterations: usize,
+
+	#[envconfig(from = "MAX_TOOL_CALLS", default = "30")]
+	max_tool_calls: usize,
+
+	#[envconfig(from = "TOOL_STATUS", default = "false")]
+	tool_status: bool,
+
+	#[envconfig(from = "TOOL_FOOTER", default = "false")]
+	tool_footer: bool,
+
+	#[envconfig(from = "TOOL_AUDIT_RETENTION", default = "90d")]
+	tool_audit_retention: ParsedDuration,
+
+	#[envconfig(from = "MESSAGE_CACHE_RETENTION", default = "30d")]
+	message_cache_retention: ParsedDuration,
 }
 
 impl EnvConfig {
@@ -157,14 +224,67 @@ impl FromStr for ParsedDuration {
 	}
 }
 
+/// Adds a message received from the gateway to the channel history, if the bot watches its channel. Channels the bot
+/// doesn't answer in would only push the watched ones out of the history. Returns whether the channel is watched.
+async fn add_to_history(
+	whitelist: &Whitelist,
+	channel_history: &ChannelHistory,
+	message: &Message,
+	http: &impl CacheHttp,
+) -> bool {
+	let watched = whitelist.watches(message, http).await;
+	if watched {
+		channel_history.add(message);
+	}
+	watched
+}
+
+/// Number of channels the whitelist remembers whether they are watched.
+const MAX_WATCHED_CHANNELS: usize = 10_000;
+
 /// A whitelist of Discord snowflake IDs.
 ///
 /// Ids can be for channels, guilds or categories.
 struct Whitelist {
 	ids: HashSet<u64>,
+
+	/// Whether the bot watches a channel, remembered since it is checked for every message.
+	watched: Mutex<LruCache<ChannelId, bool>>,
 }
 
 impl Whitelist {
+	/// Checks if the bot watches the channel of a message, keeping its messages around as they arrive. These are the
+	/// whitelisted channels. Unlike [`Whitelist::contains`], the result is remembered per channel, and direct messages
+	/// are only watched if their channel is whitelisted directly, since private channels aren't cached by serenity.
+	pub async fn watches(&self, message: &Message, http: &impl CacheHttp) -> bool {
+		if self.ids.contains(&message.channel_id.get()) {
+			return true;
+		}
+		if message.guild_id.is_none() {
+			return false;
+		}
+		if let Some(watched) = self.watched.lock().await.get(&message.channel_id) {
+			return *watched;
+		}
+
+		// failures are not remembered, the next message tries again
+		match self.contains(message.channel_id, http).await {
+			Ok(watched) => {
+				self.watched.lock().await.put(message.channel_id, watched);
+				watched
+			},
+			Err(err) => {
+				debug!("Failed to check whitelist for channel {}: {:?}", message.channel_id, err);
+				false
+			},
+		}
+	}
+
+	/// Forgets which channels are watched, channels may have moved into or out of whitelisted categories.
+	pub async fn forget_watched(&self) {
+		self.watched.lock().await.clear();
+	}
+
 	/// Checks recursively if the given channel id is in the whitelist.
 	///
 	/// This will check the channel itself and all parent categories up to and including the guild.
@@ -227,20 +347,31 @@ impl FromStr for Whitelist {
 
 		Ok(Whitelist {
 			ids,
+			watched: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_WATCHED_CHANNELS).unwrap())),
 		})
 	}
 }
 
 struct AppState {
 	tera: Tera,
-	llm_client: Box<dyn LLMProvider + Send + Sync>,
+	llm_client: Arc<dyn LLMProvider + Send + Sync>,
+	model: String,
 	mcp_manager: McpManager,
-	db: DatabaseConnection,
-	path_rate_limits: Mutex<PathRateLimits>,
+	builtin_tools: ToolRegistry,
+	db: Arc<DatabaseConnection>,
+	path_rate_limits: Arc<Mutex<PathRateLimits>>,
 	context_settings: InvocationContextSettings,
+	prompt_budget: PromptBudget,
+	message_cache: MessageCache,
+	channel_history: Arc<ChannelHistory>,
 	whitelist: Whitelist,
 	opt_out_lockout: Duration,
 	completion_timeout: Duration,
+	vision: bool,
+	max_tool_iterations: usize,
+	max_tool_calls: usize,
+	tool_status: bool,
+	tool_footer: bool,
 }
 
 type Context<'a> = poise::Context<'a, AppState, Report>;
@@ -267,25 +398,26 @@ async fn main() -> Result<()> {
 	let llm_client = {
 		let backend = env_config.get_llm_backend()?;
 
-		let mut builder = LLMBuilder::new()
+		// tools are not registered here, since they can change at runtime, instead they are passed with every request
+		let builder = LLMBuilder::new()
 			.backend(backend)
 			.api_key(&env_config.api_key)
 			.model(&env_config.model)
-			.max_tokens(2000);
-
-		// llm crate forces us to add functions at buid time, so we create a short lived connection to get the functions
-		let mcp_connection = mcp_manager
-			.create_connection()
-			.await
-			.wrap_err("failed to create MCP connection for initial tool fetch")?;
+			.max_tokens(MAX_ANSWER_TOKENS);
 
-		for fun_builder in mcp_connection.get_llm_functions() {
-			builder = builder.function(fun_builder);
-		}
-
-		builder.build().into_diagnostic().wrap_err("failed to create LLM client")?
+		let llm_client: Box<dyn LLMProvider + Send + Sync> =
+			builder.build().into_diagnostic().wrap_err("failed to create LLM client")?;
+		Arc::from(llm_client)
 	};
 
+	let tokenizer = tokenizer::for_model(&env_config.model, &env_config.tokenizer, Path::new(&env_config.tokenizer_dir))?;
+	let prompt_budget = PromptBudget::new(
+		tokenizer.clone(),
+		&env_config.model,
+		env_config.context_window,
+		MAX_ANSWER_TOKENS as usize,
+	);
+
 	let db = {
 		let mut opt = ConnectOptions::new(env_config.database_url);
 		opt
@@ -306,9 +438,18 @@ async fn main() -> Result<()> {
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to run migrations")?;
-		db
+		Arc::new(db)
 	};
 
+	// audit trail and message cache would grow forever otherwise
+	tokio::spawn(tool_audit::purge_periodically(db.clone(), env_config.tool_audit_retention.0));
+	tokio::spawn(message_cache::purge_periodically(
+		db.clone(),
+		env_config.message_cache_retention.0,
+	));
+
+	let message_cache = MessageCache::new(db.clone(), env_config.message_cache_size);
+
 	let path_rate_limits: PathRateLimits = {
 		// start background worker to periodically persist rate limiter state
 		let rate_limit_config =
@@ -317,9 +458,14 @@ async fn main() -> Result<()> {
 		rate_limit_config.into()
 	};
 
-	let mut commands = vec![help(), opt_out::opt_out_dialogue()];
+	let mut commands = vec![help(), opt_out::opt_out_dialogue(), actions::actions(), history::history()];
 	admin::register_commands(&mut commands);
 
+	// a broken MCP server shouldn't keep the bot from starting, it just can't offer its prompts
+	if let Err(err) = prompts::register_commands(&mut commands, &mcp_manager).await {
+		warn!(error = ?err, "failed to register MCP prompts as commands");
+	}
+
 	// setup discord client with serenity
 	let poise_options = FrameworkOptions {
 		commands,
@@ -368,19 +514,34 @@ async fn main() -> Result<()> {
 				Ok(AppState {
 					tera,
 					llm_client,
+					model: env_config.model,
 					mcp_manager,
+					builtin_tools: ToolRegistry::default(),
 					db,
-					path_rate_limits: Mutex::new(path_rate_limits),
+					path_rate_limits: Arc::new(Mutex::new(path_rate_limits)),
 					context_settings: InvocationContextSettings {
+						tokenizer,
 						max_token_count: 2000,
 						max_channel_history: Some(10),
+						relevance_window: 50,
+						min_recent_history: 3,
 						reply_chain_depth: Some(4),
 						reply_chain_window: Some(5),
 						reply_chain_max_token_count: Some(1000),
+						max_linked_messages: Some(3),
+						max_thread_messages: Some(50),
 					},
+					prompt_budget,
+					message_cache,
+					channel_history: Arc::new(ChannelHistory::new(env_config.channel_history_size)),
 					whitelist: env_config.whitelist,
 					opt_out_lockout: env_config.opt_out_lockout.0,
 					completion_timeout: env_config.completion_timeout.0,
+					vision: env_config.vision,
+					max_tool_iterations: env_config.max_tool_iterations,
+					max_tool_calls: env_config.max_tool_calls,
+					tool_status: env_config.tool_status,
+					tool_footer: env_config.tool_footer,
 				})
 			})
 		})
@@ -418,9 +579,24 @@ async fn discord_listener<'a>(
 	app: &'a AppState,
 ) -> Result<()> {
 	match ev {
+		FullEvent::Ready {
+			..
+		} => {
+			// events missed while disconnected would leave gaps in the history
+			app.channel_history.clear();
+			app.whitelist.forget_watched().await;
+		},
+		FullEvent::ChannelUpdate {
+			..
+		} => {
+			app.whitelist.forget_watched().await;
+		},
 		FullEvent::Message {
 			new_message,
 		} => {
+			// kept even when dropping messages below, the history must not have gaps
+			let watched = add_to_history(&app.whitelist, &app.channel_history, new_message, &ctx).await;
+
 			// a large in-memory rate limit for all messages, to prevent overloading the bot
 			{
 				let mut global_rate_limit = GLOBAL_RATE_LIMIT.lock().await;
@@ -433,6 +609,14 @@ async fn discord_listener<'a>(
 				}
 			}
 
+			// messages of whitelisted channels can become part of the context later, keeping them saves fetching them again,
+			// messages from elsewhere are only cached once they are used for context
+			if watched {
+				if let Err(err) = app.message_cache.add(new_message).await {
+					debug!("Failed to cache message {}: {:?}", new_message.id, err);
+				}
+			}
+
 			let span = info_span!("message", author = %new_message.author.name, content = %new_message.content);
 
 			// drop messages from blacklisted users
@@ -473,16 +657,28 @@ async fn discord_listener<'a>(
 			}
 		},
 		FullEvent::MessageUpdate {
-			new: Some(new), ..
+			event, ..
 		} => {
-			let message_cache = MessageCache::new(&app.db);
-			message_cache.invalidate(&new.id).await?;
+			app.channel_history.update(event);
+			app.message_cache.invalidate(&event.id).await?;
 		},
 		FullEvent::MessageDelete {
-			deleted_message_id, ..
+			channel_id,
+			deleted_message_id,
+			..
 		} => {
-			let message_cache = MessageCache::new(&app.db);
-			message_cache.invalidate(deleted_message_id).await?;
+			app.channel_history.remove(*channel_id, &[*deleted_message_id]);
+			app.message_cache.invalidate(deleted_message_id).await?;
+		},
+		FullEvent::MessageDeleteBulk {
+			channel_id,
+			multiple_deleted_messages_ids,
+			..
+		} => {
+			app.channel_history.remove(*channel_id, multiple_deleted_messages_ids);
+			for message_id in multiple_deleted_messages_ids {
+				app.message_cache.invalidate(message_id).await?;
+			}
 		},
 		_ => {},
 	}
@@ -532,6 +728,13 @@ pub async fn user_from_db_or_create<C: ConnectionTrait>(db: &C, user: &User) ->
 #[cfg(test)]
 mod tests {
 	use ctor::ctor;
+	use poise::serenity_prelude::{
+		GuildId,
+		Http,
+		MessageId,
+	};
+
+	use super::*;
 
 	#[ctor]
 	fn init_tests() {
@@ -540,4 +743,42 @@ mod tests {
 
 		tracing_subscriber::fmt::init();
 	}
+
+	fn message(channel_id: u64, guild_id: Option<u64>) -> Message {
+		let mut message = Message::default();
+		message.id = MessageId::new(1);
+		message.channel_id = ChannelId::new(channel_id);
+		message.guild_id = guild_id.map(GuildId::new);
+		message
+	}
+
+	#[tokio::test]
+	async fn test_whitelist_watches() {
+		// none of these need a request, an empty token would fail it
+		let http = Http::new("");
+		let whitelist = Whitelist::from_str("1,2").unwrap();
+
+		assert!(whitelist.watches(&message(1, Some(10)), &http).await);
+		assert!(whitelist.watches(&message(2, None), &http).await);
+		assert!(!whitelist.watches(&message(3, None), &http).await);
+
+		whitelist.watched.lock().await.put(ChannelId::new(4), false);
+		assert!(!whitelist.watches(&message(4, Some(10)), &http).await);
+
+		whitelist.forget_watched().await;
+		assert!(whitelist.watched.lock().await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_add_to_history() {
+		let http = Http::new("");
+		let whitelist = Whitelist::from_str("1").unwrap();
+		let history = ChannelHistory::new(10);
+
+		assert!(add_to_history(&whitelist, &history, &message(1, Some(10)), &http).await);
+		assert!(history.all_before(ChannelId::new(1), MessageId::new(1)).is_some());
+
+		assert!(!add_to_history(&whitelist, &history, &message(3, None), &http).await);
+		assert!(history.all_before(ChannelId::new(3), MessageId::new(1)).is_none());
+	}
 }
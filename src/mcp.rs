@@ -1,17 +1,37 @@
 use std::{
 	collections::HashMap,
+	future::Future,
 	ops::Deref,
 	process::Stdio,
 	str::FromStr,
+	sync::{
+		Arc,
+		atomic::{
+			AtomicBool,
+			AtomicU64,
+			Ordering,
+		},
+	},
 };
 
+use base64::{
+	Engine,
+	prelude::BASE64_STANDARD,
+};
+use futures::StreamExt;
 use llm::{
+	FunctionCall,
 	ToolCall,
-	builder::FunctionBuilder,
+	chat::{
+		ChatMessage,
+		FunctionTool,
+		Tool,
+	},
 };
 use log::{
 	debug,
 	trace,
+	warn,
 };
 use miette::{
 	IntoDiagnostic,
@@ -20,19 +40,52 @@ use miette::{
 };
 use reqwest::Client;
 use rmcp::{
+	ClientHandler,
+	ErrorData,
 	RoleClient,
 	ServiceError,
 	ServiceExt,
+	handler::client::progress::ProgressDispatcher,
 	model::{
+		CallToolRequest,
 		CallToolRequestParam,
 		CallToolResult,
+		ClientCapabilities,
 		ClientInfo,
+		ClientRequest,
 		Content,
+		CreateElicitationRequestParam,
+		CreateElicitationResult,
+		CreateMessageRequestMethod,
+		CreateMessageRequestParam,
+		CreateMessageResult,
+		ElicitationAction,
+		ElicitationCapability,
+		GetPromptRequestParam,
 		Implementation,
-		InitializeRequestParam,
+		JsonObject,
 		ListToolsResult,
+		Meta,
+		NumberOrString,
+		ProgressNotificationParam,
+		ProgressToken,
+		Prompt,
+		PromptMessage,
+		PromptMessageContent,
+		PromptMessageRole,
+		RawContent,
+		ReadResourceRequestParam,
+		Resource,
+		ResourceContents,
+		Role,
+		ServerResult,
+	},
+	service::{
+		NotificationContext,
+		PeerRequestOptions,
+		RequestContext,
+		RunningService,
 	},
-	service::RunningService,
 	transport::{
 		ConfigureCommandExt,
 		SseClientTransport,
@@ -42,15 +95,47 @@ use rmcp::{
 		streamable_http_client::StreamableHttpClientTransportConfig,
 	},
 };
-use serde_json::Value;
-use tokio::process::Command;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+use serde_json::{
+	Value,
+	json,
+};
+use tokio::{
+	process::Command,
+	sync::{
+		RwLock,
+		Semaphore,
+	},
+};
 use tracing::info;
 
-use crate::mcp_config::{
-	McpConfig,
-	McpServerConfig,
+use crate::{
+	mcp_config::{
+		InvocationContextMode,
+		McpConfig,
+		McpServerConfig,
+		McpServerSettings,
+		ToolSettings,
+	},
+	mcp_elicitation::{
+		CallDeadlines,
+		Elicitor,
+	},
+	mcp_sampling::{
+		Sampler,
+		SamplingSession,
+	},
 };
 
+/// Name of the tool letting the model read resources listed by servers. Tools of servers take precedence.
+const READ_RESOURCE_TOOL: &str = "read_resource";
+
+/// Maximum number of resources offered to the model, so the tool description stays reasonably small.
+const MAX_LISTED_RESOURCES: usize = 50;
+
 /// Convert a ServiceError into a descriptive error string
 /// Extracts detailed information especially from the McpError variant
 fn service_error_to_description(err: &ServiceError) -> String {
@@ -103,28 +188,424 @@ fn service_error_to_description(err: &ServiceError) -> String {
 	}
 }
 
-/// Extract text content from MCP Content array
-/// Concatenates all text content found in the array
-fn extract_text_from_content(content: &[Content]) -> String {
-	let mut result = String::new();
+/// Details about the Discord invocation a connection is made for, which can be passed on to servers.
+/// Allows servers to authorize and personalize tool calls.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvocationContext {
+	/// Snowflakes are passed as strings, since they exceed the integer precision of many JSON parsers.
+	pub user_id: String,
+	pub guild_id: Option<String>,
+	pub channel_id: String,
+
+	/// Name of the invoking user. Users who opted out can't invoke the bot, so passing it on is always fine.
+	pub display_name: String,
+}
+
+impl InvocationContext {
+	/// Key of the invocation context in the `_meta` field of tool calls.
+	const META_KEY: &'static str = "discord";
+
+	/// Adds the invocation context to the `_meta` field of a request.
+	fn add_to_meta(&self, meta: &mut Meta) {
+		let value = serde_json::to_value(self).expect("invocation context is always serializable");
+		meta.0.insert(Self::META_KEY.to_string(), value);
+	}
+
+	/// HTTP headers carrying the invocation context.
+	/// The display name is percent-encoded, since header values are limited to ASCII.
+	fn headers(&self) -> HashMap<String, String> {
+		let mut headers = HashMap::new();
+		headers.insert("X-Discord-User-Id".to_string(), self.user_id.clone());
+		headers.insert("X-Discord-Channel-Id".to_string(), self.channel_id.clone());
+		if let Some(guild_id) = &self.guild_id {
+			headers.insert("X-Discord-Guild-Id".to_string(), guild_id.clone());
+		}
+		headers.insert(
+			"X-Discord-Display-Name".to_string(),
+			percent_encode_header_value(&self.display_name),
+		);
+
+		headers
+	}
+}
+
+/// Percent-encodes everything which isn't printable ASCII, as well as the percent sign itself.
+fn percent_encode_header_value(value: &str) -> String {
+	let mut encoded = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		if (byte.is_ascii_graphic() || byte == b' ') && byte != b'%' {
+			encoded.push(byte as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", byte));
+		}
+	}
+
+	encoded
+}
+
+/// Services of the bot offered to servers, which are only available while serving a Discord invocation.
+#[derive(Clone, Default)]
+pub struct ClientServices {
+	/// Runs completions for servers with sampling enabled.
+	pub sampler: Option<Arc<Sampler>>,
+
+	/// Asks the invoking user for input requested by servers.
+	pub elicitor: Option<Arc<Elicitor>>,
+}
+
+/// Binary media returned by a tool, which can't be passed to the model as text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolMedia {
+	pub mime_type: String,
+	pub data: Vec<u8>,
+}
+
+/// A file returned by a tool, which is meant to be shown to the user instead of the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolAttachment {
+	/// Sanitized file name, safe to use as Discord attachment name.
+	pub filename: String,
+	pub media: ToolMedia,
+}
+
+/// Result of a successful tool call.
+#[derive(Debug)]
+pub struct ToolCallOutput {
+	/// The value that is passed back to the model.
+	pub value: Value,
+
+	/// Images returned by the tool, referenced by placeholders in `value`.
+	pub images: Vec<ToolMedia>,
+
+	/// Files the tool marked as intended for the user, referenced by placeholders in `value`.
+	pub attachments: Vec<ToolAttachment>,
+
+	/// Web links found in the result, which the answer may be based on.
+	pub sources: Vec<String>,
+}
+
+/// Content of an MCP tool result, converted into something the model can process.
+#[derive(Debug, Default)]
+struct ExtractedContent {
+	/// All text content, with placeholders for content that can't be represented as text.
+	text: String,
+
+	/// Images found in the content, numbered in the order of their placeholders.
+	images: Vec<ToolMedia>,
+
+	/// Files with the user as audience, which should be forwarded to the user.
+	attachments: Vec<ToolAttachment>,
+
+	/// Web links of resources referenced by the content.
+	sources: Vec<String>,
+}
+
+impl ExtractedContent {
+	/// Decodes binary content and returns the placeholder referencing it.
+	///
+	/// Images are made available to the model, user facing content is collected as attachment. Everything else is only
+	/// summarized by `summary`, which is also used if the content can't be decoded.
+	fn add_media(&mut self, mime_type: &str, data: &str, uri: Option<&str>, user_facing: bool, summary: String) -> String {
+		let is_image = mime_type.starts_with("image/");
+		if !is_image && !user_facing {
+			return summary;
+		}
+
+		let data = match BASE64_STANDARD.decode(data) {
+			Ok(data) => data,
+			Err(err) => {
+				debug!("Failed to decode binary content returned by tool: {}", err);
+				return format!("[{}: {}, malformed data]", if is_image { "image" } else { "file" }, mime_type);
+			},
+		};
+
+		let media = ToolMedia {
+			mime_type: mime_type.to_string(),
+			data,
+		};
+
+		let mut placeholder = if is_image {
+			self.images.push(media.clone());
+			format!(
+				"[image #{}: {}, {}",
+				self.images.len(),
+				mime_type,
+				format_size(media.data.len())
+			)
+		} else {
+			format!("[file: {}, {}", mime_type, format_size(media.data.len()))
+		};
+
+		if user_facing {
+			let filename = attachment_filename(uri, mime_type, self.attachments.len() + 1);
+			placeholder.push_str(&format!(", file {} for the user", filename));
+			self.attachments.push(ToolAttachment {
+				filename,
+				media,
+			});
+		}
+
+		placeholder.push(']');
+		placeholder
+	}
+}
+
+/// Content annotated with the user as audience is meant to be shown to the user directly.
+fn is_user_facing(content: &Content) -> bool {
+	content
+		.annotations
+		.as_ref()
+		.and_then(|annotations| annotations.audience.as_ref())
+		.is_some_and(|audience| audience.contains(&Role::User))
+}
+
+/// Derives a file name for an attachment from its uri, falling back to a generic name.
+/// The result only contains characters which are safe to use in a Discord attachment name.
+fn attachment_filename(uri: Option<&str>, mime_type: &str, number: usize) -> String {
+	// last path segment of uri, without query or fragment
+	let name = uri
+		.map(|uri| uri.split(['?', '#']).next().unwrap_or_default())
+		.and_then(|path| path.rsplit('/').next())
+		.unwrap_or_default();
+
+	let mut name = name
+		.chars()
+		.map(|c| {
+			if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+				c
+			} else {
+				'_'
+			}
+		})
+		.collect::<String>()
+		.trim_start_matches('.')
+		.to_string();
+	name.truncate(64);
+
+	if name.is_empty() {
+		name = format!("attachment-{}", number);
+	}
+
+	// discord uses the extension to decide how to display a file
+	if !name.contains('.') {
+		let extension = mime_type
+			.split(['/', '+', ';'])
+			.nth(1)
+			.filter(|subtype| !subtype.is_empty() && subtype.chars().all(|c| c.is_ascii_alphanumeric()))
+			.map(|subtype| if subtype == "jpeg" { "jpg" } else { subtype })
+			.unwrap_or("bin");
+		name = format!("{}.{}", name, extension);
+	}
+
+	name
+}
+
+/// Extract content from MCP Content array
+/// Concatenates all text content found in the array, embedded text resources are inlined and everything else is
+/// replaced by a placeholder describing it
+fn extract_content(content: &[Content]) -> ExtractedContent {
+	let mut result = ExtractedContent::default();
 
 	for item in content {
+		let user_facing = is_user_facing(item);
+
 		// The Content type is an Annotated<RawContent>, we need to access the inner value
-		match item.deref() {
-			rmcp::model::RawContent::Text(text_content) => {
-				if !result.is_empty() {
-					result.push('\n');
+		let text = match item.deref() {
+			RawContent::Text(text_content) => text_content.text.clone(),
+			RawContent::Image(image) => {
+				let summary = format!("[image: {}]", image.mime_type);
+				result.add_media(&image.mime_type, &image.data, None, user_facing, summary)
+			},
+			RawContent::Audio(audio) => {
+				let summary = format!(
+					"[audio: {}, {}]",
+					audio.mime_type,
+					format_size(base64_decoded_len(&audio.data))
+				);
+				result.add_media(&audio.mime_type, &audio.data, None, user_facing, summary)
+			},
+			RawContent::Resource(resource) => match &resource.resource {
+				ResourceContents::TextResourceContents {
+					uri,
+					text,
+					..
+				} => {
+					if is_web_url(uri) {
+						result.sources.push(uri.clone());
+					}
+					format!("[resource: {}]\n{}", uri, text)
+				},
+				ResourceContents::BlobResourceContents {
+					uri,
+					mime_type,
+					blob,
+					..
+				} => {
+					let mime_type = mime_type.as_deref().unwrap_or("application/octet-stream");
+					let summary = format!(
+						"[binary resource: {}, {}, {}]",
+						uri,
+						mime_type,
+						format_size(base64_decoded_len(blob))
+					);
+					result.add_media(mime_type, blob, Some(uri), user_facing, summary)
+				},
+			},
+			RawContent::ResourceLink(link) => {
+				if is_web_url(&link.uri) {
+					result.sources.push(link.uri.clone());
+				}
+
+				let mut facts = vec![link.uri.clone()];
+				if let Some(mime_type) = &link.mime_type {
+					facts.push(mime_type.clone());
 				}
-				result.push_str(&text_content.text);
+				if let Some(size) = link.size {
+					facts.push(format_size(size as usize));
+				}
+
+				let mut text = format!("[resource link: {} ({})]", link.name, facts.join(", "));
+				if let Some(description) = &link.description {
+					text.push_str(&format!(" {}", description));
+				}
+				text
 			},
-			// these should not occur for now
-			_ => unimplemented!("Extracting non-text content is not implemented"),
+		};
+
+		if !result.text.is_empty() {
+			result.text.push('\n');
 		}
+		result.text.push_str(&text);
 	}
 
 	result
 }
 
+/// Concatenates the text of resource contents, binary contents are left out.
+fn resource_text(contents: &[ResourceContents]) -> String {
+	contents
+		.iter()
+		.filter_map(|content| match content {
+			ResourceContents::TextResourceContents {
+				text, ..
+			} => Some(text.as_str()),
+			ResourceContents::BlobResourceContents {
+				..
+			} => None,
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// Converts a message of a prompt into a message for the model. Non-text content is described in text.
+fn prompt_message_to_chat(message: PromptMessage) -> ChatMessage {
+	let content = match message.content {
+		PromptMessageContent::Text {
+			text,
+		} => Content::text(text),
+		PromptMessageContent::Image {
+			image,
+		} => Content::new(RawContent::Image(image.raw), image.annotations),
+		PromptMessageContent::Resource {
+			resource,
+		} => Content::new(RawContent::Resource(resource.raw), resource.annotations),
+		PromptMessageContent::ResourceLink {
+			link,
+		} => Content::resource_link(link.raw),
+	};
+	let text = extract_content(&[content]).text;
+
+	let builder = match message.role {
+		PromptMessageRole::User => ChatMessage::user(),
+		PromptMessageRole::Assistant => ChatMessage::assistant(),
+	};
+	builder.content(text).build()
+}
+
+/// Whether the given string is a link to a website, which can be shown to the user as source.
+fn is_web_url(text: &str) -> bool {
+	let rest = text
+		.strip_prefix("https://")
+		.or_else(|| text.strip_prefix("http://"))
+		.unwrap_or_default();
+
+	!rest.is_empty() && !rest.contains(char::is_whitespace)
+}
+
+/// Collects all web links found in a structured tool result.
+fn extract_sources(value: &Value, sources: &mut Vec<String>) {
+	match value {
+		Value::String(text) if is_web_url(text) => sources.push(text.clone()),
+		Value::Array(values) => values.iter().for_each(|value| extract_sources(value, sources)),
+		Value::Object(map) => map.values().for_each(|value| extract_sources(value, sources)),
+		_ => {},
+	}
+}
+
+/// Calculates the size of base64 encoded data without decoding it.
+fn base64_decoded_len(data: &str) -> usize {
+	data.trim_end_matches('=').len() * 3 / 4
+}
+
+/// Turns a progress notification into a short text for the user.
+/// Returns `None` if the notification carries nothing a user could make sense of.
+fn describe_progress(progress: &ProgressNotificationParam) -> Option<String> {
+	if let Some(message) = progress.message.as_deref().map(str::trim).filter(|m| !m.is_empty()) {
+		return Some(message.to_string());
+	}
+
+	// without a total, the raw progress value has no meaning to the user
+	progress
+		.total
+		.filter(|total| *total > 0.0)
+		.map(|total| format!("{:.0}%", (progress.progress / total * 100.0).clamp(0.0, 100.0)))
+}
+
+/// Limits the size of a tool result to `max_size` characters.
+/// Results within the limit are returned unchanged, larger results are converted to text and cut off with a marker
+/// telling the model how much was removed.
+pub fn truncate_result(value: Value, max_size: usize) -> Value {
+	let text = match value {
+		Value::String(text) => text,
+		other => {
+			// structured results within the limit are passed back as they are
+			let text = other.to_string();
+			if text.chars().count() <= max_size {
+				return other;
+			}
+			text
+		},
+	};
+
+	let total = text.chars().count();
+	if total <= max_size {
+		return Value::String(text);
+	}
+
+	let mut truncated = text.chars().take(max_size).collect::<String>();
+	truncated.push_str(&format!(
+		"\n[truncated, {} of {} characters omitted]",
+		total - max_size,
+		total
+	));
+	Value::String(truncated)
+}
+
+/// Formats a size in bytes as human readable string.
+fn format_size(bytes: usize) -> String {
+	const KIB: f64 = 1024.0;
+	const MIB: f64 = KIB * 1024.0;
+
+	let size = bytes as f64;
+	if size >= MIB {
+		format!("{:.1} MiB", size / MIB)
+	} else if size >= KIB {
+		format!("{:.1} KiB", size / KIB)
+	} else {
+		format!("{} B", bytes)
+	}
+}
+
 /// Create a reqwest HTTP client with the provided headers
 /// Common functionality for both SSE and StreamableHttp transports
 fn create_http_client_with_headers(headers: &HashMap<String, String>) -> Result<Client> {
@@ -149,23 +630,158 @@ fn create_http_client_with_headers(headers: &HashMap<String, String>) -> Result<
 
 /// Common functionality for initializing an MCP client and fetching tools
 async fn initialize_mcp_client(
-	client: RunningService<RoleClient, InitializeRequestParam>,
+	client: RunningService<RoleClient, McpClientHandler>,
 	server_name: &str,
+	config: &McpConfig,
 ) -> Result<McpClientWithTools> {
-	McpClientWithTools::new(client)
+	let settings = config.settings.get(server_name);
+	let max_concurrent_calls = settings
+		.map(|settings| settings.max_concurrent_calls())
+		.unwrap_or_else(|| McpServerSettings::default().max_concurrent_calls());
+
+	let mut client_with_tools = McpClientWithTools::new(client, max_concurrent_calls)
 		.await
-		.wrap_err(format!("Failed to fetch tools from MCP server '{}'", server_name))
+		.wrap_err(format!("Failed to fetch tools from MCP server '{}'", server_name))?;
+
+	// resources are only listed for servers allowed to share them with the model
+	let offers_resources = client_with_tools
+		.client
+		.peer_info()
+		.is_some_and(|info| info.capabilities.resources.is_some());
+	if offers_resources && settings.is_some_and(|settings| settings.resources) {
+		client_with_tools.resources = client_with_tools
+			.client
+			.list_all_resources()
+			.await
+			.into_diagnostic()
+			.wrap_err(format!("Failed to list resources of MCP server '{}'", server_name))?;
+	}
+
+	Ok(client_with_tools)
+}
+
+/// Client side handler for a single MCP server.
+/// Introduces us to the server and keeps track of notifications the server sends during a session.
+#[derive(Clone)]
+pub struct McpClientHandler {
+	info: ClientInfo,
+
+	/// Set by the server via `notifications/tools/list_changed`, cleared once the tool list has been refetched.
+	tools_changed: Arc<AtomicBool>,
+
+	/// Forwards progress notifications to whoever is waiting for the respective tool call.
+	progress: ProgressDispatcher,
+
+	/// Only set if sampling is enabled for the server and the connection serves an invocation.
+	sampling: Option<Arc<SamplingSession>>,
+
+	/// Only set if the connection serves an invocation, so there is a user to ask.
+	elicitor: Option<Arc<Elicitor>>,
+
+	/// Deadlines of the running calls, elicitations can't outlast them.
+	call_deadlines: CallDeadlines,
+
+	server_name: String,
+}
+
+impl McpClientHandler {
+	/// Create a handler for the given server, advertising only the capabilities the server is allowed to use.
+	fn new(mut info: ClientInfo, server_name: &str, settings: Option<&McpServerSettings>, services: &ClientServices) -> Self {
+		let sampling = match (settings.and_then(|settings| settings.sampling.as_ref()), &services.sampler) {
+			(Some(sampling_settings), Some(sampler)) => Some(Arc::new(SamplingSession::new(
+				sampler.clone(),
+				server_name.to_string(),
+				sampling_settings.clone(),
+			))),
+			_ => None,
+		};
+
+		if sampling.is_some() {
+			info.capabilities.sampling = Some(Default::default());
+		}
+
+		if services.elicitor.is_some() {
+			info.capabilities.elicitation = Some(ElicitationCapability {
+				schema_validation: Some(true),
+			});
+		}
+
+		Self {
+			info,
+			tools_changed: Arc::new(AtomicBool::new(false)),
+			progress: ProgressDispatcher::new(),
+			sampling,
+			elicitor: services.elicitor.clone(),
+			call_deadlines: CallDeadlines::default(),
+			server_name: server_name.to_string(),
+		}
+	}
+}
+
+impl ClientHandler for McpClientHandler {
+	fn on_progress(
+		&self,
+		params: ProgressNotificationParam,
+		_context: NotificationContext<RoleClient>,
+	) -> impl Future<Output = ()> + Send + '_ {
+		trace!("MCP server sent progress: {:?}", params);
+		self.progress.handle_notification(params)
+	}
+
+	fn on_tool_list_changed(&self, _context: NotificationContext<RoleClient>) -> impl Future<Output = ()> + Send + '_ {
+		debug!("MCP server notified us about changed tool list");
+		self.tools_changed.store(true, Ordering::Release);
+		std::future::ready(())
+	}
+
+	async fn create_message(
+		&self,
+		params: CreateMessageRequestParam,
+		_context: RequestContext<RoleClient>,
+	) -> Result<CreateMessageResult, ErrorData> {
+		match &self.sampling {
+			Some(sampling) => sampling.create_message(params).await,
+			None => Err(ErrorData::method_not_found::<CreateMessageRequestMethod>()),
+		}
+	}
+
+	async fn create_elicitation(
+		&self,
+		request: CreateElicitationRequestParam,
+		_context: RequestContext<RoleClient>,
+	) -> Result<CreateElicitationResult, ErrorData> {
+		match &self.elicitor {
+			Some(elicitor) => elicitor.elicit(&self.server_name, request, &self.call_deadlines).await,
+			None => Ok(CreateElicitationResult {
+				action: ElicitationAction::Decline,
+				content: None,
+			}),
+		}
+	}
+
+	fn get_info(&self) -> ClientInfo {
+		self.info.clone()
+	}
 }
 
 /// Struct that combines an MCP client with its cached tools
 pub struct McpClientWithTools {
-	client: RunningService<RoleClient, InitializeRequestParam>,
+	client: RunningService<RoleClient, McpClientHandler>,
 	tools: ListToolsResult,
+
+	/// Limits the number of concurrent tool calls on this server.
+	call_permits: Semaphore,
+
+	/// Used to create unique progress tokens for tool calls.
+	next_progress_token: AtomicU64,
+
+	/// Resources the model can read, only listed if enabled for the server.
+	resources: Vec<Resource>,
 }
 
 impl McpClientWithTools {
 	/// Create a new McpClientWithTools by fetching tools from the client
-	async fn new(client: RunningService<RoleClient, InitializeRequestParam>) -> Result<Self> {
+	async fn new(client: RunningService<RoleClient, McpClientHandler>, max_concurrent_calls: usize) -> Result<Self> {
 		let tools = client
 			.list_tools(None)
 			.await
@@ -175,11 +791,14 @@ impl McpClientWithTools {
 		Ok(McpClientWithTools {
 			client,
 			tools,
+			call_permits: Semaphore::new(max_concurrent_calls),
+			next_progress_token: AtomicU64::new(0),
+			resources: Vec::new(),
 		})
 	}
 
 	/// Get a reference to the client
-	pub fn client(&self) -> &RunningService<RoleClient, InitializeRequestParam> {
+	pub fn client(&self) -> &RunningService<RoleClient, McpClientHandler> {
 		&self.client
 	}
 
@@ -187,29 +806,60 @@ impl McpClientWithTools {
 	pub fn tools(&self) -> &ListToolsResult {
 		&self.tools
 	}
+
+	/// Create a progress token which is unique for this server.
+	/// Uses strings, so they never clash with the numeric tokens rmcp attaches to every request on its own.
+	fn progress_token(&self) -> ProgressToken {
+		let id = self.next_progress_token.fetch_add(1, Ordering::Relaxed);
+		ProgressToken(NumberOrString::String(format!("tool-call-{}", id).into()))
+	}
+
+	/// Refetch tools from the server if it told us that its tool list has changed.
+	/// Returns `true` if the tool list was refetched.
+	async fn refresh_tools(&mut self) -> Result<bool> {
+		// clear flag before fetching, so we don't miss notifications arriving while we are fetching
+		if !self.client.service().tools_changed.swap(false, Ordering::AcqRel) {
+			return Ok(false);
+		}
+
+		self.tools = self
+			.client
+			.list_tools(None)
+			.await
+			.into_diagnostic()
+			.wrap_err("Failed to refetch tools from MCP client")?;
+
+		Ok(true)
+	}
 }
 
 /// RAII guard that maintains MCP connections during an LLM session.
 pub struct McpConnection {
 	clients: HashMap<String, McpClientWithTools>,
+	settings: HashMap<String, McpServerSettings>,
+
+	/// Passed on to servers which are configured to receive it.
+	invocation_context: Option<InvocationContext>,
 }
 
 /// Factory for creating MCP connections from configuration.
 /// Holds the configuration but doesn't maintain persistent connections.
+/// The configuration can be swapped at runtime, affecting all connections created afterwards.
 pub struct McpManager {
-	config: McpConfig,
+	config: RwLock<McpConfig>,
 }
 
 impl McpConnection {
 	/// Create a new MCP connection session by connecting to all configured servers
 	/// This establishes fresh connections for this session
-	pub async fn new(config: &McpConfig) -> Result<Self> {
+	/// The invocation context and services are only offered to servers configured to receive them.
+	pub async fn new(config: &McpConfig, invocation_context: Option<InvocationContext>, services: ClientServices) -> Result<Self> {
 		let mut clients = HashMap::new();
 
 		// init client info which we need to pass to all servers to introduce ourselves
 		let client_info = ClientInfo {
 			protocol_version: Default::default(),
-			capabilities: Default::default(),
+			capabilities: ClientCapabilities::default(),
 			client_info: Implementation {
 				name: env!("CARGO_PKG_NAME").to_string(),
 				version: env!("CARGO_PKG_VERSION").to_string(),
@@ -217,6 +867,14 @@ impl McpConnection {
 		};
 
 		for (server_name, server_config) in &config.servers {
+			// headers are fixed for the lifetime of the connection, which is fine, since it only serves a single invocation
+			let context_headers = match (&invocation_context, config.settings.get(server_name)) {
+				(Some(context), Some(settings)) if settings.invocation_context == InvocationContextMode::MetaAndHeaders => {
+					context.headers()
+				},
+				_ => HashMap::new(),
+			};
+
 			match server_config {
 				McpServerConfig::Http {
 					url,
@@ -224,7 +882,8 @@ impl McpConnection {
 				} => {
 					info!("Connecting to HTTP MCP server '{}' at {}", server_name, url);
 
-					let http_client = create_http_client_with_headers(headers)
+					let headers = headers.clone().into_iter().chain(context_headers).collect();
+					let http_client = create_http_client_with_headers(&headers)
 						.wrap_err(format!("Failed to build reqwest client for MCP server '{}'", server_name))?;
 
 					let transport_config = StreamableHttpClientTransportConfig {
@@ -233,14 +892,13 @@ impl McpConnection {
 					};
 
 					let transport = StreamableHttpClientTransport::with_client(http_client, transport_config);
-					let client = client_info
-						.clone()
+					let client = McpClientHandler::new(client_info.clone(), server_name, config.settings.get(server_name), &services)
 						.serve(transport)
 						.await
 						.into_diagnostic()
 						.wrap_err(format!("Failed to initialize MCP client for server '{}'", server_name))?;
 
-					let client_with_tools = initialize_mcp_client(client, server_name).await?;
+					let client_with_tools = initialize_mcp_client(client, server_name, config).await?;
 					clients.insert(server_name.clone(), client_with_tools);
 				},
 				McpServerConfig::Sse {
@@ -249,7 +907,8 @@ impl McpConnection {
 				} => {
 					info!("Connecting to SSE MCP server '{}' at {}", server_name, url);
 
-					let http_client = create_http_client_with_headers(headers)
+					let headers = headers.clone().into_iter().chain(context_headers).collect();
+					let http_client = create_http_client_with_headers(&headers)
 						.wrap_err(format!("Failed to build reqwest client for MCP server '{}'", server_name))?;
 
 					let transport_config = SseClientConfig {
@@ -262,14 +921,13 @@ impl McpConnection {
 						.into_diagnostic()
 						.wrap_err(format!("Failed to start SSE transport for MCP server '{}'", server_name))?;
 
-					let client = client_info
-						.clone()
+					let client = McpClientHandler::new(client_info.clone(), server_name, config.settings.get(server_name), &services)
 						.serve(transport)
 						.await
 						.into_diagnostic()
 						.wrap_err(format!("Failed to initialize MCP client for server '{}'", server_name))?;
 
-					let client_with_tools = initialize_mcp_client(client, server_name).await?;
+					let client_with_tools = initialize_mcp_client(client, server_name, config).await?;
 					clients.insert(server_name.clone(), client_with_tools);
 				},
 				McpServerConfig::Stdio {
@@ -296,14 +954,13 @@ impl McpConnection {
 						.into_diagnostic()
 						.wrap_err(format!("Failed to start child process for MCP server '{}'", server_name))?;
 
-					let client = client_info
-						.clone()
+					let client = McpClientHandler::new(client_info.clone(), server_name, config.settings.get(server_name), &services)
 						.serve(transport)
 						.await
 						.into_diagnostic()
 						.wrap_err(format!("Failed to initialize MCP client for server '{}'", server_name))?;
 
-					let client_with_tools = initialize_mcp_client(client, server_name).await?;
+					let client_with_tools = initialize_mcp_client(client, server_name, config).await?;
 					clients.insert(server_name.clone(), client_with_tools);
 				},
 			}
@@ -311,6 +968,8 @@ impl McpConnection {
 
 		let connection = McpConnection {
 			clients,
+			settings: config.settings.clone(),
+			invocation_context,
 		};
 		connection.dump_available_clients();
 		Ok(connection)
@@ -341,9 +1000,29 @@ impl McpConnection {
 		}
 	}
 
+	/// Refetch the tool list of all servers which notified us about changes since the last refresh.
+	/// Should be called before tools are passed to the LLM, so it never sees stale tools.
+	pub async fn refresh_tools(&mut self) -> Result<()> {
+		for (server_name, client_with_tools) in &mut self.clients {
+			if client_with_tools
+				.refresh_tools()
+				.await
+				.wrap_err(format!("Failed to refresh tools of MCP server '{}'", server_name))?
+			{
+				debug!(
+					"Refreshed tool list of server '{}', now providing {} tools",
+					server_name,
+					client_with_tools.tools().tools.len()
+				);
+			}
+		}
+
+		Ok(())
+	}
+
 	/// Get all tools from all connected MCP clients and convert them to llm::chat::Tool
-	/// This can be used to register all tools with an LLM that supports function calling
-	pub fn get_llm_functions(&self) -> Box<[FunctionBuilder]> {
+	/// The returned list reflects the current state of the servers and is passed to the LLM with every request.
+	pub fn get_llm_tools(&self) -> Vec<Tool> {
 		let mut all_tools = Vec::new();
 
 		for client_with_tools in self.clients.values() {
@@ -352,30 +1031,257 @@ impl McpConnection {
 			// Convert rmcp::model::Tool to llm::chat::Tool
 			for tool in tools {
 				let json_obj = tool.input_schema.as_ref().clone();
-				let mut function = FunctionBuilder::new(tool.name.as_ref()).json_schema(Value::Object(json_obj));
 
-				if let Some(description) = &tool.description {
-					function = function.description(description.as_ref());
+				all_tools.push(Tool {
+					tool_type: "function".to_string(),
+					function: FunctionTool {
+						name: tool.name.to_string(),
+						description: tool.description.as_deref().unwrap_or_default().to_string(),
+						parameters: Value::Object(json_obj),
+					},
+				});
+			}
+		}
+
+		if let Some(tool) = self.read_resource_tool() {
+			all_tools.push(tool);
+		}
+
+		all_tools
+	}
+
+	/// Create the tool letting the model read listed resources, if any server lists resources.
+	fn read_resource_tool(&self) -> Option<Tool> {
+		// tools of servers take precedence, the model can't tell them apart otherwise
+		if self.find_server(READ_RESOURCE_TOOL).is_some() {
+			return None;
+		}
+
+		let resources = self
+			.clients
+			.values()
+			.flat_map(|client| &client.resources)
+			.take(MAX_LISTED_RESOURCES)
+			.collect::<Vec<_>>();
+		if resources.is_empty() {
+			return None;
+		}
+
+		let mut description = "Read one of the following resources:".to_string();
+		for resource in &resources {
+			description.push_str(&format!("\n- {}: {}", resource.uri, resource.name));
+			if let Some(resource_description) = &resource.description {
+				description.push_str(&format!(" ({})", resource_description));
+			}
+		}
+
+		let uris = resources.iter().map(|resource| resource.uri.as_str()).collect::<Vec<_>>();
+		Some(Tool {
+			tool_type: "function".to_string(),
+			function: FunctionTool {
+				name: READ_RESOURCE_TOOL.to_string(),
+				description,
+				parameters: json!({
+					"type": "object",
+					"properties": {
+						"uri": {
+							"type": "string",
+							"description": "URI of the resource to read",
+							"enum": uris,
+						},
+					},
+					"required": ["uri"],
+				}),
+			},
+		})
+	}
+
+	/// Read a resource listed by one of the servers, on behalf of the model.
+	async fn read_listed_resource(&self, call: &FunctionCall) -> Result<ToolCallOutput> {
+		#[derive(Deserialize)]
+		struct Arguments {
+			uri: String,
+		}
+
+		let arguments: Arguments = serde_json::from_str(&call.arguments)
+			.into_diagnostic()
+			.wrap_err(format!("Failed to parse tool call arguments for tool '{}'", call.name))?;
+
+		// the model may only read what was offered to it
+		let (server_name, client_with_tools) = self
+			.clients
+			.iter()
+			.find(|(_, client)| client.resources.iter().any(|resource| resource.uri == arguments.uri))
+			.ok_or_else(|| miette::miette!("Resource '{}' is not listed by any MCP server", arguments.uri))?;
+
+		let _permit = client_with_tools
+			.call_permits
+			.acquire()
+			.await
+			.expect("call permits are never closed");
+
+		let timeout = self.tool_settings(READ_RESOURCE_TOOL).timeout;
+		let _deadline = client_with_tools
+			.client()
+			.service()
+			.call_deadlines
+			.register(tokio::time::Instant::now() + timeout);
+		let request = client_with_tools.client().read_resource(ReadResourceRequestParam {
+			uri: arguments.uri.clone(),
+		});
+		let result = tokio::time::timeout(timeout, request)
+			.await
+			.map_err(|_| {
+				miette::miette!(
+					"Reading resource '{}' from MCP server '{}' timed out after {}",
+					arguments.uri,
+					server_name,
+					humantime::format_duration(timeout)
+				)
+			})?
+			.map_err(|err| {
+				miette::miette!(
+					"Failed to read resource '{}' from MCP server '{}': {}",
+					arguments.uri,
+					server_name,
+					service_error_to_description(&err)
+				)
+			})?;
+
+		let content = result.contents.into_iter().map(Content::resource).collect::<Vec<_>>();
+		let extracted = extract_content(&content);
+
+		Ok(ToolCallOutput {
+			value: Value::String(extracted.text),
+			images: extracted.images,
+			attachments: extracted.attachments,
+			sources: extracted.sources,
+		})
+	}
+
+	/// Read the resources configured as context for the preprompt template, keyed by their name in the template.
+	/// Resources which can't be read are left out, so a broken server doesn't prevent a reply.
+	pub async fn read_context_resources(&self) -> HashMap<String, String> {
+		let mut resources = HashMap::new();
+
+		for (server_name, client_with_tools) in &self.clients {
+			let Some(settings) = self.settings.get(server_name) else {
+				continue;
+			};
+
+			let timeout = settings.tool_settings(READ_RESOURCE_TOOL).timeout;
+			for (name, uri) in &settings.context_resources {
+				let request = client_with_tools.client().read_resource(ReadResourceRequestParam {
+					uri: uri.clone(),
+				});
+
+				match tokio::time::timeout(timeout, request).await {
+					Ok(Ok(result)) => {
+						resources.insert(name.clone(), resource_text(&result.contents));
+					},
+					Ok(Err(err)) => warn!(
+						"Failed to read context resource '{}' from MCP server '{}': {}",
+						uri,
+						server_name,
+						service_error_to_description(&err)
+					),
+					Err(_) => warn!(
+						"Reading context resource '{}' from MCP server '{}' timed out",
+						uri, server_name
+					),
 				}
+			}
+		}
+
+		resources
+	}
+
+	/// List the prompts of all servers which are allowed to offer them, paired with the name of the server.
+	pub async fn list_prompts(&self) -> Result<Vec<(String, Prompt)>> {
+		let mut prompts = Vec::new();
 
-				all_tools.push(function);
+		for (server_name, client_with_tools) in &self.clients {
+			let enabled = self.settings.get(server_name).is_some_and(|settings| settings.prompts);
+			let offers_prompts = client_with_tools
+				.client()
+				.peer_info()
+				.is_some_and(|info| info.capabilities.prompts.is_some());
+			if !enabled || !offers_prompts {
+				continue;
 			}
+
+			let server_prompts = client_with_tools
+				.client()
+				.list_all_prompts()
+				.await
+				.into_diagnostic()
+				.wrap_err(format!("Failed to list prompts of MCP server '{}'", server_name))?;
+			prompts.extend(server_prompts.into_iter().map(|prompt| (server_name.clone(), prompt)));
 		}
 
-		all_tools.into_boxed_slice()
+		Ok(prompts)
 	}
 
-	pub async fn handle_llm_tool_call(&self, tool_call: &ToolCall) -> Option<Result<Value>> {
-		let call = &tool_call.function;
+	/// Fetch a prompt from the given server and convert its messages for the model.
+	pub async fn get_prompt(&self, server_name: &str, prompt_name: &str, arguments: JsonObject) -> Result<Vec<ChatMessage>> {
+		let client_with_tools = self
+			.clients
+			.get(server_name)
+			.ok_or_else(|| miette::miette!("MCP server '{}' is not connected", server_name))?;
+
+		let result = client_with_tools
+			.client()
+			.get_prompt(GetPromptRequestParam {
+				name: prompt_name.to_string(),
+				arguments: Some(arguments),
+			})
+			.await
+			.map_err(|err| {
+				miette::miette!(
+					"Failed to get prompt '{}' from MCP server '{}': {}",
+					prompt_name,
+					server_name,
+					service_error_to_description(&err)
+				)
+			})?;
+
+		Ok(result.messages.into_iter().map(prompt_message_to_chat).collect())
+	}
 
-		// figure out which client to use based on tool name
-		let find_result = self.clients.iter().find(|(_server_name, client)| {
+	/// Figure out which server provides the given tool.
+	fn find_server(&self, tool_name: &str) -> Option<(&String, &McpClientWithTools)> {
+		self.clients.iter().find(|(_server_name, client)| {
 			let tools = &client.tools().tools;
-			tools.iter().any(|tool| tool.name == call.name)
-		});
+			tools.iter().any(|tool| tool.name == tool_name)
+		})
+	}
+
+	/// Get the name of the server providing the given tool.
+	pub fn tool_server(&self, tool_name: &str) -> Option<&str> {
+		self.find_server(tool_name).map(|(server_name, _)| server_name.as_str())
+	}
+
+	/// Get the effective settings for a tool. Unknown tools use the default settings.
+	pub fn tool_settings(&self, tool_name: &str) -> ToolSettings {
+		self
+			.find_server(tool_name)
+			.and_then(|(server_name, _)| self.settings.get(server_name))
+			.map(|settings| settings.tool_settings(tool_name))
+			.unwrap_or_else(|| McpServerSettings::default().tool_settings(tool_name))
+	}
 
-		let (server_name, client_with_tools) = match find_result {
+	/// Call the tool requested by the LLM on the server providing it.
+	/// Progress reported by the server while the tool is running is passed to `on_progress` as text for the user.
+	pub async fn handle_llm_tool_call(
+		&self,
+		tool_call: &ToolCall,
+		on_progress: &(dyn Fn(String) + Send + Sync),
+	) -> Option<Result<ToolCallOutput>> {
+		let call = &tool_call.function;
+
+		let (server_name, client_with_tools) = match self.find_server(&call.name) {
 			Some((name, client)) => (name, client),
+			None if call.name == READ_RESOURCE_TOOL => return Some(self.read_listed_resource(call).await),
 			None => {
 				return Some(Err(miette::miette!("No MCP client found for tool '{}'", call.name)));
 			},
@@ -401,12 +1307,80 @@ impl McpConnection {
 			},
 		};
 
-		let result = client
-			.call_tool(CallToolRequestParam {
-				name: call.name.clone().into(),
-				arguments: Some(arguments),
-			})
-			.await;
+		// wait for our turn, in case other calls are already running on this server
+		let _permit = client_with_tools
+			.call_permits
+			.acquire()
+			.await
+			.expect("call permits are never closed");
+
+		// we pick the progress token ourselves, so we can subscribe before the server could possibly send progress
+		let progress_token = client_with_tools.progress_token();
+		let mut progress = client.service().progress.subscribe(progress_token.clone()).await;
+		let mut meta = Meta::new();
+		meta.set_progress_token(progress_token);
+
+		let passes_context = self
+			.settings
+			.get(server_name)
+			.is_some_and(|settings| settings.invocation_context != InvocationContextMode::None);
+		if let (true, Some(context)) = (passes_context, &self.invocation_context) {
+			context.add_to_meta(&mut meta);
+		}
+
+		let request = ClientRequest::CallToolRequest(CallToolRequest::new(CallToolRequestParam {
+			name: call.name.clone().into(),
+			arguments: Some(arguments),
+		}));
+		let options = PeerRequestOptions {
+			timeout: None,
+			meta: Some(meta),
+		};
+
+		let response = async {
+			let response = client
+				.send_cancellable_request(request, options)
+				.await?
+				.await_response()
+				.await?;
+			match response {
+				ServerResult::CallToolResult(result) => Ok(result),
+				_ => Err(ServiceError::UnexpectedResponse),
+			}
+		};
+
+		// timeout only starts once we are allowed to call the tool
+		let settings = self.tool_settings(&call.name);
+		let _deadline = client
+			.service()
+			.call_deadlines
+			.register(tokio::time::Instant::now() + settings.timeout);
+		let result = tokio::time::timeout(settings.timeout, async {
+			tokio::pin!(response);
+			loop {
+				tokio::select! {
+					result = &mut response => break result,
+					Some(update) = progress.next() => {
+						if let Some(text) = describe_progress(&update) {
+							on_progress(text);
+						}
+					},
+				}
+			}
+		})
+		.await;
+
+		let result = match result {
+			Ok(result) => result,
+			Err(_) => {
+				return Some(Err(miette::miette!(
+					"Tool '{}' on MCP server '{}' timed out after {}",
+					call.name,
+					server_name,
+					humantime::format_duration(settings.timeout)
+				)));
+			},
+		};
 
 		match result {
 			Ok(CallToolResult {
@@ -415,10 +1389,12 @@ impl McpConnection {
 				structured_content,
 				..
 			}) => {
+				let mut extracted = extract_content(&content);
+
 				// obvious error case, plain and simple
 				if is_error.unwrap_or(false) {
 					let error_message = if !content.is_empty() {
-						extract_text_from_content(&content)
+						extracted.text
 					} else {
 						"Tool execution failed without error details".to_string()
 					};
@@ -436,20 +1412,35 @@ impl McpConnection {
 					// If we have structured content and it's not empty, return it
 					if !structured.is_null() {
 						debug!("Returning structured content for tool '{}'", call.name);
-						return Some(Ok(structured));
+						extract_sources(&structured, &mut extracted.sources);
+						return Some(Ok(ToolCallOutput {
+							value: structured,
+							images: extracted.images,
+							attachments: extracted.attachments,
+							sources: extracted.sources,
+						}));
 					}
 				}
 
 				// Fall back to extracting text content if no structured content or if it's empty
 				if !content.is_empty() {
-					let text_content = extract_text_from_content(&content);
-					debug!("Returning text content for tool '{}': {}", call.name, text_content);
-					return Some(Ok(Value::String(text_content)));
+					debug!("Returning text content for tool '{}': {}", call.name, extracted.text);
+					return Some(Ok(ToolCallOutput {
+						value: Value::String(extracted.text),
+						images: extracted.images,
+						attachments: extracted.attachments,
+						sources: extracted.sources,
+					}));
 				}
 
 				// No content at all - return empty string
 				debug!("Tool '{}' returned no content, returning empty string", call.name);
-				Some(Ok(Value::String(String::new())))
+				Some(Ok(ToolCallOutput {
+					value: Value::String(String::new()),
+					images: Vec::new(),
+					attachments: Vec::new(),
+					sources: Vec::new(),
+				}))
 			},
 
 			Err(err) => Some(Err(miette::miette!(
@@ -467,13 +1458,371 @@ impl McpManager {
 	/// This only stores the configuration - connections are created on demand
 	pub fn new(config: McpConfig) -> Self {
 		Self {
-			config,
+			config: RwLock::new(config),
 		}
 	}
 
 	/// Create a new MCP connection session
 	/// This establishes connections to all configured servers
-	pub async fn create_connection(&self) -> Result<McpConnection> {
-		McpConnection::new(&self.config).await
+	pub async fn create_connection(
+		&self,
+		invocation_context: Option<InvocationContext>,
+		services: ClientServices,
+	) -> Result<McpConnection> {
+		let config = self.config.read().await;
+		McpConnection::new(&config, invocation_context, services).await
+	}
+
+	/// Reload configuration from disk and replace the current configuration.
+	/// The new configuration is only applied if a connection to all servers can be established.
+	/// Returns a connection with the new configuration, which can be used to inspect the now available tools.
+	pub async fn reload(&self) -> Result<McpConnection> {
+		let config = McpConfig::load_default()
+			.await?
+			.ok_or_else(|| miette::miette!("No MCP configuration file found"))?;
+
+		let connection = McpConnection::new(&config, None, ClientServices::default())
+			.await
+			.wrap_err("Failed to connect with reloaded MCP configuration")?;
+
+		*self.config.write().await = config;
+		info!("Reloaded MCP configuration");
+
+		Ok(connection)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rmcp::model::{
+		AnnotateAble,
+		Annotations,
+		RawAudioContent,
+		RawEmbeddedResource,
+		RawResource,
+	};
+
+	use super::*;
+
+	fn content(raw: RawContent) -> Content {
+		raw.no_annotation()
+	}
+
+	fn user_content(raw: RawContent) -> Content {
+		raw.annotate(Annotations {
+			audience: Some(vec![Role::User]),
+			priority: None,
+			last_modified: None,
+		})
+	}
+
+	/// Test that plain text is concatenated line by line
+	#[test]
+	fn test_extract_text() {
+		let extracted = extract_content(&[content(RawContent::text("first")), content(RawContent::text("second"))]);
+
+		assert_eq!(extracted.text, "first\nsecond");
+		assert!(extracted.images.is_empty());
+	}
+
+	/// Test that images are decoded and replaced with a numbered placeholder
+	#[test]
+	fn test_extract_image() {
+		let data = BASE64_STANDARD.encode([0u8; 2048]);
+		let extracted = extract_content(&[
+			content(RawContent::image(data.clone(), "image/png")),
+			content(RawContent::image(data, "image/jpeg")),
+		]);
+
+		assert_eq!(
+			extracted.text,
+			"[image #1: image/png, 2.0 KiB]\n[image #2: image/jpeg, 2.0 KiB]"
+		);
+		assert_eq!(extracted.images.len(), 2);
+		assert_eq!(extracted.images[0].mime_type, "image/png");
+		assert_eq!(extracted.images[0].data.len(), 2048);
+	}
+
+	/// Test that malformed image data does not fail the entire extraction
+	#[test]
+	fn test_extract_malformed_image() {
+		let extracted = extract_content(&[content(RawContent::image("not base64!", "image/png"))]);
+
+		assert_eq!(extracted.text, "[image: image/png, malformed data]");
+		assert!(extracted.images.is_empty());
+	}
+
+	/// Test that audio is summarised with mime type and size
+	#[test]
+	fn test_extract_audio() {
+		let audio = RawAudioContent {
+			data: BASE64_STANDARD.encode([0u8; 100]),
+			mime_type: "audio/wav".to_string(),
+		};
+		let extracted = extract_content(&[content(RawContent::Audio(audio.no_annotation()))]);
+
+		assert_eq!(extracted.text, "[audio: audio/wav, 100 B]");
+		assert!(extracted.images.is_empty());
+	}
+
+	/// Test that embedded text resources are inlined
+	#[test]
+	fn test_extract_text_resource() {
+		let extracted = extract_content(&[content(RawContent::embedded_text("file:///notes.txt", "hello world"))]);
+
+		assert_eq!(extracted.text, "[resource: file:///notes.txt]\nhello world");
+	}
+
+	/// Test that binary resources are summarised, unless they are images
+	#[test]
+	fn test_extract_blob_resource() {
+		let blob = |mime_type: Option<&str>| {
+			content(RawContent::resource(ResourceContents::BlobResourceContents {
+				uri: "file:///blob".to_string(),
+				mime_type: mime_type.map(str::to_string),
+				blob: BASE64_STANDARD.encode(vec![0u8; 3 * 1024 * 1024]),
+				meta: None,
+			}))
+		};
+
+		let extracted = extract_content(&[blob(Some("application/pdf")), blob(None), blob(Some("image/webp"))]);
+
+		assert_eq!(
+			extracted.text,
+			"[binary resource: file:///blob, application/pdf, 3.0 MiB]\n[binary resource: file:///blob, application/octet-stream, 3.0 \
+			 MiB]\n[image #1: image/webp, 3.0 MiB]"
+		);
+		assert_eq!(extracted.images.len(), 1);
+		assert_eq!(extracted.images[0].mime_type, "image/webp");
+	}
+
+	/// Test that resource links are described with all available metadata
+	#[test]
+	fn test_extract_resource_link() {
+		let link = RawResource {
+			uri: "https://example.com/report.csv".to_string(),
+			name: "report".to_string(),
+			description: Some("Monthly report".to_string()),
+			mime_type: Some("text/csv".to_string()),
+			size: Some(512),
+		};
+		let minimal = RawResource::new("file:///data", "data");
+
+		let extracted = extract_content(&[
+			content(RawContent::ResourceLink(link)),
+			content(RawContent::ResourceLink(minimal)),
+		]);
+
+		assert_eq!(
+			extracted.text,
+			"[resource link: report (https://example.com/report.csv, text/csv, 512 B)] Monthly report\n[resource link: data \
+			 (file:///data)]"
+		);
+	}
+
+	/// Test that content for the user is collected as attachment with a sanitized name
+	#[test]
+	fn test_extract_user_facing() {
+		let image = BASE64_STANDARD.encode([0u8; 16]);
+		let pdf = RawContent::resource(ResourceContents::BlobResourceContents {
+			uri: "https://example.com/files/../Q3 report (final).pdf?download=1".to_string(),
+			mime_type: Some("application/pdf".to_string()),
+			blob: BASE64_STANDARD.encode([0u8; 32]),
+			meta: None,
+		});
+
+		let extracted = extract_content(&[
+			user_content(RawContent::image(image, "image/jpeg")),
+			user_content(pdf),
+			user_content(RawContent::text("visible to model")),
+		]);
+
+		assert_eq!(
+			extracted.text,
+			"[image #1: image/jpeg, 16 B, file attachment-1.jpg for the user]\n[file: application/pdf, 32 B, file \
+			 Q3_report__final_.pdf for the user]\nvisible to model"
+		);
+		assert_eq!(extracted.images.len(), 1);
+		assert_eq!(extracted.attachments.len(), 2);
+		assert_eq!(extracted.attachments[0].filename, "attachment-1.jpg");
+		assert_eq!(extracted.attachments[1].filename, "Q3_report__final_.pdf");
+		assert_eq!(extracted.attachments[1].media.data.len(), 32);
+	}
+
+	/// Test file name derivation from uri and mime type
+	#[test]
+	fn test_attachment_filename() {
+		assert_eq!(
+			attachment_filename(Some("file:///tmp/chart.png"), "image/png", 1),
+			"chart.png"
+		);
+		assert_eq!(
+			attachment_filename(Some("file:///tmp/.hidden"), "text/plain", 1),
+			"hidden.plain"
+		);
+		assert_eq!(
+			attachment_filename(Some("https://example.com/"), "image/svg+xml", 2),
+			"attachment-2.svg"
+		);
+		assert_eq!(attachment_filename(None, "text/csv; charset=utf-8", 3), "attachment-3.csv");
+		assert_eq!(attachment_filename(None, "application/octet-stream", 4), "attachment-4.bin");
+		assert_eq!(
+			attachment_filename(Some(&"a".repeat(100)), "text/plain", 1).len(),
+			64 + ".plain".len()
+		);
+	}
+
+	/// Test that results are only truncated if they exceed the limit
+	#[test]
+	fn test_truncate_result() {
+		let short = Value::String("short".to_string());
+		assert_eq!(truncate_result(short.clone(), 5), short);
+
+		let structured = serde_json::json!({"key": "value"});
+		assert_eq!(truncate_result(structured.clone(), 100), structured);
+
+		assert_eq!(
+			truncate_result(Value::String("äöü".repeat(4)), 5),
+			Value::String("äöüäö\n[truncated, 7 of 12 characters omitted]".to_string())
+		);
+		assert_eq!(
+			truncate_result(structured, 5),
+			Value::String("{\"key\n[truncated, 10 of 15 characters omitted]".to_string())
+		);
+	}
+
+	#[test]
+	fn test_describe_progress() {
+		let progress = |progress: f64, total: Option<f64>, message: Option<&str>| ProgressNotificationParam {
+			progress_token: ProgressToken(NumberOrString::Number(1)),
+			progress,
+			total,
+			message: message.map(str::to_string),
+		};
+
+		assert_eq!(
+			describe_progress(&progress(1.0, Some(3.0), Some("Reading 3 pages"))),
+			Some("Reading 3 pages".to_string())
+		);
+		assert_eq!(describe_progress(&progress(1.0, Some(4.0), None)), Some("25%".to_string()));
+		assert_eq!(
+			describe_progress(&progress(1.0, Some(4.0), Some("  "))),
+			Some("25%".to_string())
+		);
+		assert_eq!(describe_progress(&progress(5.0, Some(4.0), None)), Some("100%".to_string()));
+		assert_eq!(describe_progress(&progress(5.0, None, None)), None);
+		assert_eq!(describe_progress(&progress(5.0, Some(0.0), None)), None);
+	}
+
+	#[test]
+	fn test_extract_sources() {
+		let value = serde_json::json!({
+			"results": [
+				{ "title": "Rust", "url": "https://www.rust-lang.org/" },
+				{ "title": "Docs", "url": "http://docs.rs/llm" }
+			],
+			"query": "https://",
+			"note": "see https://example.com for details",
+			"count": 2
+		});
+
+		let mut sources = Vec::new();
+		extract_sources(&value, &mut sources);
+		assert_eq!(sources, vec!["https://www.rust-lang.org/", "http://docs.rs/llm"]);
+	}
+
+	#[test]
+	fn test_resource_link_source() {
+		let link = RawContent::ResourceLink(RawResource::new("https://example.com/report", "report"));
+		let file = RawContent::ResourceLink(RawResource::new("file:///tmp/report", "report"));
+
+		let extracted = extract_content(&[content(link), content(file)]);
+		assert_eq!(extracted.sources, vec!["https://example.com/report"]);
+	}
+
+	/// Test that only text contents of resources end up in the template context
+	#[test]
+	fn test_resource_text() {
+		let contents = vec![
+			ResourceContents::text("first", "file:///first"),
+			ResourceContents::BlobResourceContents {
+				uri: "file:///image".to_string(),
+				mime_type: Some("image/png".to_string()),
+				blob: "aGVsbG8=".to_string(),
+				meta: None,
+			},
+			ResourceContents::text("second", "file:///second"),
+		];
+
+		assert_eq!(resource_text(&contents), "first\nsecond");
+	}
+
+	/// Test that prompt messages keep their role, and embedded resources are inlined
+	#[test]
+	fn test_prompt_message_to_chat() {
+		let message = prompt_message_to_chat(PromptMessage::new_text(PromptMessageRole::Assistant, "hello"));
+		assert_eq!(message.role, llm::chat::ChatRole::Assistant);
+		assert_eq!(message.content, "hello");
+
+		let message = prompt_message_to_chat(PromptMessage {
+			role: PromptMessageRole::User,
+			content: PromptMessageContent::Resource {
+				resource: RawEmbeddedResource {
+					meta: None,
+					resource: ResourceContents::text("fn main() {}", "file:///main.rs"),
+				}
+				.no_annotation(),
+			},
+		});
+		assert_eq!(message.role, llm::chat::ChatRole::User);
+		assert_eq!(message.content, "[resource: file:///main.rs]\nfn main() {}");
+	}
+
+	fn invocation_context() -> InvocationContext {
+		InvocationContext {
+			user_id: "1".to_string(),
+			guild_id: Some("2".to_string()),
+			channel_id: "3".to_string(),
+			display_name: "Jürgen 100%".to_string(),
+		}
+	}
+
+	#[test]
+	fn test_invocation_context_meta() {
+		let mut meta = Meta::new();
+		invocation_context().add_to_meta(&mut meta);
+
+		assert_eq!(
+			meta.0.get("discord"),
+			Some(&serde_json::json!({
+				"user_id": "1",
+				"guild_id": "2",
+				"channel_id": "3",
+				"display_name": "Jürgen 100%"
+			}))
+		);
+	}
+
+	#[test]
+	fn test_invocation_context_headers() {
+		let headers = invocation_context().headers();
+		assert_eq!(headers.get("X-Discord-User-Id").map(String::as_str), Some("1"));
+		assert_eq!(headers.get("X-Discord-Guild-Id").map(String::as_str), Some("2"));
+		assert_eq!(headers.get("X-Discord-Channel-Id").map(String::as_str), Some("3"));
+		assert_eq!(
+			headers.get("X-Discord-Display-Name").map(String::as_str),
+			Some("J%C3%BCrgen 100%25")
+		);
+
+		// all values must be valid header values, otherwise they are silently dropped
+		for value in headers.values() {
+			assert!(reqwest::header::HeaderValue::from_str(value).is_ok());
+		}
+
+		let dm = InvocationContext {
+			guild_id: None,
+			..invocation_context()
+		};
+		assert!(!dm.headers().contains_key("X-Discord-Guild-Id"));
 	}
 }
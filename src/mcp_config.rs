@@ -1,6 +1,7 @@
 use std::{
 	collections::HashMap,
 	path::Path,
+	time::Duration,
 };
 
 use miette::{
@@ -10,13 +11,206 @@ use miette::{
 };
 use serde::{
 	Deserialize,
+	Deserializer,
 	Serialize,
+	Serializer,
 };
 use tokio::fs;
 
+/// Timeout for tool calls, if not configured otherwise.
+const DEFAULT_TOOL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of characters of a tool result passed to the model, if not configured otherwise.
+const DEFAULT_MAX_RESULT_SIZE: usize = 20_000;
+
+/// Number of concurrent tool calls per server, if not configured otherwise.
+const DEFAULT_MAX_CONCURRENT_CALLS: usize = 4;
+
+/// Number of sampling requests a server can make per invocation, if not configured otherwise.
+const DEFAULT_MAX_SAMPLING_REQUESTS: u32 = 3;
+
+/// Number of tokens a server can use for sampling per invocation, if not configured otherwise.
+const DEFAULT_MAX_SAMPLING_TOKENS: usize = 4000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpConfig {
 	pub servers: HashMap<String, McpServerConfig>,
+
+	/// Additional settings for servers, keyed by server name.
+	/// Kept separate from `servers`, so the server list stays compatible with other MCP clients.
+	#[serde(default)]
+	pub settings: HashMap<String, McpServerSettings>,
+}
+
+/// Settings for a single MCP server.
+/// Settings apply to all tools of the server, unless overwritten for a specific tool.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct McpServerSettings {
+	#[serde(flatten)]
+	pub defaults: McpToolSettings,
+
+	/// Maximum number of tool calls that are executed concurrently on this server.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_concurrent_calls: Option<usize>,
+
+	/// Whether the server is told which Discord user, guild and channel a tool call is made for.
+	#[serde(default)]
+	pub invocation_context: InvocationContextMode,
+
+	/// Allows the server to request completions from our model. Disabled, unless configured.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sampling: Option<McpSamplingSettings>,
+
+	/// Resources read for every invocation and passed to the preprompt template, keyed by the name used in the template.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub context_resources: HashMap<String, String>,
+
+	/// Whether the model can read the resources listed by the server.
+	#[serde(default)]
+	pub resources: bool,
+
+	/// Whether the prompts of the server are offered as slash commands.
+	#[serde(default)]
+	pub prompts: bool,
+
+	/// Settings for individual tools, keyed by tool name.
+	#[serde(default)]
+	pub tools: HashMap<String, McpToolSettings>,
+}
+
+/// Quota for completions a server can request via sampling.
+/// Sampling requests also count against the rate limits of the invoking user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct McpSamplingSettings {
+	/// Maximum number of sampling requests per invocation.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_requests: Option<u32>,
+
+	/// Maximum number of tokens used for sampling per invocation. A request exceeding it is still completed, but no
+	/// further requests are allowed.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_tokens: Option<usize>,
+}
+
+impl McpSamplingSettings {
+	pub fn max_requests(&self) -> u32 {
+		self.max_requests.unwrap_or(DEFAULT_MAX_SAMPLING_REQUESTS)
+	}
+
+	pub fn max_tokens(&self) -> usize {
+		self.max_tokens.unwrap_or(DEFAULT_MAX_SAMPLING_TOKENS)
+	}
+}
+
+/// How details about the Discord invocation are passed to a server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvocationContextMode {
+	/// Nothing is passed, the server can't tell who a tool call is made for.
+	#[default]
+	None,
+
+	/// Passed in the `_meta` field of every tool call.
+	Meta,
+
+	/// Passed in the `_meta` field and as HTTP headers of the connection. Headers are only sent to HTTP and SSE servers.
+	MetaAndHeaders,
+}
+
+/// Who has to approve a call of a tool, before it is executed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalMode {
+	/// Calls are executed right away.
+	#[default]
+	None,
+
+	/// The user who invoked the bot has to approve.
+	User,
+
+	/// Members allowed to manage messages in the channel have to approve. In DMs, the invoking user decides instead.
+	Moderators,
+}
+
+/// Settings for tool calls. Unset fields fall back to the server settings and then to the global defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct McpToolSettings {
+	/// Maximum duration of a single tool call, in any format supported by the `humantime` crate.
+	#[serde(
+		default,
+		deserialize_with = "deserialize_duration",
+		serialize_with = "serialize_duration",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub timeout: Option<Duration>,
+
+	/// Maximum number of characters of a tool result that are passed to the model.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_result_size: Option<usize>,
+
+	/// Whether results exceeding `max_result_size` are summarized by the model instead of being truncated.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub summarize: Option<bool>,
+
+	/// Text shown to the user while the tool is running, e.g. "Searching the web".
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
+
+	/// Who has to approve calls, for tools which shouldn't run just because the model asked for it.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub approval: Option<ApprovalMode>,
+}
+
+/// Effective settings for a tool call, with all fallbacks applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolSettings {
+	pub timeout: Duration,
+	pub max_result_size: usize,
+	pub summarize: bool,
+	pub display_name: Option<String>,
+	pub approval: ApprovalMode,
+}
+
+impl McpServerSettings {
+	/// Resolves the number of concurrent tool calls allowed on this server. Always at least one.
+	pub fn max_concurrent_calls(&self) -> usize {
+		self.max_concurrent_calls.unwrap_or(DEFAULT_MAX_CONCURRENT_CALLS).max(1)
+	}
+
+	/// Resolves the effective settings for the given tool.
+	pub fn tool_settings(&self, tool_name: &str) -> ToolSettings {
+		let tool = self.tools.get(tool_name);
+		let defaults = &self.defaults;
+
+		ToolSettings {
+			timeout: tool
+				.and_then(|t| t.timeout)
+				.or(defaults.timeout)
+				.unwrap_or(DEFAULT_TOOL_TIMEOUT),
+			max_result_size: tool
+				.and_then(|t| t.max_result_size)
+				.or(defaults.max_result_size)
+				.unwrap_or(DEFAULT_MAX_RESULT_SIZE),
+			summarize: tool.and_then(|t| t.summarize).or(defaults.summarize).unwrap_or(false),
+			display_name: tool
+				.and_then(|t| t.display_name.clone())
+				.or_else(|| defaults.display_name.clone()),
+			approval: tool.and_then(|t| t.approval).or(defaults.approval).unwrap_or_default(),
+		}
+	}
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+	Option::<String>::deserialize(deserializer)?
+		.map(|s| humantime::parse_duration(&s).map_err(serde::de::Error::custom))
+		.transpose()
+}
+
+fn serialize_duration<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+	match duration {
+		Some(duration) => serializer.serialize_str(&humantime::format_duration(*duration).to_string()),
+		None => serializer.serialize_none(),
+	}
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -426,6 +620,7 @@ mod tests {
 
 		let original_config = McpConfig {
 			servers,
+			settings: HashMap::new(),
 		};
 
 		let json = serde_json::to_string(&original_config).expect("Failed to serialize");
@@ -470,4 +665,103 @@ mod tests {
 			}
 		}
 	}
+
+	/// Test parsing server settings next to the server list
+	#[tokio::test]
+	async fn test_parse_server_settings() {
+		let json = r#"
+        {
+            "servers": {
+                "web-search": {
+                    "type": "http",
+                    "url": "http://localhost:8080"
+                }
+            },
+            "settings": {
+                "web-search": {
+                    "timeout": "10s",
+                    "max_result_size": 5000,
+                    "invocation_context": "meta_and_headers",
+                    "sampling": { "max_requests": 5 },
+                    "context_resources": { "rules": "file:///rules.md" },
+                    "resources": true,
+                    "tools": {
+                        "fetch": {
+                            "timeout": "2m",
+                            "summarize": true,
+                            "display_name": "Reading pages"
+                        },
+                        "post": {
+                            "approval": "moderators"
+                        }
+                    }
+                }
+            }
+        }
+        "#;
+
+		let config: McpConfig = serde_json::from_str(json).expect("Failed to parse config");
+		let settings = config.settings.get("web-search").expect("web-search settings not found");
+		assert_eq!(settings.invocation_context, InvocationContextMode::MetaAndHeaders);
+
+		let sampling = settings.sampling.as_ref().expect("sampling settings not found");
+		assert_eq!(sampling.max_requests(), 5);
+		assert_eq!(sampling.max_tokens(), DEFAULT_MAX_SAMPLING_TOKENS);
+
+		assert_eq!(settings.context_resources.get("rules"), Some(&"file:///rules.md".to_string()));
+		assert!(settings.resources);
+		assert!(!settings.prompts);
+
+		assert_eq!(settings.tool_settings("search"), ToolSettings {
+			timeout: Duration::from_secs(10),
+			max_result_size: 5000,
+			summarize: false,
+			display_name: None,
+			approval: ApprovalMode::None,
+		});
+		assert_eq!(settings.tool_settings("fetch"), ToolSettings {
+			timeout: Duration::from_secs(120),
+			max_result_size: 5000,
+			summarize: true,
+			display_name: Some("Reading pages".to_string()),
+			approval: ApprovalMode::None,
+		});
+		assert_eq!(settings.tool_settings("post").approval, ApprovalMode::Moderators);
+	}
+
+	/// Test that servers without settings use the global defaults
+	#[tokio::test]
+	async fn test_default_tool_settings() {
+		assert_eq!(McpServerSettings::default().invocation_context, InvocationContextMode::None);
+		assert!(McpServerSettings::default().sampling.is_none());
+		assert!(McpServerSettings::default().context_resources.is_empty());
+
+		let settings = McpServerSettings::default().tool_settings("anything");
+
+		assert_eq!(settings, ToolSettings {
+			timeout: DEFAULT_TOOL_TIMEOUT,
+			max_result_size: DEFAULT_MAX_RESULT_SIZE,
+			summarize: false,
+			display_name: None,
+			approval: ApprovalMode::None,
+		});
+	}
+
+	/// Test error handling for malformed durations
+	#[tokio::test]
+	async fn test_invalid_timeout() {
+		let json = r#"
+        {
+            "servers": {},
+            "settings": {
+                "broken": {
+                    "timeout": "soon"
+                }
+            }
+        }
+        "#;
+
+		let result: Result<McpConfig, _> = serde_json::from_str(json);
+		assert!(result.is_err());
+	}
 }
@@ -0,0 +1,719 @@
+use std::{
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::Duration,
+};
+
+use log::debug;
+use poise::serenity_prelude::{
+	ActionRowComponent,
+	ButtonStyle,
+	ChannelId,
+	ComponentInteraction,
+	ComponentInteractionCollector,
+	Context,
+	CreateActionRow,
+	CreateAllowedMentions,
+	CreateButton,
+	CreateEmbed,
+	CreateInputText,
+	CreateInteractionResponse,
+	CreateInteractionResponseMessage,
+	CreateMessage,
+	CreateModal,
+	EditMessage,
+	InputTextStyle,
+	Message,
+	MessageId,
+	ModalInteraction,
+	ModalInteractionCollector,
+	UserId,
+};
+use rmcp::{
+	ErrorData,
+	model::{
+		CreateElicitationRequestParam,
+		CreateElicitationResult,
+		ElicitationAction,
+		JsonObject,
+	},
+};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::text::truncate_chars;
+
+/// Time the user has at most to answer, before the request is cancelled.
+/// The wait ends earlier if the tool call asking for input times out before.
+const ELICITATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Discord allows at most five text inputs per modal.
+const MAX_MODAL_FIELDS: usize = 5;
+
+/// Discord allows 25 buttons per message, two of them are needed to decline and cancel.
+const MAX_CHOICE_BUTTONS: usize = 20;
+
+/// Labels of text inputs and titles of modals are limited to 45 characters.
+const MAX_LABEL_LENGTH: usize = 45;
+
+/// Type of a single field requested by a server.
+#[derive(Debug, Clone, PartialEq)]
+enum FieldKind {
+	Text,
+	Integer,
+	Number,
+	Boolean,
+	Choice(Vec<String>),
+}
+
+/// A single field of the schema requested by a server. Elicitation only allows flat objects with primitive fields.
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+	name: String,
+	label: String,
+	description: Option<String>,
+	kind: FieldKind,
+	required: bool,
+}
+
+impl Field {
+	/// Options that can be offered as buttons, if the field has a fixed set of values.
+	fn options(&self) -> Option<Vec<(String, Value)>> {
+		match &self.kind {
+			FieldKind::Boolean => Some(vec![
+				("Yes".to_string(), Value::Bool(true)),
+				("No".to_string(), Value::Bool(false)),
+			]),
+			FieldKind::Choice(options) => Some(
+				options
+					.iter()
+					.map(|option| (option.clone(), Value::String(option.clone())))
+					.collect(),
+			),
+			_ => None,
+		}
+	}
+
+	/// Hint shown in the empty text input, telling the user what is expected.
+	fn placeholder(&self) -> Option<String> {
+		let placeholder = match &self.kind {
+			FieldKind::Text => self.description.clone()?,
+			FieldKind::Integer => "A whole number".to_string(),
+			FieldKind::Number => "A number".to_string(),
+			FieldKind::Boolean => "yes or no".to_string(),
+			FieldKind::Choice(options) => options.join(", "),
+		};
+
+		Some(truncate_chars(&placeholder, 100))
+	}
+
+	/// Converts the text entered by the user into a value matching the field type.
+	fn parse(&self, input: &str) -> Result<Value, String> {
+		let input = input.trim();
+		match &self.kind {
+			FieldKind::Text => Ok(Value::String(input.to_string())),
+			FieldKind::Integer => input
+				.parse::<i64>()
+				.map(Value::from)
+				.map_err(|_| format!("{} must be a whole number", self.label)),
+			FieldKind::Number => input
+				.parse::<f64>()
+				.ok()
+				.and_then(serde_json::Number::from_f64)
+				.map(Value::Number)
+				.ok_or_else(|| format!("{} must be a number", self.label)),
+			FieldKind::Boolean => match input.to_lowercase().as_str() {
+				"yes" | "y" | "true" | "1" => Ok(Value::Bool(true)),
+				"no" | "n" | "false" | "0" => Ok(Value::Bool(false)),
+				_ => Err(format!("{} must be yes or no", self.label)),
+			},
+			FieldKind::Choice(options) => options
+				.iter()
+				.find(|option| option.eq_ignore_ascii_case(input))
+				.map(|option| Value::String(option.clone()))
+				.ok_or_else(|| format!("{} must be one of: {}", self.label, options.join(", "))),
+		}
+	}
+}
+
+/// Parses the schema requested by a server into fields.
+/// Returns an error if the schema uses anything not allowed for elicitation.
+fn parse_schema(schema: &JsonObject) -> Result<Vec<Field>, String> {
+	let required = schema
+		.get("required")
+		.and_then(Value::as_array)
+		.map(|required| required.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+		.unwrap_or_default();
+
+	let properties = match schema.get("properties") {
+		Some(Value::Object(properties)) => properties,
+		Some(_) => return Err("properties must be an object".to_string()),
+		None => return Ok(Vec::new()),
+	};
+
+	let mut fields = Vec::new();
+	for (name, property) in properties {
+		let kind = match (
+			property.get("type").and_then(Value::as_str),
+			property.get("enum").and_then(Value::as_array),
+		) {
+			(Some("string"), Some(options)) => FieldKind::Choice(
+				options
+					.iter()
+					.map(|option| option.as_str().map(str::to_string))
+					.collect::<Option<Vec<_>>>()
+					.ok_or_else(|| format!("options of '{}' must be strings", name))?,
+			),
+			(Some("string"), None) => FieldKind::Text,
+			(Some("integer"), _) => FieldKind::Integer,
+			(Some("number"), _) => FieldKind::Number,
+			(Some("boolean"), _) => FieldKind::Boolean,
+			(kind, _) => return Err(format!("type {:?} of '{}' is not supported", kind, name)),
+		};
+
+		let label = property.get("title").and_then(Value::as_str).unwrap_or(name);
+		fields.push(Field {
+			name: name.clone(),
+			label: truncate_chars(label, MAX_LABEL_LENGTH),
+			description: property.get("description").and_then(Value::as_str).map(str::to_string),
+			kind,
+			required: required.contains(&name.as_str()),
+		});
+	}
+
+	Ok(fields)
+}
+
+/// How the requested input is presented to the user.
+enum Layout {
+	/// Nothing to fill in, the user only confirms.
+	Confirm,
+
+	/// A single field with few options, each option is a button.
+	Choice(Field, Vec<(String, Value)>),
+
+	/// Fields are filled in using a modal.
+	Modal(Vec<Field>),
+}
+
+impl Layout {
+	fn new(mut fields: Vec<Field>) -> Option<Self> {
+		if fields.is_empty() {
+			return Some(Layout::Confirm);
+		}
+
+		if fields.len() == 1 {
+			if let Some(options) = fields[0].options().filter(|options| options.len() <= MAX_CHOICE_BUTTONS) {
+				return Some(Layout::Choice(fields.remove(0), options));
+			}
+		}
+
+		(fields.len() <= MAX_MODAL_FIELDS).then_some(Layout::Modal(fields))
+	}
+
+	fn components(&self) -> Vec<CreateActionRow> {
+		let mut rows = Vec::new();
+		let mut last_row = Vec::new();
+
+		match self {
+			Layout::Confirm => {
+				last_row.push(CreateButton::new("accept").style(ButtonStyle::Success).label("Confirm"));
+			},
+			Layout::Choice(_, options) => {
+				let buttons = options
+					.iter()
+					.enumerate()
+					.map(|(index, (label, _))| {
+						CreateButton::new(format!("choice:{}", index))
+							.style(ButtonStyle::Primary)
+							.label(truncate_chars(label, 80))
+					})
+					.collect::<Vec<_>>();
+
+				rows.extend(buttons.chunks(5).map(|row| CreateActionRow::Buttons(row.to_vec())));
+			},
+			Layout::Modal(_) => {
+				last_row.push(CreateButton::new("fill").style(ButtonStyle::Primary).label("Fill in"));
+			},
+		}
+
+		last_row.push(CreateButton::new("decline").style(ButtonStyle::Secondary).label("Decline"));
+		last_row.push(CreateButton::new("cancel").style(ButtonStyle::Danger).label("Cancel"));
+		rows.push(CreateActionRow::Buttons(last_row));
+
+		rows
+	}
+}
+
+fn result(action: ElicitationAction, content: Option<Value>) -> CreateElicitationResult {
+	CreateElicitationResult {
+		action,
+		content,
+	}
+}
+
+/// Deadlines of the tool calls currently running on a connection.
+/// Answers arriving after all of them are useless, the calls have timed out by then.
+#[derive(Clone, Default)]
+pub struct CallDeadlines(Arc<Mutex<Vec<Instant>>>);
+
+impl CallDeadlines {
+	/// Tracks a call ending at `deadline` until the returned guard is dropped.
+	pub fn register(&self, deadline: Instant) -> CallDeadlineGuard {
+		self.0.lock().unwrap().push(deadline);
+		CallDeadlineGuard {
+			deadlines: self.clone(),
+			deadline,
+		}
+	}
+
+	/// Latest deadline of the running calls, `None` if no call is running.
+	fn latest(&self) -> Option<Instant> {
+		self.0.lock().unwrap().iter().max().copied()
+	}
+}
+
+/// Removes the deadline of a call once it finished.
+pub struct CallDeadlineGuard {
+	deadlines: CallDeadlines,
+	deadline: Instant,
+}
+
+impl Drop for CallDeadlineGuard {
+	fn drop(&mut self) {
+		let mut deadlines = self.deadlines.0.lock().unwrap();
+		if let Some(index) = deadlines.iter().position(|deadline| *deadline == self.deadline) {
+			deadlines.swap_remove(index);
+		}
+	}
+}
+
+/// Time until which we wait for the user, limited by the calls which might have asked for input.
+fn response_deadline(now: Instant, call_deadline: Option<Instant>) -> Instant {
+	let deadline = now + ELICITATION_TIMEOUT;
+	call_deadline.map_or(deadline, |call_deadline| deadline.min(call_deadline))
+}
+
+/// Removes the buttons of a prompt if the elicitation is dropped before it finished,
+/// which happens when the server cancels the request, e.g. because the tool call timed out.
+struct PromptGuard {
+	ctx: Context,
+	channel_id: ChannelId,
+	message_id: MessageId,
+	finished: bool,
+}
+
+impl Drop for PromptGuard {
+	fn drop(&mut self) {
+		if self.finished {
+			return;
+		}
+
+		let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+			return;
+		};
+
+		let (ctx, channel_id, message_id) = (self.ctx.clone(), self.channel_id, self.message_id);
+		runtime.spawn(async move {
+			let edit = EditMessage::new()
+				.components(Vec::new())
+				.content("-# Request ended, cancelled");
+			if let Err(err) = channel_id.edit_message(&ctx, message_id, edit).await {
+				debug!("Failed to remove buttons from elicitation message: {}", err);
+			}
+		});
+	}
+}
+
+/// Asks the invoking user for input requested by servers via elicitation.
+/// Requests are rendered as Discord message with buttons, or a modal if the user has to enter text.
+pub struct Elicitor {
+	ctx: Context,
+	channel_id: ChannelId,
+	user_id: UserId,
+	message_id: MessageId,
+}
+
+impl Elicitor {
+	/// Creates an elicitor asking the author of `message`, in reply to it.
+	pub fn new(ctx: Context, message: &Message) -> Self {
+		Self {
+			ctx,
+			channel_id: message.channel_id,
+			user_id: message.author.id,
+			message_id: message.id,
+		}
+	}
+
+	/// Asks the user for the input requested by `server_name`.
+	/// The user can answer until the latest of the server's running calls, tracked by `calls`, times out.
+	pub async fn elicit(
+		&self,
+		server_name: &str,
+		request: CreateElicitationRequestParam,
+		calls: &CallDeadlines,
+	) -> Result<CreateElicitationResult, ErrorData> {
+		let now = Instant::now();
+		let deadline = response_deadline(now, calls.latest());
+		if deadline <= now {
+			debug!(
+				"Cancelling elicitation of MCP server '{}', no time left to answer",
+				server_name
+			);
+			return Ok(result(ElicitationAction::Cancel, None));
+		}
+
+		let layout = match parse_schema(&request.requested_schema).map(Layout::new) {
+			Ok(Some(layout)) => layout,
+			Ok(None) => {
+				debug!("Declining elicitation of MCP server '{}', too many fields", server_name);
+				return Ok(result(ElicitationAction::Decline, None));
+			},
+			Err(err) => {
+				debug!(
+					"Declining elicitation of MCP server '{}', invalid schema: {}",
+					server_name, err
+				);
+				return Ok(result(ElicitationAction::Decline, None));
+			},
+		};
+
+		let prompt = CreateMessage::new()
+			.reference_message((self.channel_id, self.message_id))
+			.allowed_mentions(CreateAllowedMentions::new().empty_roles().empty_users().replied_user(true))
+			.embed(
+				CreateEmbed::new()
+					.title(truncate_chars(&format!("{} needs your input", server_name), 256))
+					.description(truncate_chars(&request.message, 4096)),
+			)
+			.components(layout.components());
+
+		let mut prompt = self
+			.channel_id
+			.send_message(&self.ctx, prompt)
+			.await
+			.map_err(|err| ErrorData::internal_error(format!("failed to ask user for input: {}", err), None))?;
+
+		let mut guard = PromptGuard {
+			ctx: self.ctx.clone(),
+			channel_id: self.channel_id,
+			message_id: prompt.id,
+			finished: false,
+		};
+
+		let (response, outcome) = match self.wait_for_response(&prompt, server_name, &layout, deadline).await {
+			Some(response) => {
+				let outcome = match response.action {
+					ElicitationAction::Accept => "Submitted",
+					ElicitationAction::Decline => "Declined",
+					ElicitationAction::Cancel => "Cancelled",
+				};
+				(response, outcome)
+			},
+			None => (result(ElicitationAction::Cancel, None), "No response, cancelled"),
+		};
+
+		// buttons are useless from now on
+		guard.finished = true;
+		let edit = EditMessage::new().components(Vec::new()).content(format!("-# {}", outcome));
+		if let Err(err) = prompt.edit(&self.ctx, edit).await {
+			debug!("Failed to remove buttons from elicitation message: {}", err);
+		}
+
+		Ok(response)
+	}
+
+	/// Waits for the user to respond to the prompt. Returns `None` if the user didn't respond before `deadline`.
+	async fn wait_for_response(
+		&self,
+		prompt: &Message,
+		server_name: &str,
+		layout: &Layout,
+		deadline: Instant,
+	) -> Option<CreateElicitationResult> {
+		let modal_id = format!("{}:elicitation", prompt.id);
+
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+
+			let components = ComponentInteractionCollector::new(&self.ctx)
+				.author_id(self.user_id)
+				.message_id(prompt.id)
+				.timeout(remaining);
+			let modals = ModalInteractionCollector::new(&self.ctx)
+				.author_id(self.user_id)
+				.custom_ids(vec![modal_id.clone()])
+				.timeout(remaining);
+
+			tokio::select! {
+				Some(interaction) = components.next() => {
+					if let Some(response) = self.handle_button(&interaction, server_name, layout, &modal_id).await {
+						return Some(response);
+					}
+				},
+				Some(interaction) = modals.next() => {
+					if let Layout::Modal(fields) = layout {
+						if let Some(response) = self.handle_modal(&interaction, fields).await {
+							return Some(response);
+						}
+					}
+				},
+				else => return None,
+			}
+		}
+	}
+
+	/// Handles a click on one of the buttons. Returns `None` if the user still has to fill in the modal.
+	async fn handle_button(
+		&self,
+		interaction: &ComponentInteraction,
+		server_name: &str,
+		layout: &Layout,
+		modal_id: &str,
+	) -> Option<CreateElicitationResult> {
+		let custom_id = interaction.data.custom_id.as_str();
+
+		if let ("fill", Layout::Modal(fields)) = (custom_id, layout) {
+			let inputs = fields
+				.iter()
+				.enumerate()
+				.map(|(index, field)| {
+					let mut input = CreateInputText::new(InputTextStyle::Short, &field.label, index.to_string()).required(field.required);
+					if let Some(placeholder) = field.placeholder() {
+						input = input.placeholder(placeholder);
+					}
+					CreateActionRow::InputText(input)
+				})
+				.collect();
+
+			let modal = CreateModal::new(
+				modal_id,
+				truncate_chars(&format!("Input for {}", server_name), MAX_LABEL_LENGTH),
+			)
+			.components(inputs);
+			if let Err(err) = interaction
+				.create_response(&self.ctx, CreateInteractionResponse::Modal(modal))
+				.await
+			{
+				debug!("Failed to open elicitation modal: {}", err);
+			}
+
+			return None;
+		}
+
+		let response = match (custom_id, layout) {
+			("accept", Layout::Confirm) => result(ElicitationAction::Accept, Some(Value::Object(Default::default()))),
+			("decline", _) => result(ElicitationAction::Decline, None),
+			("cancel", _) => result(ElicitationAction::Cancel, None),
+			(custom_id, Layout::Choice(field, options)) => {
+				let (_, value) = custom_id
+					.strip_prefix("choice:")
+					.and_then(|index| index.parse::<usize>().ok())
+					.and_then(|index| options.get(index))?;
+
+				let mut content = serde_json::Map::new();
+				content.insert(field.name.clone(), value.clone());
+				result(ElicitationAction::Accept, Some(Value::Object(content)))
+			},
+			_ => return None,
+		};
+
+		if let Err(err) = interaction
+			.create_response(&self.ctx, CreateInteractionResponse::Acknowledge)
+			.await
+		{
+			debug!("Failed to acknowledge elicitation response: {}", err);
+		}
+
+		Some(response)
+	}
+
+	/// Validates the submitted modal. Returns `None` and tells the user what is wrong, if the input is invalid.
+	async fn handle_modal(&self, interaction: &ModalInteraction, fields: &[Field]) -> Option<CreateElicitationResult> {
+		let inputs = interaction
+			.data
+			.components
+			.iter()
+			.flat_map(|row| &row.components)
+			.filter_map(|component| match component {
+				ActionRowComponent::InputText(input) => Some((input.custom_id.as_str(), input.value.as_deref().unwrap_or_default())),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+
+		let mut content = serde_json::Map::new();
+		let mut errors = Vec::new();
+		for (index, field) in fields.iter().enumerate() {
+			let input = inputs
+				.iter()
+				.find(|(custom_id, _)| *custom_id == index.to_string())
+				.map(|(_, value)| value.trim())
+				.unwrap_or_default();
+
+			if input.is_empty() {
+				if field.required {
+					errors.push(format!("{} is required", field.label));
+				}
+				continue;
+			}
+
+			match field.parse(input) {
+				Ok(value) => {
+					content.insert(field.name.clone(), value);
+				},
+				Err(err) => errors.push(err),
+			}
+		}
+
+		let response = if errors.is_empty() {
+			CreateInteractionResponse::Acknowledge
+		} else {
+			CreateInteractionResponse::Message(
+				CreateInteractionResponseMessage::new()
+					.ephemeral(true)
+					.content(format!("{}. Please try again.", errors.join(", "))),
+			)
+		};
+
+		if let Err(err) = interaction.create_response(&self.ctx, response).await {
+			debug!("Failed to respond to elicitation modal: {}", err);
+		}
+
+		errors
+			.is_empty()
+			.then(|| result(ElicitationAction::Accept, Some(Value::Object(content))))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn schema(value: Value) -> JsonObject {
+		match value {
+			Value::Object(schema) => schema,
+			_ => panic!("schema must be an object"),
+		}
+	}
+
+	#[test]
+	fn test_parse_schema() {
+		let fields = parse_schema(&schema(json!({
+			"type": "object",
+			"properties": {
+				"age": { "type": "integer", "title": "Your age" },
+				"color": { "type": "string", "enum": ["red", "green"] },
+				"name": { "type": "string", "description": "Your full name" },
+				"subscribe": { "type": "boolean" },
+				"weight": { "type": "number" }
+			},
+			"required": ["name"]
+		})))
+		.unwrap();
+
+		assert_eq!(fields.len(), 5);
+		assert_eq!(fields[0].label, "Your age");
+		assert_eq!(fields[0].kind, FieldKind::Integer);
+		assert_eq!(
+			fields[1].kind,
+			FieldKind::Choice(vec!["red".to_string(), "green".to_string()])
+		);
+		assert_eq!(fields[2].kind, FieldKind::Text);
+		assert_eq!(fields[2].placeholder(), Some("Your full name".to_string()));
+		assert!(fields[2].required);
+		assert!(!fields[3].required);
+		assert_eq!(fields[3].kind, FieldKind::Boolean);
+		assert_eq!(fields[4].kind, FieldKind::Number);
+
+		assert!(parse_schema(&schema(json!({ "properties": {} }))).unwrap().is_empty());
+		assert!(parse_schema(&schema(json!({ "properties": { "list": { "type": "array" } } }))).is_err());
+	}
+
+	#[test]
+	fn test_parse_field_input() {
+		let field = |kind| Field {
+			name: "field".to_string(),
+			label: "Field".to_string(),
+			description: None,
+			kind,
+			required: true,
+		};
+
+		assert_eq!(field(FieldKind::Text).parse(" hello "), Ok(json!("hello")));
+		assert_eq!(field(FieldKind::Integer).parse("42"), Ok(json!(42)));
+		assert!(field(FieldKind::Integer).parse("4.2").is_err());
+		assert_eq!(field(FieldKind::Number).parse("4.2"), Ok(json!(4.2)));
+		assert!(field(FieldKind::Number).parse("four").is_err());
+		assert_eq!(field(FieldKind::Boolean).parse("Yes"), Ok(json!(true)));
+		assert_eq!(field(FieldKind::Boolean).parse("false"), Ok(json!(false)));
+		assert!(field(FieldKind::Boolean).parse("maybe").is_err());
+
+		let choice = field(FieldKind::Choice(vec!["Red".to_string(), "Green".to_string()]));
+		assert_eq!(choice.parse("red"), Ok(json!("Red")));
+		assert_eq!(choice.parse("blue"), Err("Field must be one of: Red, Green".to_string()));
+	}
+
+	#[test]
+	fn test_layout() {
+		let field = |name: &str, kind| Field {
+			name: name.to_string(),
+			label: name.to_string(),
+			description: None,
+			kind,
+			required: false,
+		};
+
+		assert!(matches!(Layout::new(Vec::new()), Some(Layout::Confirm)));
+		assert!(matches!(
+			Layout::new(vec![field("confirm", FieldKind::Boolean)]),
+			Some(Layout::Choice(_, options)) if options.len() == 2
+		));
+		assert!(matches!(
+			Layout::new(vec![field("name", FieldKind::Text)]),
+			Some(Layout::Modal(_))
+		));
+
+		let many_options = (0..30).map(|i| i.to_string()).collect();
+		assert!(matches!(
+			Layout::new(vec![field("choice", FieldKind::Choice(many_options))]),
+			Some(Layout::Modal(_))
+		));
+
+		let too_many_fields = (0..6).map(|i| field(&i.to_string(), FieldKind::Text)).collect();
+		assert!(Layout::new(too_many_fields).is_none());
+	}
+
+	#[test]
+	fn test_response_deadline() {
+		let now = Instant::now();
+		assert_eq!(response_deadline(now, None), now + ELICITATION_TIMEOUT);
+		assert_eq!(
+			response_deadline(now, Some(now + Duration::from_secs(20))),
+			now + Duration::from_secs(20)
+		);
+		assert_eq!(
+			response_deadline(now, Some(now + Duration::from_secs(300))),
+			now + ELICITATION_TIMEOUT
+		);
+	}
+
+	#[test]
+	fn test_call_deadlines() {
+		let now = Instant::now();
+		let calls = CallDeadlines::default();
+		assert_eq!(calls.latest(), None);
+
+		let short = calls.register(now + Duration::from_secs(10));
+		let long = calls.register(now + Duration::from_secs(30));
+		assert_eq!(calls.latest(), Some(now + Duration::from_secs(30)));
+
+		drop(long);
+		assert_eq!(calls.latest(), Some(now + Duration::from_secs(10)));
+		drop(short);
+		assert_eq!(calls.latest(), None);
+	}
+}
@@ -0,0 +1,463 @@
+use std::{
+	collections::HashMap,
+	num::NonZeroU32,
+	ops::Deref,
+	sync::Arc,
+};
+
+use llm::{
+	LLMProvider,
+	chat::ChatMessage,
+};
+use log::debug;
+use rmcp::{
+	ErrorData,
+	model::{
+		Content,
+		CreateMessageRequestParam,
+		CreateMessageResult,
+		RawContent,
+		Role,
+		SamplingMessage,
+	},
+};
+use sea_orm::DatabaseConnection;
+use tokio::sync::Mutex;
+
+use crate::{
+	mcp_config::McpSamplingSettings,
+	rate_limit_config::PathRateLimits,
+	tokenizer::Tokenizer,
+};
+
+/// Sampling requests count against the rate limits like invocations, one unit for every started block of this many
+/// tokens.
+const TOKENS_PER_RATE_LIMIT_UNIT: usize = 1000;
+
+/// Runs completions which MCP servers request via sampling, on behalf of the user who invoked the bot.
+/// Every request is subject to the same rate limits as an invocation by that user, weighted by its tokens.
+pub struct Sampler {
+	llm_client: Arc<dyn LLMProvider + Send + Sync>,
+	model: String,
+	db: Arc<DatabaseConnection>,
+	rate_limits: Arc<Mutex<PathRateLimits>>,
+	tokenizer: Arc<dyn Tokenizer>,
+
+	/// Keys used to evaluate rate limits, identifying the invoking user, channel and guild.
+	rate_limit_context: HashMap<&'static str, String>,
+}
+
+impl Sampler {
+	pub fn new(
+		llm_client: Arc<dyn LLMProvider + Send + Sync>,
+		model: String,
+		db: Arc<DatabaseConnection>,
+		rate_limits: Arc<Mutex<PathRateLimits>>,
+		tokenizer: Arc<dyn Tokenizer>,
+		rate_limit_context: HashMap<&'static str, String>,
+	) -> Self {
+		Self {
+			llm_client,
+			model,
+			db,
+			rate_limits,
+			tokenizer,
+			rate_limit_context,
+		}
+	}
+
+	/// Checks the rate limits for a sampling request of the given server, which is expected to use `tokens` tokens.
+	/// Besides the limits of the invoking user, routes using the `mcp_server` key allow limiting individual servers.
+	async fn check_rate_limit(&self, server_name: &str, tokens: usize) -> Result<bool, ErrorData> {
+		let mut context = self.rate_limit_context.clone();
+		context.insert("mcp_server", server_name.to_string());
+
+		let limits = self.rate_limits.lock().await;
+		limits
+			.check_route_with_amount(&context, &self.db, rate_limit_amount(tokens))
+			.await
+			.map_err(|err| ErrorData::internal_error(format!("failed to check rate limits: {}", err), None))
+	}
+
+	/// Estimates the tokens of a request before running it, assuming the completion uses up its maximum length, but no
+	/// more than `max_completion_tokens`.
+	fn estimate_tokens(&self, params: &CreateMessageRequestParam, max_completion_tokens: usize) -> usize {
+		let system_prompt = params
+			.system_prompt
+			.as_deref()
+			.map_or(0, |prompt| self.tokenizer.count_tokens(prompt));
+		let messages = params
+			.messages
+			.iter()
+			.map(|message| match message.content.deref() {
+				RawContent::Text(text) => self.tokenizer.count_tokens(&text.text),
+				_ => 0,
+			})
+			.sum::<usize>();
+
+		system_prompt + messages + (params.max_tokens as usize).min(max_completion_tokens)
+	}
+
+	/// Runs the completion and returns it together with the number of tokens it used.
+	async fn complete(&self, params: CreateMessageRequestParam) -> Result<(CreateMessageResult, usize), ErrorData> {
+		let mut messages = Vec::new();
+
+		// system prompts are passed like our own preprompt, as first user message
+		if let Some(system_prompt) = params.system_prompt.filter(|prompt| !prompt.trim().is_empty()) {
+			messages.push(ChatMessage::user().content(system_prompt).build());
+		}
+
+		for message in params.messages {
+			let text = match message.content.deref() {
+				RawContent::Text(text) => text.text.clone(),
+				_ => {
+					return Err(ErrorData::invalid_params(
+						"only text content is supported in sampling requests",
+						None,
+					));
+				},
+			};
+
+			let builder = match message.role {
+				Role::User => ChatMessage::user(),
+				Role::Assistant => ChatMessage::assistant(),
+			};
+			messages.push(builder.content(text).build());
+		}
+
+		let response = self
+			.llm_client
+			.chat(&messages)
+			.await
+			.map_err(|err| ErrorData::internal_error(format!("completion request failed: {}", err), None))?;
+
+		let text = response
+			.text()
+			.ok_or_else(|| ErrorData::internal_error("completion has no content", None))?;
+
+		// not all providers report usage, so we fall back to our own estimate
+		let tokens = match response.usage() {
+			Some(usage) => usage.total_tokens as usize,
+			None => {
+				let prompt = messages.iter().map(|message| message.content.as_str()).collect::<String>();
+				self.tokenizer.count_tokens(&prompt) + self.tokenizer.count_tokens(&text)
+			},
+		};
+
+		let result = CreateMessageResult {
+			model: self.model.clone(),
+			stop_reason: Some(CreateMessageResult::STOP_REASON_END_TURN.to_string()),
+			message: SamplingMessage {
+				role: Role::Assistant,
+				content: Content::text(text),
+			},
+		};
+
+		Ok((result, tokens))
+	}
+}
+
+/// Sampling requests and tokens a server used during a single invocation.
+#[derive(Debug, Default)]
+struct SamplingUsage {
+	requests: u32,
+	tokens: usize,
+}
+
+/// Sampling on behalf of a single server, limited by the quota configured for that server.
+pub struct SamplingSession {
+	sampler: Arc<Sampler>,
+	server_name: String,
+	settings: McpSamplingSettings,
+	usage: Mutex<SamplingUsage>,
+}
+
+impl SamplingSession {
+	pub fn new(sampler: Arc<Sampler>, server_name: String, settings: McpSamplingSettings) -> Self {
+		Self {
+			sampler,
+			server_name,
+			settings,
+			usage: Mutex::new(SamplingUsage::default()),
+		}
+	}
+
+	pub async fn create_message(&self, params: CreateMessageRequestParam) -> Result<CreateMessageResult, ErrorData> {
+		// hold the lock for the entire request, so concurrent requests can't exceed the quota together
+		let mut usage = self.usage.lock().await;
+
+		if usage.requests >= self.settings.max_requests() || usage.tokens >= self.settings.max_tokens() {
+			debug!("MCP server '{}' exhausted its sampling quota", self.server_name);
+			return Err(ErrorData::invalid_request(
+				"sampling quota for this invocation is exhausted",
+				None,
+			));
+		}
+
+		// the tokens are only known afterwards, so the rate limits are charged with an estimate
+		let estimate = self.sampler.estimate_tokens(&params, self.settings.max_tokens());
+		if !self.sampler.check_rate_limit(&self.server_name, estimate).await? {
+			debug!("Sampling request of MCP server '{}' was rate limited", self.server_name);
+			return Err(ErrorData::invalid_request("sampling request was rate limited", None));
+		}
+
+		let (result, tokens) = self.sampler.complete(params).await?;
+
+		usage.requests += 1;
+		usage.tokens += tokens;
+		debug!(
+			"MCP server '{}' used {} tokens for sampling, {} in total",
+			self.server_name, tokens, usage.tokens
+		);
+
+		Ok(result)
+	}
+}
+
+/// Converts tokens into units of the rate limits, every request costs at least one unit.
+fn rate_limit_amount(tokens: usize) -> NonZeroU32 {
+	let units = tokens.div_ceil(TOKENS_PER_RATE_LIMIT_UNIT).clamp(1, u32::MAX as usize);
+	NonZeroU32::new(units as u32).unwrap_or(NonZeroU32::MIN)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		fmt,
+		sync::atomic::{
+			AtomicUsize,
+			Ordering,
+		},
+	};
+
+	use async_trait::async_trait;
+	use chrono::Utc;
+	use entity::rate_limit;
+	use llm::{
+		ToolCall,
+		chat::{
+			ChatProvider,
+			ChatResponse,
+			Tool,
+		},
+		completion::{
+			CompletionProvider,
+			CompletionRequest,
+			CompletionResponse,
+		},
+		embedding::EmbeddingProvider,
+		error::LLMError,
+		models::ModelsProvider,
+		stt::SpeechToTextProvider,
+		tts::TextToSpeechProvider,
+	};
+	use regex::Regex;
+	use sea_orm::{
+		DatabaseBackend,
+		MockDatabase,
+		MockExecResult,
+	};
+
+	use super::*;
+	use crate::{
+		rate_limit_config::RateLimitConfig,
+		tokenizer::Heuristic,
+	};
+
+	/// Answers every request with the same text, counting the requests.
+	#[derive(Default)]
+	struct MockProvider {
+		requests: AtomicUsize,
+	}
+
+	#[derive(Debug)]
+	struct MockResponse;
+
+	impl fmt::Display for MockResponse {
+		fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+			write!(f, "{}", "answer ".repeat(60))
+		}
+	}
+
+	impl ChatResponse for MockResponse {
+		fn text(&self) -> Option<String> {
+			Some(self.to_string())
+		}
+
+		fn tool_calls(&self) -> Option<Vec<ToolCall>> {
+			None
+		}
+	}
+
+	#[async_trait]
+	impl ChatProvider for MockProvider {
+		async fn chat_with_tools(
+			&self,
+			_messages: &[ChatMessage],
+			_tools: Option<&[Tool]>,
+		) -> Result<Box<dyn ChatResponse>, LLMError> {
+			self.requests.fetch_add(1, Ordering::SeqCst);
+			Ok(Box::new(MockResponse))
+		}
+	}
+
+	#[async_trait]
+	impl CompletionProvider for MockProvider {
+		async fn complete(&self, _request: &CompletionRequest) -> Result<CompletionResponse, LLMError> {
+			Err(LLMError::Generic("not supported".to_string()))
+		}
+	}
+
+	#[async_trait]
+	impl EmbeddingProvider for MockProvider {
+		async fn embed(&self, _input: Vec<String>) -> Result<Vec<Vec<f32>>, LLMError> {
+			Err(LLMError::Generic("not supported".to_string()))
+		}
+	}
+
+	#[async_trait]
+	impl SpeechToTextProvider for MockProvider {
+		async fn transcribe(&self, _audio: Vec<u8>) -> Result<String, LLMError> {
+			Err(LLMError::Generic("not supported".to_string()))
+		}
+	}
+
+	#[async_trait]
+	impl TextToSpeechProvider for MockProvider {}
+
+	#[async_trait]
+	impl ModelsProvider for MockProvider {}
+
+	impl LLMProvider for MockProvider {}
+
+	/// Session of server `test` with the given quota, on behalf of user 1, limited to 10 units per hour.
+	fn session(provider: Arc<MockProvider>, db: DatabaseConnection, max_requests: u32, max_tokens: usize) -> SamplingSession {
+		let config = toml::from_str::<RateLimitConfig>(
+			r#"
+			[limits]
+			"user/{user_id}" = [
+				{ hours = 1, quota = 10 },
+			]
+		"#,
+		)
+		.unwrap();
+
+		let sampler = Sampler::new(
+			provider,
+			"model".to_string(),
+			Arc::new(db),
+			Arc::new(Mutex::new((&config).into())),
+			Arc::new(Heuristic),
+			HashMap::from([("user_id", "1".to_string()), ("channel_id", "2".to_string())]),
+		);
+		let settings = McpSamplingSettings {
+			max_requests: Some(max_requests),
+			max_tokens: Some(max_tokens),
+		};
+
+		SamplingSession::new(Arc::new(sampler), "test".to_string(), settings)
+	}
+
+	fn request(max_tokens: u32) -> CreateMessageRequestParam {
+		CreateMessageRequestParam {
+			messages: vec![SamplingMessage {
+				role: Role::User,
+				content: Content::text("summarize this"),
+			}],
+			model_preferences: None,
+			system_prompt: None,
+			include_context: None,
+			temperature: None,
+			max_tokens,
+			stop_sequences: None,
+			metadata: None,
+		}
+	}
+
+	/// Database without any rate limit state, which accepts the given number of state inserts.
+	fn empty_db(requests: usize) -> MockDatabase {
+		let inserted = rate_limit::Model {
+			path: "user/1".to_string(),
+			period: 3_600_000,
+			state: 0,
+		};
+
+		let mut db = MockDatabase::new(DatabaseBackend::MySql);
+		for _ in 0..requests {
+			// lookup of the state, followed by the insert, which reads back the inserted row
+			db = db
+				.append_query_results([Vec::new(), vec![inserted.clone()]])
+				.append_exec_results([MockExecResult {
+					last_insert_id: 0,
+					rows_affected: 1,
+				}]);
+		}
+		db
+	}
+
+	#[test]
+	fn test_rate_limit_amount() {
+		assert_eq!(rate_limit_amount(0).get(), 1);
+		assert_eq!(rate_limit_amount(1000).get(), 1);
+		assert_eq!(rate_limit_amount(1001).get(), 2);
+	}
+
+	#[tokio::test]
+	async fn test_request_quota() {
+		let provider = Arc::new(MockProvider::default());
+		let session = session(provider.clone(), empty_db(2).into_connection(), 2, 10_000);
+
+		assert!(session.create_message(request(100)).await.is_ok());
+		assert!(session.create_message(request(100)).await.is_ok());
+		assert!(session.create_message(request(100)).await.is_err());
+		assert_eq!(provider.requests.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn test_token_quota() {
+		let provider = Arc::new(MockProvider::default());
+		let session = session(provider.clone(), empty_db(1).into_connection(), 10, 50);
+
+		// the first request already uses up all tokens, since the answer alone is 70 tokens
+		assert!(session.create_message(request(100)).await.is_ok());
+		assert!(session.create_message(request(100)).await.is_err());
+		assert_eq!(provider.requests.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn test_rate_limited() {
+		let provider = Arc::new(MockProvider::default());
+		let exceeded = rate_limit::Model {
+			path: "user/1".to_string(),
+			period: 3_600_000,
+			state: (Utc::now() + chrono::Duration::days(1)).timestamp_millis() as u64,
+		};
+		let db = MockDatabase::new(DatabaseBackend::MySql).append_query_results([vec![exceeded]]);
+		let session = session(provider.clone(), db.into_connection(), 10, 10_000);
+
+		assert!(session.create_message(request(100)).await.is_err());
+		assert_eq!(provider.requests.load(Ordering::SeqCst), 0);
+	}
+
+	#[tokio::test]
+	async fn test_rate_limit_counts_tokens() {
+		let provider = Arc::new(MockProvider::default());
+		let session = session(provider, empty_db(1).into_connection(), 10, 10_000);
+
+		// a completion of up to 3000 tokens costs 3 of the 10 units per hour
+		let now = Utc::now().timestamp_millis() as u64;
+		session.create_message(request(2990)).await.unwrap();
+
+		// the stored time of burst is 9 units of tolerance plus 3 units of usage in the future, 6 minutes per unit
+		let db = session.sampler.db.clone();
+		drop(session);
+		let log = format!("{:?}", Arc::into_inner(db).unwrap().into_transaction_log());
+		let state = Regex::new(r"BigUnsigned\(Some\((\d{13})\)\)")
+			.unwrap()
+			.captures(&log)
+			.and_then(|captures| captures[1].parse::<u64>().ok())
+			.unwrap();
+		let minutes = (state - now) / 60_000;
+		assert!((71..=72).contains(&minutes), "time of burst is {} minutes ahead", minutes);
+	}
+}
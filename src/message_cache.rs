@@ -1,83 +1,149 @@
-use entity::message_cache;
-use log::debug;
+use std::{
+	num::NonZeroUsize,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::Duration,
+};
+
+use chrono::Utc;
+use entity::{
+	message_cache,
+	user,
+};
+use log::{
+	debug,
+	error,
+	info,
+};
+use lru::LruCache;
 use miette::{
 	IntoDiagnostic,
 	Result,
 	WrapErr,
 };
 use poise::serenity_prelude::{
+	CacheHttp,
 	ChannelId,
+	GuildId,
 	Message,
 	MessageId,
+	MessageReference,
+	MessageType,
 	UserId,
 };
 use sea_orm::{
 	ActiveModelTrait,
 	ActiveValue::Set,
 	ColumnTrait,
-	ConnectionTrait,
+	DatabaseConnection,
 	EntityTrait,
 	QueryFilter,
 };
 
-use crate::{
-	user_from_db_or_create,
-	Context,
-};
+use crate::user_from_db_or_create;
+
+/// How often expired entries are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-/// Database backed message cache. Used to minimize the amount of requests to the Discord API. Once a message has been
-/// fetched, it is stored in the cache for a certain amount of time. On message updates or deletions, the cache needs to
-/// be invalidated.
-pub struct MessageCache<'a, C> {
-	db: &'a C,
+/// Message cache used to minimize the amount of requests to the Discord API. Recent messages are kept in memory, in
+/// front of a database table which outlives restarts. Messages are added as they are received from the gateway or
+/// fetched from Discord, and purged from the table once they are older than the retention period. On message updates or
+/// deletions, the cache needs to be invalidated.
+pub struct MessageCache {
+	db: Arc<DatabaseConnection>,
+
+	/// Most recently used messages, as received from Discord.
+	recent: Mutex<LruCache<MessageId, Message>>,
 }
 
-impl<'a, C: ConnectionTrait> MessageCache<'a, C> {
-	/// Creates a new handle to the message cache.
-	pub fn new(db: &'a C) -> Self {
+impl MessageCache {
+	/// Creates a new message cache, keeping up to `capacity` messages in memory.
+	pub fn new(db: Arc<DatabaseConnection>, capacity: NonZeroUsize) -> Self {
 		Self {
 			db,
+			recent: Mutex::new(LruCache::new(capacity)),
+		}
+	}
+
+	/// Adds a message to the cache, along with the message it replied to. Messages of users who opted out are not cached.
+	pub async fn add(&self, message: &Message) -> Result<()> {
+		// replies contain the message they replied to, which is the next message we need when following a reply chain
+		if let Some(replied_message) = &message.referenced_message {
+			self.add_single(replied_message).await?;
 		}
+
+		self.add_single(message).await
 	}
 
-	pub async fn _add(&self, message: &Message) -> Result<message_cache::Model> {
+	async fn add_single(&self, message: &Message) -> Result<()> {
 		// ensure author is also present in database
-		let _ = user_from_db_or_create(self.db, &message.author).await?;
-
-		let existing = {
-			message_cache::Entity::find()
-				.filter(message_cache::Column::DiscordMessageId.eq(message.id.get()))
-				.one(self.db)
-				.await
-				.into_diagnostic()
-				.wrap_err("failed to fetch message cache entry")?
-		};
+		let author = user_from_db_or_create(self.db.as_ref(), &message.author).await?;
+		if author.opt_out_since.is_some() {
+			return Ok(());
+		}
+
+		self.recent.lock().unwrap().put(message.id, message.clone());
 
-		if let Some(existing) = existing {
+		if self.find(message.id).await?.is_some() {
 			debug!("message {} already in cache", message.id);
-			return Ok(existing);
+			return Ok(());
 		}
 
+		// the replied message may not be cached, the reference is only used to look it up
+		let replied_id = match &message.message_reference {
+			Some(reference) if message.kind == MessageType::InlineReply => reference.message_id.map(MessageId::get),
+			_ => None,
+		};
+
 		let entry = message_cache::ActiveModel {
 			discord_message_id: Set(message.id.get()),
+			discord_channel_id: Set(message.channel_id.get()),
+			discord_guild_id: Set(message.guild_id.map(GuildId::get)),
 			discord_user_id: Set(message.author.id.get()),
+			ref_discord_message_id: Set(replied_id),
 			content: Set(message.content.clone()),
+			timestamp: Set(*message.timestamp),
+			edited_timestamp: Set(message.edited_timestamp.map(|timestamp| *timestamp)),
+			attachments: Set(
+				serde_json::to_string(&message.attachments)
+					.into_diagnostic()
+					.wrap_err("failed to serialize attachments")?,
+			),
+			embeds: Set(
+				serde_json::to_string(&message.embeds)
+					.into_diagnostic()
+					.wrap_err("failed to serialize embeds")?,
+			),
 			..Default::default()
 		};
 
-		let model = entry
-			.insert(self.db)
+		entry
+			.insert(self.db.as_ref())
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to insert message cache entry")?;
 
-		Ok(model)
+		Ok(())
 	}
 
 	pub async fn delete_from_user(&self, user_id: UserId) -> Result<()> {
+		{
+			let mut recent = self.recent.lock().unwrap();
+			let message_ids = recent
+				.iter()
+				.filter(|(_, message)| message.author.id == user_id)
+				.map(|(message_id, _)| *message_id)
+				.collect::<Vec<_>>();
+			for message_id in message_ids {
+				recent.pop(&message_id);
+			}
+		}
+
 		let _ = entity::prelude::MessageCache::delete_many()
 			.filter(message_cache::Column::DiscordUserId.eq(user_id.get()))
-			.exec(self.db)
+			.exec(self.db.as_ref())
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to delete message cache entry")?;
@@ -86,42 +152,58 @@ impl<'a, C: ConnectionTrait> MessageCache<'a, C> {
 	}
 
 	/// Fetches a message from the cache. If the message is not in the cache, it will be loaded from the Discord API.
-	pub async fn _fetch(
-		&self,
-		channel_id: ChannelId,
-		message_id: MessageId,
-		ctx: &Context<'_>,
-	) -> Result<Option<message_cache::Model>> {
+	pub async fn fetch(&self, http: impl CacheHttp, channel_id: ChannelId, message_id: MessageId) -> Result<Message> {
+		if let Some(message) = self.recent.lock().unwrap().get(&message_id) {
+			return Ok(message.clone());
+		}
+
 		let entry = entity::prelude::MessageCache::find()
 			.filter(message_cache::Column::DiscordMessageId.eq(message_id.get()))
-			.one(self.db)
+			.find_also_related(entity::prelude::User)
+			.one(self.db.as_ref())
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to fetch message cache entry")?;
 
+		if let Some((entry, Some(author))) = entry {
+			let message = to_message(entry, author);
+			self.recent.lock().unwrap().put(message_id, message.clone());
+			return Ok(message);
+		}
+
 		// if not in cache, we fetch from discord and add to cache
-		let entry = match entry {
-			Some(entry) => entry,
-			None => {
-				debug!("message {} not in cache, fetching from discord", message_id);
-				let discord_message = ctx
-					.http()
-					.get_message(channel_id, message_id)
-					.await
-					.into_diagnostic()
-					.wrap_err("failed to fetch message from discord")?;
-				self._add(&discord_message).await?
-			},
-		};
+		debug!("message {} not in cache, fetching from discord", message_id);
+		let message = http
+			.http()
+			.get_message(channel_id, message_id)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch message from discord")?;
+
+		// the message was fetched successfully, failing to cache it doesn't change that
+		if let Err(err) = self.add(&message).await {
+			debug!("Failed to cache message {}: {:?}", message_id, err);
+		}
 
-		Ok(Some(entry))
+		Ok(message)
+	}
+
+	async fn find(&self, message_id: MessageId) -> Result<Option<message_cache::Model>> {
+		message_cache::Entity::find()
+			.filter(message_cache::Column::DiscordMessageId.eq(message_id.get()))
+			.one(self.db.as_ref())
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch message cache entry")
 	}
 
 	/// Invalidates a message in the cache. This is used when a message is updated or deleted.
 	pub async fn invalidate(&self, message_id: &MessageId) -> Result<()> {
+		self.recent.lock().unwrap().pop(message_id);
+
 		entity::prelude::MessageCache::delete_many()
 			.filter(message_cache::Column::DiscordMessageId.eq(message_id.get()))
-			.exec(self.db)
+			.exec(self.db.as_ref())
 			.await
 			.into_diagnostic()
 			.wrap_err("failed to delete message cache entry")?;
@@ -129,3 +211,119 @@ impl<'a, C: ConnectionTrait> MessageCache<'a, C> {
 		Ok(())
 	}
 }
+
+/// Deletes all entries of messages sent before the retention period. Returns the number of deleted entries.
+async fn purge(db: &DatabaseConnection, retention: Duration) -> Result<u64> {
+	let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+	let cutoff = Utc::now()
+		.checked_sub_signed(retention)
+		.unwrap_or(chrono::DateTime::<Utc>::MIN_UTC);
+
+	let result = entity::prelude::MessageCache::delete_many()
+		.filter(message_cache::Column::Timestamp.lt(cutoff))
+		.exec(db)
+		.await
+		.into_diagnostic()
+		.wrap_err("failed to purge message cache entries")?;
+
+	Ok(result.rows_affected)
+}
+
+/// Periodically purges entries older than the retention period. Runs forever, so it should be spawned as a task.
+pub async fn purge_periodically(db: Arc<DatabaseConnection>, retention: Duration) {
+	let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+	loop {
+		interval.tick().await;
+
+		match purge(db.as_ref(), retention).await {
+			Ok(0) => {},
+			Ok(purged) => info!("Purged {} expired message cache entries", purged),
+			Err(err) => error!("Failed to purge message cache entries: {:?}", err),
+		}
+	}
+}
+
+/// Restores a message from the database. The table only holds what is needed to build the context, everything else is
+/// left at its default.
+fn to_message(entry: message_cache::Model, author: user::Model) -> Message {
+	let channel_id = ChannelId::new(entry.discord_channel_id);
+
+	let mut message = Message::default();
+	message.id = MessageId::new(entry.discord_message_id);
+	message.channel_id = channel_id;
+	message.guild_id = entry.discord_guild_id.map(GuildId::new);
+	message.timestamp = entry.timestamp.into();
+	message.edited_timestamp = entry.edited_timestamp.map(Into::into);
+	message.author.id = UserId::new(author.discord_user_id);
+	message.author.name = author.username;
+	message.content = entry.content;
+	// entries written by an older version may not parse, losing attachments is better than losing the message
+	message.attachments = serde_json::from_str(&entry.attachments).unwrap_or_default();
+	message.embeds = serde_json::from_str(&entry.embeds).unwrap_or_default();
+	if let Some(replied_id) = entry.ref_discord_message_id {
+		message.kind = MessageType::InlineReply;
+		message.message_reference = Some(MessageReference::from((channel_id, MessageId::new(replied_id))));
+	}
+
+	message
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::DateTime;
+
+	use super::*;
+	use crate::invocation_builder::InvocationBuilder;
+
+	#[test]
+	fn test_to_message() {
+		let entry = message_cache::Model {
+			id: 1,
+			discord_message_id: 175928847299117063,
+			discord_channel_id: 7,
+			discord_guild_id: Some(3),
+			discord_user_id: 42,
+			ref_discord_message_id: Some(175928847299117062),
+			content: "hello".to_string(),
+			timestamp: DateTime::from_timestamp(1_462_015_105, 0).unwrap(),
+			edited_timestamp: None,
+			attachments: "[]".to_string(),
+			embeds: "not json".to_string(),
+		};
+		let author = user::Model {
+			id: 1,
+			uuid: vec![0; 16],
+			discord_user_id: 42,
+			username: "alice".to_string(),
+			opt_out_since: None,
+		};
+
+		let message = to_message(entry, author);
+		assert_eq!(message.id, MessageId::new(175928847299117063));
+		assert_eq!(message.channel_id, ChannelId::new(7));
+		assert_eq!(message.guild_id, Some(GuildId::new(3)));
+		assert_eq!(message.author.name, "alice");
+		assert_eq!(message.content, "hello");
+		assert_eq!(message.timestamp.unix_timestamp(), 1_462_015_105);
+		assert!(message.edited_timestamp.is_none());
+		assert!(message.embeds.is_empty());
+		assert_eq!(message.kind, MessageType::InlineReply);
+		let reference = message.message_reference.as_ref().unwrap();
+		assert_eq!(reference.channel_id, ChannelId::new(7));
+		assert_eq!(reference.message_id, Some(MessageId::new(175928847299117062)));
+
+		// the reply is still recognized as one when building the conversation
+		let mut replied = Message::default();
+		replied.id = MessageId::new(175928847299117062);
+		replied.channel_id = ChannelId::new(7);
+		replied.content = "hi".to_string();
+		let mut builder = InvocationBuilder::new(UserId::new(1), "bot");
+		builder.add_message(&replied);
+		builder.add_message(&message);
+		assert_eq!(
+			builder.build_llm_messages()[2].content,
+			"[SYSTEM: message no. 2, reply to message no. 1]"
+		);
+	}
+}
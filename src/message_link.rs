@@ -0,0 +1,87 @@
+use lazy_static::lazy_static;
+use poise::serenity_prelude::{
+	ChannelId,
+	GuildId,
+	MessageId,
+};
+use regex::Regex;
+
+lazy_static! {
+	static ref MESSAGE_LINK_REGEX: Regex = Regex::new(
+		r"https://(?:(?:ptb|canary)\.)?discord(?:app)?\.com/channels/(?P<guild>\d+|@me)/(?P<channel>\d+)/(?P<message>\d+)"
+	)
+	.unwrap();
+}
+
+/// Location of a message, as given by a message link. The guild is `None` for direct messages.
+pub type MessageLink = (Option<GuildId>, ChannelId, MessageId);
+
+/// Extracts guild, channel and message from a message link.
+pub fn parse_message_link(link: &str) -> Option<MessageLink> {
+	MESSAGE_LINK_REGEX
+		.captures(link.trim())
+		.and_then(|captures| from_captures(&captures))
+}
+
+/// Finds all message links in a text, in order of appearance and without duplicates.
+pub fn find_message_links(text: &str) -> Vec<MessageLink> {
+	let mut links = Vec::new();
+	for link in MESSAGE_LINK_REGEX
+		.captures_iter(text)
+		.filter_map(|captures| from_captures(&captures))
+	{
+		if !links.contains(&link) {
+			links.push(link);
+		}
+	}
+
+	links
+}
+
+fn from_captures(captures: &regex::Captures) -> Option<MessageLink> {
+	// snowflakes are never zero, creating ids from zero panics
+	let snowflake = |name: &str| captures.name(name)?.as_str().parse::<u64>().ok().filter(|id| *id != 0);
+
+	let guild_id = match &captures["guild"] {
+		"@me" => None,
+		_ => Some(GuildId::new(snowflake("guild")?)),
+	};
+
+	Some((
+		guild_id,
+		ChannelId::new(snowflake("channel")?),
+		MessageId::new(snowflake("message")?),
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_message_link() {
+		assert_eq!(
+			parse_message_link("https://discord.com/channels/1/2/3"),
+			Some((Some(GuildId::new(1)), ChannelId::new(2), MessageId::new(3)))
+		);
+		assert_eq!(
+			parse_message_link("https://canary.discordapp.com/channels/@me/2/3"),
+			Some((None, ChannelId::new(2), MessageId::new(3)))
+		);
+		assert_eq!(parse_message_link("https://discord.com/channels/1/2"), None);
+		assert_eq!(parse_message_link("https://example.com/channels/1/2/3"), None);
+		assert_eq!(parse_message_link("https://discord.com/channels/0/2/3"), None);
+	}
+
+	#[test]
+	fn test_find_message_links() {
+		let text = "compare https://discord.com/channels/1/2/3 with <https://discord.com/channels/1/2/4>, and again \
+		            https://discord.com/channels/1/2/3 but not https://discord.com/channels/1/0/5";
+
+		assert_eq!(find_message_links(text), vec![
+			(Some(GuildId::new(1)), ChannelId::new(2), MessageId::new(3)),
+			(Some(GuildId::new(1)), ChannelId::new(2), MessageId::new(4)),
+		]);
+		assert_eq!(find_message_links("no links here"), Vec::new());
+	}
+}
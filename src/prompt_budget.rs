@@ -0,0 +1,289 @@
+use std::sync::Arc;
+
+use llm::chat::{
+	ChatMessage,
+	MessageType,
+	Tool as LlmTool,
+};
+use log::{
+	debug,
+	warn,
+};
+use serde_json::Value;
+
+use crate::{
+	mcp::truncate_result,
+	tokenizer::Tokenizer,
+};
+
+/// Context window assumed for models we don't know, small enough for all current models.
+const DEFAULT_CONTEXT_WINDOW: usize = 8_192;
+
+/// Context windows of model families, matched by prefix in order, so more specific names have to come first.
+const CONTEXT_WINDOWS: [(&str, usize); 12] = [
+	("gpt-4.1", 1_047_576),
+	("gpt-5", 400_000),
+	("gpt-4o", 128_000),
+	("chatgpt-4o", 128_000),
+	("gpt-4-turbo", 128_000),
+	("gpt-4", 8_192),
+	("gpt-3.5", 16_385),
+	("o1", 200_000),
+	("o3", 200_000),
+	("o4", 200_000),
+	("claude", 200_000),
+	("gemini", 1_048_576),
+];
+
+/// Images are not part of the text, providers bill them at a few hundred tokens, depending on their size.
+const IMAGE_TOKENS: usize = 800;
+
+/// Tool results are never shrunk below this size, at that point the result is mostly the truncation marker.
+const MIN_TOOL_RESULT_TOKENS: usize = 100;
+
+/// Room left for the marker added to truncated tool results, so they end up within the target size.
+const TRUNCATION_MARKER_LENGTH: usize = 50;
+
+/// Keeps the whole prompt within the context window of the model, leaving room for the answer.
+/// The limits of the context extraction only know about Discord messages, this also accounts for the preprompt, tool
+/// definitions and the results of tool calls, which grow with every iteration.
+#[derive(Debug)]
+pub struct PromptBudget {
+	tokenizer: Arc<dyn Tokenizer>,
+
+	/// Number of tokens the model can process in a single request, prompt and answer combined.
+	context_window: usize,
+
+	/// Number of tokens kept free for the answer.
+	answer_reserve: usize,
+}
+
+impl PromptBudget {
+	/// Creates the budget for a model. Unless a context window is given, it is looked up by the model family.
+	pub fn new(tokenizer: Arc<dyn Tokenizer>, model: &str, context_window: Option<usize>, answer_reserve: usize) -> Self {
+		let context_window = context_window.unwrap_or_else(|| context_window_for_model(model));
+
+		Self {
+			tokenizer,
+			context_window,
+			answer_reserve,
+		}
+	}
+
+	/// Number of tokens the prompt may take up.
+	fn available(&self) -> usize {
+		self.context_window.saturating_sub(self.answer_reserve)
+	}
+
+	/// Trims the conversation until it fits into the budget, together with the tools offered to the model.
+	///
+	/// The conversation starts with the preprompt, followed by a header and the message itself for every message of the
+	/// context, up to `context_end`. The oldest messages of the context are dropped first, always keeping the message the
+	/// bot was invoked with. If that is not enough, the largest tool results are shrunk.
+	pub fn fit(&self, conversation: &mut Vec<ChatMessage>, context_end: &mut usize, tools: &[LlmTool]) {
+		let available = self.available();
+		let tool_tokens = serde_json::to_string(tools)
+			.map(|tools| self.tokenizer.count_tokens(&tools))
+			.unwrap_or_default();
+		let mut tokens = tool_tokens + conversation.iter().map(|message| self.count_message(message)).sum::<usize>();
+		if tokens <= available {
+			return;
+		}
+
+		// older messages are the least relevant, the last one of the context is the one the bot was invoked with
+		let mut dropped = 0;
+		while tokens > available && *context_end > 3 {
+			for message in conversation.drain(1..3) {
+				tokens -= self.count_message(&message);
+			}
+			*context_end -= 2;
+			dropped += 1;
+		}
+		if dropped > 0 {
+			debug!(
+				"Dropped {} messages from the context to fit the prompt into {} tokens",
+				dropped, available
+			);
+		}
+
+		// the largest results are shrunk first, since they are most likely to contain things the model doesn't need
+		while tokens > available {
+			let Some((index, call, result_tokens)) = self.largest_tool_result(conversation) else {
+				break;
+			};
+
+			let target = result_tokens.saturating_sub(tokens - available).max(MIN_TOOL_RESULT_TOKENS);
+			let before = self.count_message(&conversation[index]);
+			if let MessageType::ToolResult(results) = &mut conversation[index].message_type {
+				let arguments = &mut results[call].function.arguments;
+				*arguments = shrink_result(arguments, result_tokens, target);
+			}
+			let after = self.count_message(&conversation[index]);
+
+			// shrinking is based on an estimate, so we stop once it no longer helps
+			if after >= before {
+				break;
+			}
+			debug!("Shrunk a tool result by {} tokens to fit the prompt", before - after);
+			tokens -= before - after;
+		}
+
+		if tokens > available {
+			warn!(
+				"Prompt takes up {} tokens after trimming, exceeding the {} tokens available in the context window",
+				tokens, available
+			);
+		}
+	}
+
+	/// Counts the tokens of a message, including tool calls and their results.
+	fn count_message(&self, message: &ChatMessage) -> usize {
+		let content = self.tokenizer.count_tokens(&message.content);
+		let extra = match &message.message_type {
+			MessageType::ToolUse(calls) | MessageType::ToolResult(calls) => calls
+				.iter()
+				.map(|call| self.tokenizer.count_tokens(&call.function.name) + self.tokenizer.count_tokens(&call.function.arguments))
+				.sum(),
+			MessageType::Image(_) | MessageType::ImageURL(_) => IMAGE_TOKENS,
+			_ => 0,
+		};
+
+		content + extra
+	}
+
+	/// Finds the largest tool result which can still be shrunk, as index of its message, index of the call in the message
+	/// and its size in tokens.
+	fn largest_tool_result(&self, conversation: &[ChatMessage]) -> Option<(usize, usize, usize)> {
+		conversation
+			.iter()
+			.enumerate()
+			.filter_map(|(index, message)| match &message.message_type {
+				MessageType::ToolResult(results) => Some((index, results)),
+				_ => None,
+			})
+			.flat_map(|(index, results)| {
+				results
+					.iter()
+					.enumerate()
+					.map(move |(call, result)| (index, call, self.tokenizer.count_tokens(&result.function.arguments)))
+			})
+			.filter(|(_, _, tokens)| *tokens > MIN_TOOL_RESULT_TOKENS)
+			.max_by_key(|(_, _, tokens)| *tokens)
+	}
+}
+
+/// Looks up the context window of a model family. Provider prefixes like `openai/` are ignored.
+fn context_window_for_model(model: &str) -> usize {
+	let model = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+
+	CONTEXT_WINDOWS
+		.iter()
+		.find(|(prefix, _)| model.starts_with(prefix))
+		.map(|(_, context_window)| *context_window)
+		.unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+/// Shrinks a serialized tool result from `tokens` to roughly `target` tokens, by cutting it proportionally.
+fn shrink_result(result: &str, tokens: usize, target: usize) -> String {
+	let value = serde_json::from_str(result).unwrap_or_else(|_| Value::String(result.to_string()));
+
+	// truncating a structured result turns it into a string, so it has to be measured as one
+	let length = match &value {
+		Value::String(text) => text.chars().count(),
+		other => other.to_string().chars().count(),
+	};
+	let max_size = (length * target / tokens.max(1)).saturating_sub(TRUNCATION_MARKER_LENGTH);
+
+	truncate_result(value, max_size).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use llm::{
+		FunctionCall,
+		ToolCall,
+	};
+
+	use super::*;
+	use crate::tokenizer::Heuristic;
+
+	fn budget(context_window: usize) -> PromptBudget {
+		PromptBudget::new(Arc::new(Heuristic), "some-model", Some(context_window), 100)
+	}
+
+	/// Text of the given number of tokens, as estimated by the heuristic.
+	fn text(tokens: usize) -> String {
+		"abcdef".repeat(tokens)
+	}
+
+	fn conversation(context_messages: usize, tokens: usize) -> Vec<ChatMessage> {
+		let mut conversation = vec![ChatMessage::user().content(text(tokens)).build()];
+		for _ in 0..context_messages {
+			conversation.push(ChatMessage::user().content("[SYSTEM: message]").build());
+			conversation.push(ChatMessage::user().content(text(tokens)).build());
+		}
+		conversation
+	}
+
+	fn tool_result(tokens: usize) -> ChatMessage {
+		ChatMessage::user()
+			.tool_result(vec![ToolCall {
+				id: "call".to_string(),
+				call_type: "function".to_string(),
+				function: FunctionCall {
+					name: "search".to_string(),
+					arguments: Value::String(text(tokens)).to_string(),
+				},
+			}])
+			.build()
+	}
+
+	#[test]
+	fn test_context_window_for_model() {
+		assert_eq!(context_window_for_model("gpt-4o-mini"), 128_000);
+		assert_eq!(context_window_for_model("openai/gpt-4.1-mini"), 1_047_576);
+		assert_eq!(context_window_for_model("gpt-4-0613"), 8_192);
+		assert_eq!(context_window_for_model("unknown-model"), DEFAULT_CONTEXT_WINDOW);
+	}
+
+	#[test]
+	fn test_fit_keeps_prompt_within_budget() {
+		let mut conversation = conversation(3, 100);
+		let mut context_end = conversation.len();
+
+		budget(1_000).fit(&mut conversation, &mut context_end, &[]);
+		assert_eq!(conversation.len(), 7);
+		assert_eq!(context_end, 7);
+	}
+
+	#[test]
+	fn test_fit_drops_oldest_messages() {
+		let mut conversation = conversation(3, 100);
+		let newest = conversation[6].content.clone();
+		let mut context_end = conversation.len();
+
+		budget(450).fit(&mut conversation, &mut context_end, &[]);
+		assert_eq!(conversation.len(), 5);
+		assert_eq!(context_end, 5);
+		assert_eq!(conversation[4].content, newest);
+	}
+
+	#[test]
+	fn test_fit_shrinks_tool_results() {
+		let mut conversation = conversation(2, 100);
+		let mut context_end = conversation.len();
+		conversation.push(tool_result(200));
+		conversation.push(tool_result(1_000));
+
+		budget(800).fit(&mut conversation, &mut context_end, &[]);
+		assert_eq!(context_end, 3);
+
+		let budget = budget(800);
+		let tokens = conversation
+			.iter()
+			.map(|message| budget.count_message(message))
+			.sum::<usize>();
+		assert!(tokens <= budget.available(), "{} tokens exceed the budget", tokens);
+		assert_eq!(budget.count_message(&conversation[3]), 200 + 1);
+	}
+}
@@ -104,6 +104,17 @@ enum DbAction {
 
 impl PathRateLimits {
 	pub async fn check_route_with_context(&self, map: &HashMap<&str, String>, db: &DatabaseConnection) -> Result<bool> {
+		self.check_route_with_amount(map, db, NonZeroU32::MIN).await
+	}
+
+	/// Like `check_route_with_context`, but consumes `amount` units of quota on every route. Amounts exceeding the quota
+	/// of a limit consume all of it.
+	pub async fn check_route_with_amount(
+		&self,
+		map: &HashMap<&str, String>,
+		db: &DatabaseConnection,
+		amount: NonZeroU32,
+	) -> Result<bool> {
 		let now = Utc::now();
 
 		// track new rate limit states and commit them at the end, if all checks pass
@@ -140,7 +151,7 @@ impl PathRateLimits {
 					.map(|state| state.state)
 					.map(|milis| DateTime::<Utc>::from_timestamp((milis / 1000) as i64, ((milis % 1000) * 1_000_000) as u32).unwrap());
 
-				let new_tob = gcra.check(now, tob, 1.try_into().unwrap());
+				let new_tob = gcra.check(now, tob, amount.min(gcra.quota));
 				match new_tob {
 					Some(tob) => {
 						let remaining = gcra.remaining(now, Some(tob));
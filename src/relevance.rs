@@ -0,0 +1,146 @@
+use std::collections::{
+	HashMap,
+	HashSet,
+};
+
+use poise::serenity_prelude::{
+	Message,
+	UserId,
+};
+
+/// Term frequency saturation of BM25, higher values let repeated terms count for more.
+const K1: f64 = 1.2;
+
+/// Length normalization of BM25, 0 ignores the length of messages, 1 fully normalizes by it.
+const B: f64 = 0.75;
+
+/// Score added for every user both messages are about, either as author or by mentioning them.
+const USER_WEIGHT: f64 = 1.0;
+
+/// Terms shorter than this are mostly filler words, which would only add noise.
+const MIN_TERM_LENGTH: usize = 3;
+
+/// Numbers this long are IDs in Discord markup, like mentions, which are scored separately.
+const MAX_NUMBER_LENGTH: usize = 15;
+
+/// Orders messages by their relevance to `query`, the most relevant first. Messages are scored with BM25 on the terms
+/// of the query, plus the users they share with it. Messages with the same score keep their order.
+pub fn rank(query: &Message, messages: Vec<Message>) -> Vec<Message> {
+	let scores = score(query, &messages);
+
+	let mut ranked = messages.into_iter().zip(scores).collect::<Vec<_>>();
+	ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+	ranked.into_iter().map(|(message, _)| message).collect()
+}
+
+/// Scores the relevance of each message to `query`, with the other messages as corpus.
+fn score(query: &Message, messages: &[Message]) -> Vec<f64> {
+	let documents = messages.iter().map(|message| terms(&message.content)).collect::<Vec<_>>();
+	let average_length = documents.iter().map(Vec::len).sum::<usize>() as f64 / documents.len().max(1) as f64;
+
+	// number of messages each term occurs in
+	let mut document_frequencies = HashMap::<&str, usize>::new();
+	for document in &documents {
+		for term in document.iter().map(String::as_str).collect::<HashSet<_>>() {
+			*document_frequencies.entry(term).or_default() += 1;
+		}
+	}
+
+	let query_terms = terms(&query.content).into_iter().collect::<HashSet<_>>();
+	let query_users = users(query);
+
+	messages
+		.iter()
+		.zip(&documents)
+		.map(|(message, document)| {
+			let length_norm = 1.0 - B + B * document.len() as f64 / average_length.max(1.0);
+			let lexical = query_terms
+				.iter()
+				.filter_map(|term| {
+					let frequency = document.iter().filter(|t| *t == term).count() as f64;
+					let document_frequency = *document_frequencies.get(term.as_str())? as f64;
+					let idf = (1.0 + (documents.len() as f64 - document_frequency + 0.5) / (document_frequency + 0.5)).ln();
+					Some(idf * frequency * (K1 + 1.0) / (frequency + K1 * length_norm))
+				})
+				.sum::<f64>();
+
+			let shared_users = users(message).intersection(&query_users).count() as f64;
+			lexical + USER_WEIGHT * shared_users
+		})
+		.collect()
+}
+
+/// Splits a text into lowercase terms, skipping short words and IDs.
+fn terms(text: &str) -> Vec<String> {
+	text
+		.split(|c: char| !c.is_alphanumeric())
+		.filter(|term| term.chars().count() >= MIN_TERM_LENGTH)
+		.filter(|term| !(term.len() > MAX_NUMBER_LENGTH && term.chars().all(|c| c.is_ascii_digit())))
+		.map(str::to_lowercase)
+		.collect()
+}
+
+/// Users a message is about, its author and everyone it mentions.
+fn users(message: &Message) -> HashSet<UserId> {
+	std::iter::once(message.author.id)
+		.chain(message.mentions.iter().map(|user| user.id))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use poise::serenity_prelude::{
+		MessageId,
+		User,
+	};
+
+	use super::*;
+
+	fn message(id: u64, author: u64, content: &str) -> Message {
+		let mut message = Message::default();
+		message.id = MessageId::new(id);
+		message.author.id = UserId::new(author);
+		message.content = content.to_string();
+		message
+	}
+
+	fn ids(messages: &[Message]) -> Vec<u64> {
+		messages.iter().map(|message| message.id.get()).collect()
+	}
+
+	#[test]
+	fn test_terms() {
+		assert_eq!(terms("Is the <@175928847299117063> server DOWN again?"), vec![
+			"the", "server", "down", "again"
+		]);
+	}
+
+	#[test]
+	fn test_rank_by_terms() {
+		let query = message(10, 1, "why does the build fail on windows?");
+		let messages = vec![
+			message(9, 2, "lunch anyone?"),
+			message(8, 3, "the windows build fails since yesterday"),
+			message(7, 4, "anyone seen the new trailer"),
+			message(6, 3, "it's the linker, windows build needs the new toolchain"),
+		];
+
+		assert_eq!(ids(&rank(&query, messages)), vec![8, 6, 7, 9]);
+	}
+
+	#[test]
+	fn test_rank_by_users() {
+		let mut query = message(10, 1, "what did they say?");
+		let mut mentioned = User::default();
+		mentioned.id = UserId::new(3);
+		query.mentions.push(mentioned);
+
+		let messages = vec![
+			message(9, 2, "something else"),
+			message(8, 3, "something"),
+			message(7, 2, "nothing"),
+		];
+
+		assert_eq!(ids(&rank(&query, messages)), vec![8, 9, 7]);
+	}
+}
@@ -0,0 +1,22 @@
+/// Shortens a text to at most `max_length` characters, marking the cut with an ellipsis.
+pub(crate) fn truncate_chars(text: &str, max_length: usize) -> String {
+	if text.chars().count() <= max_length {
+		return text.to_string();
+	}
+
+	let mut truncated = text.chars().take(max_length - 1).collect::<String>();
+	truncated.push('…');
+	truncated
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_truncate_chars() {
+		assert_eq!(truncate_chars("short", 5), "short");
+		assert_eq!(truncate_chars("too long", 5), "too …");
+		assert_eq!(truncate_chars("äöüäöü", 4), "äöü…");
+	}
+}
@@ -0,0 +1,320 @@
+use std::{
+	collections::HashMap,
+	fmt,
+	path::Path,
+	str::FromStr,
+	sync::Arc,
+};
+
+use base64::{
+	Engine,
+	engine::general_purpose::STANDARD,
+};
+use lazy_static::lazy_static;
+use log::{
+	info,
+	warn,
+};
+use miette::{
+	IntoDiagnostic,
+	Report,
+	Result,
+	WrapErr,
+	miette,
+};
+use regex::Regex;
+
+// the original patterns end with `\s+(?!\S)|\s+`, lookaheads are emulated in `Bpe::split`
+lazy_static! {
+	static ref CL100K_PATTERN: Regex = Regex::new(
+		r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+"
+	)
+	.unwrap();
+	static ref O200K_PATTERN: Regex = Regex::new(
+		r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+"
+	)
+	.unwrap();
+}
+
+/// Counts the tokens a text takes up in the context of a model.
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+	fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Estimates token counts from the length of a text, used if there is no vocabulary for a model.
+#[derive(Debug)]
+pub struct Heuristic;
+
+impl Tokenizer for Heuristic {
+	fn count_tokens(&self, text: &str) -> usize {
+		estimate_token_count(text)
+	}
+}
+
+pub(crate) fn estimate_token_count(str: &str) -> usize {
+	// for now we just count 6 characters as a token
+	str.chars().count() / 6
+}
+
+/// BPE vocabularies in the format used by tiktoken, each covering a family of models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	Cl100kBase,
+	O200kBase,
+}
+
+impl Encoding {
+	/// Picks the encoding of a model family by its name. Provider prefixes like `openai/` are ignored.
+	pub fn for_model(model: &str) -> Option<Self> {
+		let model = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+
+		const O200K_PREFIXES: [&str; 8] = ["gpt-4o", "chatgpt-4o", "gpt-4.1", "gpt-4.5", "gpt-5", "o1", "o3", "o4"];
+		const CL100K_PREFIXES: [&str; 4] = ["gpt-4", "gpt-3.5", "text-embedding-3", "text-embedding-ada"];
+
+		if O200K_PREFIXES.iter().any(|prefix| model.starts_with(prefix)) {
+			Some(Self::O200kBase)
+		} else if CL100K_PREFIXES.iter().any(|prefix| model.starts_with(prefix)) {
+			Some(Self::Cl100kBase)
+		} else {
+			None
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Cl100kBase => "cl100k_base",
+			Self::O200kBase => "o200k_base",
+		}
+	}
+
+	fn pattern(&self) -> &'static Regex {
+		match self {
+			Self::Cl100kBase => &CL100K_PATTERN,
+			Self::O200kBase => &O200K_PATTERN,
+		}
+	}
+}
+
+impl FromStr for Encoding {
+	type Err = Report;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"cl100k_base" => Ok(Self::Cl100kBase),
+			"o200k_base" => Ok(Self::O200kBase),
+			_ => Err(miette!("unknown encoding '{}', expected cl100k_base or o200k_base", s)),
+		}
+	}
+}
+
+/// Byte pair encoding, which counts tokens exactly like the models of its vocabulary do.
+pub struct Bpe {
+	encoding: Encoding,
+
+	/// Rank of each token, tokens with a lower rank are merged first.
+	ranks: HashMap<Vec<u8>, u32>,
+}
+
+impl fmt::Debug for Bpe {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Bpe")
+			.field("encoding", &self.encoding)
+			.field("vocabulary_size", &self.ranks.len())
+			.finish()
+	}
+}
+
+impl Bpe {
+	/// Loads a vocabulary in the tiktoken format, each line is a base64 encoded token followed by its rank.
+	pub fn load(encoding: Encoding, path: &Path) -> Result<Self> {
+		let vocabulary = std::fs::read_to_string(path)
+			.into_diagnostic()
+			.wrap_err_with(|| format!("failed to read vocabulary {}", path.display()))?;
+
+		Self::parse(encoding, &vocabulary).wrap_err_with(|| format!("failed to parse vocabulary {}", path.display()))
+	}
+
+	fn parse(encoding: Encoding, vocabulary: &str) -> Result<Self> {
+		let mut ranks = HashMap::new();
+		for line in vocabulary.lines().filter(|line| !line.trim().is_empty()) {
+			let (token, rank) = line.split_once(' ').ok_or_else(|| miette!("invalid line '{}'", line))?;
+			let token = STANDARD.decode(token).into_diagnostic().wrap_err("invalid token")?;
+			let rank = rank.trim().parse().into_diagnostic().wrap_err("invalid rank")?;
+			ranks.insert(token, rank);
+		}
+
+		Ok(Self {
+			encoding,
+			ranks,
+		})
+	}
+
+	/// Splits a text into the pieces which are encoded separately.
+	fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+		let pattern = self.encoding.pattern();
+		let mut pieces = Vec::new();
+		let mut start = 0;
+
+		while let Some(found) = pattern.find_at(text, start) {
+			let mut end = found.end();
+
+			// emulates `\s+(?!\S)`, whitespace in front of a word leaves its last character to the word
+			let piece = found.as_str();
+			let is_spaces = piece.chars().all(char::is_whitespace) && !piece.ends_with(['\r', '\n']);
+			if is_spaces && piece.chars().count() > 1 && text[end..].starts_with(|c: char| !c.is_whitespace()) {
+				end -= piece.chars().next_back().map_or(0, char::len_utf8);
+			}
+
+			pieces.push(&text[found.start()..end]);
+			start = end;
+		}
+
+		pieces
+	}
+
+	/// Counts the tokens a single piece is encoded to, by merging the pair with the lowest rank until none is left.
+	/// Like tiktoken, the rank of each pair is kept and only the neighbours of a merge are looked up again.
+	fn count_piece(&self, piece: &[u8]) -> usize {
+		if self.ranks.contains_key(piece) {
+			return 1;
+		}
+		if piece.len() < 2 {
+			return piece.len();
+		}
+
+		// start offset of each part, paired with the rank of merging it with the next part, `u32::MAX` if unmergeable
+		let rank_of = |bytes: &[u8]| self.ranks.get(bytes).copied().unwrap_or(u32::MAX);
+		let mut parts = (0..piece.len() - 1)
+			.map(|i| (i, rank_of(&piece[i..i + 2])))
+			.collect::<Vec<_>>();
+		parts.push((piece.len() - 1, u32::MAX));
+		parts.push((piece.len(), u32::MAX));
+
+		// rank of merging part `i` with the next one, once the part after it was merged into it
+		let merged_rank = |parts: &[(usize, u32)], i: usize| match parts.get(i + 3) {
+			Some(&(end, _)) => rank_of(&piece[parts[i].0..end]),
+			None => u32::MAX,
+		};
+
+		loop {
+			let (i, rank) = parts[..parts.len() - 1]
+				.iter()
+				.enumerate()
+				.min_by_key(|&(i, &(_, rank))| (rank, i))
+				.map(|(i, &(_, rank))| (i, rank))
+				.expect("a piece has at least one part");
+			if rank == u32::MAX {
+				break;
+			}
+
+			if i > 0 {
+				parts[i - 1].1 = merged_rank(&parts, i - 1);
+			}
+			parts[i].1 = merged_rank(&parts, i);
+			parts.remove(i + 1);
+		}
+
+		parts.len() - 1
+	}
+}
+
+impl Tokenizer for Bpe {
+	fn count_tokens(&self, text: &str) -> usize {
+		self.split(text).iter().map(|piece| self.count_piece(piece.as_bytes())).sum()
+	}
+}
+
+/// Chooses the tokenizer for a model. `tokenizer` is either `auto`, to pick the encoding by the model family,
+/// `heuristic` or the name of an encoding. Vocabularies are loaded from `<vocabulary_dir>/<encoding>.tiktoken`, if
+/// one can't be loaded, token counts are estimated.
+pub fn for_model(model: &str, tokenizer: &str, vocabulary_dir: &Path) -> Result<Arc<dyn Tokenizer>> {
+	let encoding = match tokenizer {
+		"auto" => Encoding::for_model(model),
+		"heuristic" => None,
+		encoding => Some(encoding.parse()?),
+	};
+
+	let Some(encoding) = encoding else {
+		info!("Estimating token counts for model {}", model);
+		return Ok(Arc::new(Heuristic));
+	};
+
+	let path = vocabulary_dir.join(format!("{}.tiktoken", encoding.name()));
+	match Bpe::load(encoding, &path) {
+		Ok(bpe) => {
+			info!("Counting tokens with {} for model {}", encoding.name(), model);
+			Ok(Arc::new(bpe))
+		},
+		Err(err) => {
+			warn!(
+				"Estimating token counts, vocabulary {} is not available: {:?}",
+				encoding.name(),
+				err
+			);
+			Ok(Arc::new(Heuristic))
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Vocabulary with all single bytes of `abc `, and merges of some of them.
+	fn small_bpe(encoding: Encoding) -> Bpe {
+		let tokens = ["a", "b", "c", " ", "ab", " a", "abc", " ab"];
+		let vocabulary = tokens
+			.iter()
+			.enumerate()
+			.map(|(rank, token)| format!("{} {}", STANDARD.encode(token), rank))
+			.collect::<Vec<_>>()
+			.join("\n");
+
+		Bpe::parse(encoding, &vocabulary).unwrap()
+	}
+
+	#[test]
+	fn test_encoding_for_model() {
+		assert_eq!(Encoding::for_model("gpt-4o-mini"), Some(Encoding::O200kBase));
+		assert_eq!(Encoding::for_model("openai/o3-mini"), Some(Encoding::O200kBase));
+		assert_eq!(Encoding::for_model("gpt-4-turbo"), Some(Encoding::Cl100kBase));
+		assert_eq!(Encoding::for_model("GPT-3.5-turbo"), Some(Encoding::Cl100kBase));
+		assert_eq!(Encoding::for_model("claude-3-haiku"), None);
+	}
+
+	#[test]
+	fn test_split() {
+		let bpe = small_bpe(Encoding::Cl100kBase);
+		assert_eq!(bpe.split("Hello world"), vec!["Hello", " world"]);
+		assert_eq!(bpe.split("a   b"), vec!["a", "  ", " b"]);
+		assert_eq!(bpe.split("it's 12345!\n\nend  "), vec![
+			"it", "'s", " ", "123", "45", "!\n\n", "end", "  "
+		]);
+
+		let bpe = small_bpe(Encoding::O200kBase);
+		assert_eq!(bpe.split("HelloWorld don't"), vec!["Hello", "World", " don't"]);
+	}
+
+	#[test]
+	fn test_count_tokens() {
+		let bpe = small_bpe(Encoding::Cl100kBase);
+		assert_eq!(bpe.count_tokens("abc"), 1);
+		assert_eq!(bpe.count_tokens("abc abc"), 3);
+		assert_eq!(bpe.count_tokens("cab"), 2);
+		assert_eq!(bpe.count_tokens("abc  cba"), 6);
+		assert_eq!(bpe.count_tokens(""), 0);
+	}
+
+	#[test]
+	fn test_count_piece() {
+		let bpe = small_bpe(Encoding::Cl100kBase);
+		assert_eq!(bpe.count_piece(b"a"), 1);
+		assert_eq!(bpe.count_piece(b"x"), 1);
+		assert_eq!(bpe.count_piece(b" abc"), 2);
+		assert_eq!(bpe.count_piece(b"cabcab"), 3);
+
+		// long pieces have to be counted in reasonable time
+		assert_eq!(bpe.count_piece(&b"ab".repeat(1000)), 1000);
+		assert_eq!(bpe.count_piece(&b"abc".repeat(1000)), 1000);
+	}
+}
@@ -0,0 +1,272 @@
+use std::time::Duration;
+
+use llm::FunctionCall;
+use log::debug;
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+};
+use poise::serenity_prelude::{
+	ButtonStyle,
+	ChannelId,
+	ComponentInteraction,
+	ComponentInteractionCollector,
+	Context,
+	CreateActionRow,
+	CreateAllowedMentions,
+	CreateButton,
+	CreateEmbed,
+	CreateEmbedFooter,
+	CreateInteractionResponse,
+	CreateInteractionResponseMessage,
+	CreateMessage,
+	EditMessage,
+	Message,
+	MessageId,
+	Permissions,
+	UserId,
+};
+use tokio::time::Instant;
+
+use crate::{
+	mcp_config::ApprovalMode,
+	text::truncate_chars,
+};
+
+/// Time to decide about a tool call, before it is skipped.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time kept free before the reply times out, so the model can still answer once a call was skipped.
+const ANSWER_MARGIN: Duration = Duration::from_secs(15);
+
+/// Arguments are shown in an embed, which is limited to 4096 characters.
+const MAX_ARGUMENTS_LENGTH: usize = 1000;
+
+/// Outcome of asking for approval of a tool call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApprovalDecision {
+	Approved,
+	Denied,
+
+	/// Nobody allowed to decide did so in time.
+	TimedOut,
+}
+
+impl ApprovalDecision {
+	/// Explains to the model why the tool was not called, used in place of the tool result.
+	pub fn explanation(&self) -> &'static str {
+		match self {
+			ApprovalDecision::Approved => "The tool call was approved.",
+			ApprovalDecision::Denied => "The tool call was denied by a human and was not executed. Do not try again.",
+			ApprovalDecision::TimedOut => "The tool call was not approved in time and was not executed.",
+		}
+	}
+}
+
+/// Asks humans to approve tool calls of sensitive tools, before they are executed.
+/// The intended call is posted in reply to the invoking message, with buttons to approve or deny it.
+pub struct ToolApproval {
+	ctx: Context,
+	channel_id: ChannelId,
+	message_id: MessageId,
+	user_id: UserId,
+	in_guild: bool,
+
+	/// When the reply times out, decisions have to be made well before.
+	reply_deadline: Instant,
+}
+
+impl ToolApproval {
+	pub fn new(ctx: Context, message: &Message, reply_deadline: Instant) -> Self {
+		Self {
+			ctx,
+			channel_id: message.channel_id,
+			message_id: message.id,
+			user_id: message.author.id,
+			in_guild: message.guild_id.is_some(),
+			reply_deadline,
+		}
+	}
+
+	/// Posts the intended call and waits for someone allowed to decide.
+	pub async fn request(&self, call: &FunctionCall, mode: ApprovalMode) -> Result<ApprovalDecision> {
+		if mode == ApprovalMode::None {
+			return Ok(ApprovalDecision::Approved);
+		}
+
+		// without time left to decide, nobody is asked in the first place
+		let now = Instant::now();
+		let deadline = decision_deadline(now, self.reply_deadline);
+		if deadline <= now {
+			debug!("No time left to approve a call of '{}', skipping it", call.name);
+			return Ok(ApprovalDecision::TimedOut);
+		}
+
+		let deciders = match (mode, self.in_guild) {
+			(ApprovalMode::Moderators, true) => "Only moderators can decide.",
+			_ => "Only the user who asked can decide.",
+		};
+
+		let request = CreateMessage::new()
+			.reference_message((self.channel_id, self.message_id))
+			.allowed_mentions(CreateAllowedMentions::new().empty_roles().empty_users().replied_user(true))
+			.embed(
+				CreateEmbed::new()
+					.title("Approval required")
+					.description(format!(
+						"I'd like to use `{}` with these arguments:\n```json\n{}\n```",
+						call.name,
+						format_arguments(&call.arguments)
+					))
+					.footer(CreateEmbedFooter::new(deciders)),
+			)
+			.components(vec![CreateActionRow::Buttons(vec![
+				CreateButton::new("approve").style(ButtonStyle::Success).label("Approve"),
+				CreateButton::new("deny").style(ButtonStyle::Danger).label("Deny"),
+			])]);
+
+		let mut request = self
+			.channel_id
+			.send_message(&self.ctx, request)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to ask for tool approval")?;
+
+		let (decision, outcome) = match self.wait_for_decision(&request, mode, deadline).await {
+			Some((decision, name)) if decision == ApprovalDecision::Approved => (decision, format!("Approved by {}", name)),
+			Some((decision, name)) => (decision, format!("Denied by {}", name)),
+			None => (
+				ApprovalDecision::TimedOut,
+				"Not approved in time, the tool was not used".to_string(),
+			),
+		};
+
+		// buttons are useless from now on
+		let edit = EditMessage::new().components(Vec::new()).content(format!("-# {}", outcome));
+		if let Err(err) = request.edit(&self.ctx, edit).await {
+			debug!("Failed to remove buttons from approval message: {}", err);
+		}
+
+		Ok(decision)
+	}
+
+	/// Waits for a decision and returns it together with the name of whoever made it.
+	/// Returns `None` if nobody allowed to decide did so before the deadline.
+	async fn wait_for_decision(
+		&self,
+		request: &Message,
+		mode: ApprovalMode,
+		deadline: Instant,
+	) -> Option<(ApprovalDecision, String)> {
+		loop {
+			let interaction = ComponentInteractionCollector::new(&self.ctx)
+				.message_id(request.id)
+				.timeout(deadline.saturating_duration_since(Instant::now()))
+				.await?;
+
+			let permissions = interaction.member.as_ref().and_then(|member| member.permissions);
+			if !may_decide(mode, self.user_id, self.in_guild, interaction.user.id, permissions) {
+				respond(
+					&self.ctx,
+					&interaction,
+					CreateInteractionResponse::Message(
+						CreateInteractionResponseMessage::new()
+							.ephemeral(true)
+							.content("You are not allowed to decide about this tool call."),
+					),
+				)
+				.await;
+				continue;
+			}
+
+			let decision = match interaction.data.custom_id.as_str() {
+				"approve" => ApprovalDecision::Approved,
+				"deny" => ApprovalDecision::Denied,
+				_ => continue,
+			};
+
+			respond(&self.ctx, &interaction, CreateInteractionResponse::Acknowledge).await;
+			return Some((decision, interaction.user.display_name().to_string()));
+		}
+	}
+}
+
+async fn respond(ctx: &Context, interaction: &ComponentInteraction, response: CreateInteractionResponse) {
+	if let Err(err) = interaction.create_response(ctx, response).await {
+		debug!("Failed to respond to approval interaction: {}", err);
+	}
+}
+
+/// Until when a decision is waited for, at most the approval timeout and never so long that the reply times out.
+fn decision_deadline(now: Instant, reply_deadline: Instant) -> Instant {
+	let latest = reply_deadline.checked_sub(ANSWER_MARGIN).unwrap_or(now);
+	(now + APPROVAL_TIMEOUT).min(latest)
+}
+
+/// Whether the user who clicked a button is allowed to decide about the tool call.
+fn may_decide(mode: ApprovalMode, invoking_user: UserId, in_guild: bool, user: UserId, permissions: Option<Permissions>) -> bool {
+	match mode {
+		ApprovalMode::None => true,
+		ApprovalMode::Moderators if in_guild => permissions.is_some_and(|permissions| permissions.manage_messages()),
+		// there are no moderators in DMs
+		ApprovalMode::User | ApprovalMode::Moderators => user == invoking_user,
+	}
+}
+
+/// Formats the arguments of a call for humans. Invalid JSON is shown as is.
+fn format_arguments(arguments: &str) -> String {
+	let formatted = serde_json::from_str::<serde_json::Value>(arguments)
+		.and_then(|value| serde_json::to_string_pretty(&value))
+		.unwrap_or_else(|_| arguments.to_string())
+		// arguments must not be able to end the code block
+		.replace("```", "`\u{200b}``");
+
+	truncate_chars(&formatted, MAX_ARGUMENTS_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_may_decide() {
+		let invoking = UserId::new(1);
+		let other = UserId::new(2);
+		let moderator = Some(Permissions::MANAGE_MESSAGES | Permissions::SEND_MESSAGES);
+		let member = Some(Permissions::SEND_MESSAGES);
+
+		assert!(may_decide(ApprovalMode::User, invoking, true, invoking, None));
+		assert!(!may_decide(ApprovalMode::User, invoking, true, other, moderator));
+
+		assert!(may_decide(ApprovalMode::Moderators, invoking, true, other, moderator));
+		assert!(!may_decide(ApprovalMode::Moderators, invoking, true, invoking, member));
+		assert!(!may_decide(ApprovalMode::Moderators, invoking, true, other, None));
+
+		// in DMs, the invoking user decides
+		assert!(may_decide(ApprovalMode::Moderators, invoking, false, invoking, None));
+		assert!(!may_decide(ApprovalMode::Moderators, invoking, false, other, None));
+	}
+
+	#[test]
+	fn test_decision_deadline() {
+		let now = Instant::now();
+		assert_eq!(decision_deadline(now, now + Duration::from_secs(300)), now + APPROVAL_TIMEOUT);
+		assert_eq!(
+			decision_deadline(now, now + Duration::from_secs(60)),
+			now + Duration::from_secs(60) - ANSWER_MARGIN
+		);
+		assert!(decision_deadline(now, now + Duration::from_secs(10)) <= now);
+	}
+
+	#[test]
+	fn test_format_arguments() {
+		assert_eq!(format_arguments(r#"{"text":"hi"}"#), "{\n  \"text\": \"hi\"\n}");
+		assert_eq!(format_arguments("not json"), "not json");
+		assert_eq!(format_arguments(r#"{"text":"```"}"#), "{\n  \"text\": \"`\u{200b}``\"\n}");
+
+		let long = format_arguments(&format!(r#"{{"text":"{}"}}"#, "a".repeat(2000)));
+		assert_eq!(long.chars().count(), MAX_ARGUMENTS_LENGTH);
+		assert!(long.ends_with('…'));
+	}
+}
@@ -0,0 +1,210 @@
+use std::{
+	sync::Arc,
+	time::Duration,
+};
+
+use chrono::Utc;
+use entity::tool_call;
+use log::{
+	error,
+	info,
+};
+use miette::{
+	IntoDiagnostic,
+	Result,
+	WrapErr,
+};
+use poise::serenity_prelude::{
+	GuildId,
+	Message,
+	UserId,
+};
+use sea_orm::{
+	ActiveModelTrait,
+	ActiveValue::Set,
+	ColumnTrait,
+	ConnectionTrait,
+	DatabaseConnection,
+	EntityTrait,
+	QueryFilter,
+	QueryOrder,
+	QuerySelect,
+};
+
+use crate::text::truncate_chars;
+
+/// How often expired entries are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Errors are shortened when listing entries, so a single entry can't fill the entire listing.
+const MAX_LISTED_ERROR_LENGTH: usize = 100;
+
+/// A single tool call, as it is recorded in the audit trail.
+#[derive(Debug, Clone)]
+pub struct ToolCallRecord {
+	pub server: Option<String>,
+	pub tool: String,
+	pub arguments: String,
+	pub result_size: usize,
+	pub duration: Duration,
+	pub error: Option<String>,
+}
+
+/// Database backed audit trail of all tool calls. Every entry is linked to the message which triggered the tool call.
+pub struct ToolAudit<'a, C> {
+	db: &'a C,
+}
+
+impl<'a, C: ConnectionTrait> ToolAudit<'a, C> {
+	/// Creates a new handle to the audit trail.
+	pub fn new(db: &'a C) -> Self {
+		Self {
+			db,
+		}
+	}
+
+	/// Records a tool call made in response to the given message.
+	pub async fn record(&self, message: &Message, record: ToolCallRecord) -> Result<()> {
+		let entry = tool_call::ActiveModel {
+			discord_message_id: Set(message.id.get()),
+			discord_user_id: Set(message.author.id.get()),
+			discord_guild_id: Set(message.guild_id.map(|id| id.get())),
+			discord_channel_id: Set(message.channel_id.get()),
+			server: Set(record.server),
+			tool: Set(record.tool),
+			arguments: Set(record.arguments),
+			result_size: Set(record.result_size as u64),
+			duration_ms: Set(record.duration.as_millis() as u64),
+			error: Set(record.error),
+			..Default::default()
+		};
+
+		entry
+			.insert(self.db)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to insert tool call audit entry")?;
+
+		Ok(())
+	}
+
+	/// Fetches the most recent tool calls triggered by the given user, newest first.
+	pub async fn recent_for_user(&self, user_id: UserId, limit: u64) -> Result<Vec<tool_call::Model>> {
+		self.recent(tool_call::Column::DiscordUserId.eq(user_id.get()), limit).await
+	}
+
+	/// Fetches the most recent tool calls made in the given guild, newest first.
+	pub async fn recent_for_guild(&self, guild_id: GuildId, limit: u64) -> Result<Vec<tool_call::Model>> {
+		self.recent(tool_call::Column::DiscordGuildId.eq(guild_id.get()), limit).await
+	}
+
+	async fn recent(&self, filter: sea_orm::sea_query::SimpleExpr, limit: u64) -> Result<Vec<tool_call::Model>> {
+		entity::prelude::ToolCall::find()
+			.filter(filter)
+			.order_by_desc(tool_call::Column::CreatedAt)
+			.order_by_desc(tool_call::Column::Id)
+			.limit(limit)
+			.all(self.db)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to fetch tool call audit entries")
+	}
+
+	/// Deletes all entries older than the retention period. Returns the number of deleted entries.
+	pub async fn purge(&self, retention: Duration) -> Result<u64> {
+		let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+		let cutoff = Utc::now()
+			.checked_sub_signed(retention)
+			.unwrap_or(chrono::DateTime::<Utc>::MIN_UTC);
+
+		let result = entity::prelude::ToolCall::delete_many()
+			.filter(tool_call::Column::CreatedAt.lt(cutoff))
+			.exec(self.db)
+			.await
+			.into_diagnostic()
+			.wrap_err("failed to purge tool call audit entries")?;
+
+		Ok(result.rows_affected)
+	}
+}
+
+/// Periodically purges entries older than the retention period. Runs forever, so it should be spawned as a task.
+pub async fn purge_periodically(db: Arc<DatabaseConnection>, retention: Duration) {
+	let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+	loop {
+		interval.tick().await;
+
+		match ToolAudit::new(db.as_ref()).purge(retention).await {
+			Ok(0) => {},
+			Ok(purged) => info!("Purged {} expired tool call audit entries", purged),
+			Err(err) => error!("Failed to purge tool call audit entries: {:?}", err),
+		}
+	}
+}
+
+/// Formats an entry as a single line for listings in Discord.
+pub fn format_entry(entry: &tool_call::Model) -> String {
+	let tool = match &entry.server {
+		Some(server) => format!("{}/{}", server, entry.tool),
+		None => entry.tool.clone(),
+	};
+	let guild = entry
+		.discord_guild_id
+		.map(|id| id.to_string())
+		.unwrap_or_else(|| "@me".to_string());
+
+	let mut line = format!(
+		"<t:{}:R> `{}` by <@{}> in [message](https://discord.com/channels/{}/{}/{}), {} chars in {} ms",
+		entry.created_at.timestamp(),
+		tool,
+		entry.discord_user_id,
+		guild,
+		entry.discord_channel_id,
+		entry.discord_message_id,
+		entry.result_size,
+		entry.duration_ms
+	);
+	if let Some(error) = &entry.error {
+		line.push_str(&format!(", failed: {}", truncate_chars(error, MAX_LISTED_ERROR_LENGTH)));
+	}
+
+	line
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	#[test]
+	fn test_format_entry() {
+		let mut entry = tool_call::Model {
+			id: 1,
+			discord_message_id: 3,
+			discord_user_id: 4,
+			discord_guild_id: Some(1),
+			discord_channel_id: 2,
+			server: Some("web".to_string()),
+			tool: "search".to_string(),
+			arguments: "{}".to_string(),
+			result_size: 1200,
+			duration_ms: 350,
+			error: None,
+			created_at: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+		};
+
+		assert_eq!(
+			format_entry(&entry),
+			"<t:1700000000:R> `web/search` by <@4> in [message](https://discord.com/channels/1/2/3), 1200 chars in 350 ms"
+		);
+
+		entry.server = None;
+		entry.discord_guild_id = None;
+		entry.error = Some("x".repeat(200));
+		let line = format_entry(&entry);
+		assert!(line.starts_with("<t:1700000000:R> `search` by <@4> in [message](https://discord.com/channels/@me/2/3)"));
+		assert!(line.ends_with(&format!(", failed: {}…", "x".repeat(MAX_LISTED_ERROR_LENGTH - 1))));
+	}
+}
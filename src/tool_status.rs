@@ -0,0 +1,206 @@
+use std::{
+	sync::Arc,
+	time::Duration,
+};
+
+use log::debug;
+use poise::serenity_prelude::{
+	CreateAllowedMentions,
+	CreateMessage,
+	EditMessage,
+	Http,
+	Message,
+	MessageReference,
+};
+use tokio::{
+	sync::watch,
+	task::JoinHandle,
+	time::Instant,
+};
+
+/// Discord rate limits message edits, so the status message is updated at most once per interval.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A tool call which is currently running.
+#[derive(Debug, Clone)]
+struct RunningTool {
+	id: String,
+	label: String,
+	progress: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct StatusState {
+	tools: Vec<RunningTool>,
+
+	/// Set once the reply is ready, the status message is then handed over to the caller.
+	finished: bool,
+}
+
+impl StatusState {
+	/// Renders running tools as Discord subtext, one line per label. Identical calls are merged into a single line.
+	fn render(&self) -> String {
+		if self.tools.is_empty() {
+			return "-# Thinking…".to_string();
+		}
+
+		let mut lines: Vec<(&str, usize, Option<&str>)> = Vec::new();
+		for tool in &self.tools {
+			match lines.iter_mut().find(|(label, ..)| *label == tool.label) {
+				Some((_, count, progress)) => {
+					*count += 1;
+					// progress of a single call is misleading, once multiple calls are merged
+					*progress = None;
+				},
+				None => lines.push((&tool.label, 1, tool.progress.as_deref())),
+			}
+		}
+
+		lines
+			.into_iter()
+			.map(|(label, count, progress)| {
+				let mut line = format!("-# {}…", label);
+				if count > 1 {
+					line.push_str(&format!(" ({})", count));
+				}
+				if let Some(progress) = progress {
+					line.push_str(&format!(" {}", progress));
+				}
+				line
+			})
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+}
+
+/// Status message telling the user which tools are currently running.
+/// The message is only sent once the first tool starts, and is updated in the background while tools are running.
+pub struct ToolStatus {
+	state: watch::Sender<StatusState>,
+	task: JoinHandle<Option<Message>>,
+}
+
+impl ToolStatus {
+	/// Starts showing the status of tool calls in reply to `message`.
+	pub fn start(http: Arc<Http>, message: &Message) -> Self {
+		let (state, receiver) = watch::channel(StatusState::default());
+		let task = tokio::spawn(update_status_message(http, message.into(), receiver));
+
+		Self {
+			state,
+			task,
+		}
+	}
+
+	pub fn tool_started(&self, id: &str, label: String) {
+		self.state.send_modify(|state| {
+			state.tools.push(RunningTool {
+				id: id.to_string(),
+				label,
+				progress: None,
+			});
+		});
+	}
+
+	pub fn tool_progress(&self, id: &str, progress: String) {
+		self.state.send_modify(|state| {
+			if let Some(tool) = state.tools.iter_mut().find(|tool| tool.id == id) {
+				tool.progress = Some(progress);
+			}
+		});
+	}
+
+	pub fn tool_finished(&self, id: &str) {
+		self.state.send_modify(|state| state.tools.retain(|tool| tool.id != id));
+	}
+
+	/// Stops updating the status message and returns it, so it can be replaced with the final reply.
+	/// Returns `None` if no tool was called, or the status message couldn't be sent.
+	pub async fn finish(self) -> Option<Message> {
+		self.state.send_modify(|state| state.finished = true);
+		self.task.await.ok().flatten()
+	}
+}
+
+/// Keeps the status message in sync with the state, until the status is finished.
+/// If the status is dropped without being finished, e.g. because the reply failed, the status message is deleted.
+async fn update_status_message(
+	http: Arc<Http>,
+	reference: MessageReference,
+	mut state: watch::Receiver<StatusState>,
+) -> Option<Message> {
+	let mut status_message: Option<Message> = None;
+	let mut last_update: Option<Instant> = None;
+
+	while state.changed().await.is_ok() {
+		// wait until we are allowed to update again, but don't hold up the reply in the meantime
+		if let Some(last_update) = last_update {
+			let _ = tokio::time::timeout_at(last_update + UPDATE_INTERVAL, state.wait_for(|state| state.finished)).await;
+		}
+
+		let (content, finished, running) = {
+			let state = state.borrow_and_update();
+			(state.render(), state.finished, !state.tools.is_empty())
+		};
+
+		if finished {
+			return status_message;
+		}
+
+		let result = match &mut status_message {
+			Some(message) => message.edit(&http, EditMessage::new().content(content)).await,
+			// nothing to show, until the first tool is running
+			None if !running => continue,
+			None => {
+				let message = CreateMessage::new()
+					.reference_message(reference.clone())
+					.allowed_mentions(CreateAllowedMentions::new().empty_roles().empty_users().replied_user(true))
+					.content(content);
+
+				reference.channel_id.send_message(&http, message).await.map(|message| {
+					status_message = Some(message);
+				})
+			},
+		};
+
+		if let Err(err) = result {
+			debug!("Failed to update tool status message: {}", err);
+		}
+		last_update = Some(Instant::now());
+	}
+
+	if let Some(message) = status_message {
+		if let Err(err) = message.delete(&http).await {
+			debug!("Failed to delete tool status message: {}", err);
+		}
+	}
+
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tool(id: &str, label: &str, progress: Option<&str>) -> RunningTool {
+		RunningTool {
+			id: id.to_string(),
+			label: label.to_string(),
+			progress: progress.map(str::to_string),
+		}
+	}
+
+	#[test]
+	fn test_render_status() {
+		let mut state = StatusState::default();
+		assert_eq!(state.render(), "-# Thinking…");
+
+		state.tools.push(tool("1", "Searching the web", Some("50%")));
+		assert_eq!(state.render(), "-# Searching the web… 50%");
+
+		state.tools.push(tool("2", "Using `fetch`", None));
+		state.tools.push(tool("3", "Searching the web", None));
+		state.tools.push(tool("4", "Searching the web", Some("10%")));
+		assert_eq!(state.render(), "-# Searching the web… (3)\n-# Using `fetch`…");
+	}
+}